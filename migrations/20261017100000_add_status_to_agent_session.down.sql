@@ -0,0 +1,5 @@
+ALTER TABLE agent_session
+    DROP COLUMN IF EXISTS status,
+    DROP COLUMN IF EXISTS status_text;
+
+DROP TYPE IF EXISTS agent_status;
@@ -0,0 +1,5 @@
+CREATE TYPE agent_status AS ENUM ('online', 'away', 'busy');
+
+ALTER TABLE agent_session
+    ADD status      agent_status DEFAULT 'online' NOT NULL,
+    ADD status_text text;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS classroom_ban
+(
+    classroom_id uuid        NOT NULL,
+    account_id   account_id  NOT NULL,
+    expires_at   timestamptz NOT NULL,
+    created_at   timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (classroom_id, account_id)
+);
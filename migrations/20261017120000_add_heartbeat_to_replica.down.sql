@@ -0,0 +1,2 @@
+ALTER TABLE replica
+    DROP COLUMN IF EXISTS heartbeat_at;
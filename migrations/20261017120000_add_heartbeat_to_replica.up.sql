@@ -0,0 +1,2 @@
+ALTER TABLE replica
+    ADD heartbeat_at timestamptz DEFAULT NOW() NOT NULL;
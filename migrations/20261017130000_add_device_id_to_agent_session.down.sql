@@ -0,0 +1,14 @@
+-- Only the first session of each agent in a classroom is kept
+DELETE FROM agent_session s
+    USING agent_session older
+WHERE s.classroom_id = older.classroom_id
+    AND s.agent_id = older.agent_id
+    AND s.id > older.id;
+
+DROP INDEX uniq_classroom_id_agent_id_device_id;
+
+ALTER TABLE agent_session
+    DROP COLUMN device_id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_classroom_id_agent_id
+    ON agent_session (classroom_id, agent_id);
@@ -0,0 +1,7 @@
+ALTER TABLE agent_session
+    ADD device_id text DEFAULT '' NOT NULL;
+
+DROP INDEX uniq_classroom_id_agent_id;
+
+CREATE UNIQUE INDEX IF NOT EXISTS uniq_classroom_id_agent_id_device_id
+    ON agent_session (classroom_id, agent_id, device_id);
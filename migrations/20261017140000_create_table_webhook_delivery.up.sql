@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS webhook_delivery
+(
+    id              bigserial   NOT NULL PRIMARY KEY,
+    audience        text        NOT NULL,
+    payload         text        NOT NULL,
+    attempts        int         NOT NULL DEFAULT 0,
+    next_attempt_at timestamptz NOT NULL DEFAULT now(),
+    created_at      timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS webhook_delivery_next_attempt_at ON webhook_delivery (next_attempt_at);
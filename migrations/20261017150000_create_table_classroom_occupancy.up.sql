@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS classroom_occupancy
+(
+    id           bigserial   NOT NULL PRIMARY KEY,
+    classroom_id uuid        NOT NULL UNIQUE,
+    occupied_at  timestamptz NOT NULL DEFAULT now()
+);
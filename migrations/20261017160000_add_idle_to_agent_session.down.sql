@@ -0,0 +1 @@
+ALTER TABLE agent_session DROP COLUMN IF EXISTS idle;
@@ -0,0 +1 @@
+ALTER TABLE agent_session ADD COLUMN IF NOT EXISTS metadata jsonb;
@@ -0,0 +1,31 @@
+use crate::{
+    app::{metrics::AuthzMeasure, state::State},
+    authz::AuthzObject,
+    classroom::ClassroomId,
+};
+use svc_authn::AccountId;
+
+/// Agents need it to be in the classroom, it's checked on connect and while they are connected.
+pub const CONNECT_ACTION: &str = "connect";
+
+/// Authorizes the action of the account on the classroom.
+/// The audience of the account is resolved to the one with known authz config, if any.
+pub async fn authorize<S: State>(
+    state: &S,
+    classroom_id: ClassroomId,
+    account_id: &AccountId,
+    action: &str,
+) -> Result<chrono::Duration, svc_authz::error::Error> {
+    let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
+
+    let audience = state
+        .lookup_known_authz_audience(account_id.audience())
+        .unwrap_or(account_id.audience())
+        .to_owned();
+
+    state
+        .authz()
+        .authorize(audience, account_id.clone(), object, action.to_owned())
+        .await
+        .measure()
+}
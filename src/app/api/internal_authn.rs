@@ -0,0 +1,119 @@
+use crate::app::{
+    api::AppResult,
+    error::{ErrorExt, ErrorKind},
+    state::State,
+};
+use anyhow::{anyhow, Result};
+use axum::{extract::Extension, middleware::Next};
+use http::{header::AUTHORIZATION, HeaderValue, Request};
+use std::sync::Arc;
+use svc_authn::{
+    jose::{Claims, ConfigMap},
+    token::jws_compact::extract::{decode_jws_compact_with_config, extract_jws_compact},
+    AccountId,
+};
+
+/// Allows requests to the internal API only from replicas of the service.
+pub async fn authenticate_replica<S: State, B>(
+    Extension(state): Extension<S>,
+    Extension(authn): Extension<Arc<ConfigMap>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> AppResult {
+    let header = req
+        .headers()
+        .get(AUTHORIZATION)
+        .ok_or_else(|| anyhow!("Missing authorization header"))
+        .error(ErrorKind::Unauthenticated)?;
+
+    verify_token(header, &authn, &state.config().id).error(ErrorKind::Unauthenticated)?;
+
+    Ok(next.run(req).await)
+}
+
+fn verify_token(header: &HeaderValue, authn: &ConfigMap, id: &AccountId) -> Result<()> {
+    let data = extract_jws_compact::<String>(header, authn)?;
+
+    verify_account(data.claims, id)
+}
+
+/// Verifies the token of a command sent by another replica over NATS, bypassing the internal API.
+pub fn verify_replica_token(token: &str, authn: &ConfigMap, id: &AccountId) -> Result<()> {
+    let data = decode_jws_compact_with_config::<String>(token, authn)?;
+
+    verify_account(data.claims, id)
+}
+
+fn verify_account(claims: Claims<String>, id: &AccountId) -> Result<()> {
+    let account_id = AccountId::new(claims.subject(), claims.audience());
+
+    // Tokens of other accounts are valid too, but they mustn't manage sessions
+    if &account_id != id {
+        return Err(anyhow!(
+            "Account {} is not allowed to call the internal API",
+            account_id
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{app::state::State, test_helpers::prelude::*};
+    use uuid::Uuid;
+
+    pub(crate) fn authn() -> ConfigMap {
+        let json = format!(
+            r#"
+            {{
+                "{}": {{
+                    "algorithm": "ES256",
+                    "audience": ["{}"],
+                    "key": "{}"
+                }},
+                "{}": {{
+                    "algorithm": "ES256",
+                    "audience": ["{}"],
+                    "key": "{}"
+                }}
+            }}
+            "#,
+            SVC_AUDIENCE, SVC_AUDIENCE, PUBKEY_PATH, TOKEN_ISSUER, USR_AUDIENCE, PUBKEY_PATH
+        );
+
+        serde_json::from_str(&json).expect("Failed to parse json string")
+    }
+
+    #[tokio::test]
+    async fn verify_replica_token() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let state = TestState::new(db_pool, TestAuthz::new(), Uuid::new_v4());
+
+        let token = state.internal_api().token().expect("Failed to build token");
+        let header =
+            HeaderValue::from_str(&format!("Bearer {}", token)).expect("Failed to build header");
+
+        verify_token(&header, &authn(), &state.config().id).expect("Failed to verify token");
+    }
+
+    #[tokio::test]
+    async fn reject_agent_token() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let state = TestState::new(db_pool, TestAuthz::new(), Uuid::new_v4());
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let header = HeaderValue::from_str(&format!("Bearer {}", agent.token()))
+            .expect("Failed to build header");
+
+        verify_token(&header, &authn(), &state.config().id).expect_err("Unexpectedly succeeded");
+
+        let header = HeaderValue::from_static("Bearer invalid");
+        verify_token(&header, &authn(), &state.config().id).expect_err("Unexpectedly succeeded");
+    }
+}
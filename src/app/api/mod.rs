@@ -1,5 +1,6 @@
 use axum::response::Response;
 
+pub mod internal_authn;
 pub mod v1;
 
 pub type AppError = crate::app::error::Error;
@@ -0,0 +1,369 @@
+use crate::{
+    app::{
+        api::AppResult,
+        error::{Error, ErrorExt, ErrorKind},
+        metrics::AuthzMeasure,
+        state::State,
+    },
+    authz::AuthzObject,
+    classroom::ClassroomId,
+    db::agent_session_history::{self, AgentLifetime},
+};
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{Extension, Path, Query},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_derive::Serialize;
+use sqlx::types::time::OffsetDateTime;
+use std::{collections::HashMap, ops::Bound};
+use svc_agent::AgentId;
+use svc_authn::Authenticable;
+use svc_utils::extractors::AgentIdExtractor;
+
+#[derive(Deserialize, Default)]
+pub struct Payload {
+    /// Start of the time window (unix time in milliseconds).
+    from: Option<i64>,
+    /// End of the time window (unix time in milliseconds).
+    to: Option<i64>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Interval {
+    started_at: i64,
+    ended_at: i64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Attendance {
+    agent_id: AgentId,
+    intervals: Vec<Interval>,
+    total_time: i64,
+    first_seen: i64,
+    last_seen: i64,
+}
+
+pub async fn list_attendance<S: State>(
+    Extension(state): Extension<S>,
+    Path(classroom_id): Path<ClassroomId>,
+    AgentIdExtractor(agent_id): AgentIdExtractor,
+    Query(payload): Query<Payload>,
+) -> AppResult {
+    do_list_attendance(state, classroom_id, agent_id, payload).await
+}
+
+async fn do_list_attendance<S: State>(
+    state: S,
+    classroom_id: ClassroomId,
+    agent_id: AgentId,
+    payload: Payload,
+) -> AppResult {
+    let account_id = agent_id.as_account_id();
+    let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
+
+    let audience = state
+        .lookup_known_authz_audience(account_id.audience())
+        .unwrap_or(account_id.audience())
+        .to_owned();
+
+    state
+        .authz()
+        .authorize(audience, account_id.clone(), object, "read".into())
+        .await
+        .measure()?;
+
+    let from = payload.from.map(from_millis).transpose()?;
+    let to = payload.to.map(from_millis).transpose()?;
+
+    if let (Some(from), Some(to)) = (from, to) {
+        if from > to {
+            return Err(Error::new(
+                ErrorKind::InvalidPayload,
+                anyhow!("`from` must be less than or equal to `to`"),
+            ));
+        }
+    }
+
+    let mut conn = state
+        .get_conn()
+        .await
+        .error(ErrorKind::DbConnAcquisitionFailed)?;
+
+    let lifetimes = agent_session_history::AttendanceQuery::new(classroom_id, from, to)
+        .execute(&mut conn)
+        .await
+        .context("Failed to get attendance")
+        .error(ErrorKind::DbQueryFailed)?;
+
+    Ok(Json(build_attendance(lifetimes)).into_response())
+}
+
+pub(super) fn from_millis(millis: i64) -> Result<OffsetDateTime, Error> {
+    OffsetDateTime::from_unix_timestamp_nanos(i128::from(millis) * 1_000_000)
+        .context("Invalid timestamp")
+        .error(ErrorKind::InvalidPayload)
+}
+
+fn to_millis(time: OffsetDateTime) -> i64 {
+    (time.unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+/// Groups lifetimes by agents and merges overlapping ones.
+fn build_attendance(lifetimes: Vec<AgentLifetime>) -> Vec<Attendance> {
+    let mut intervals = HashMap::<AgentId, Vec<(OffsetDateTime, OffsetDateTime)>>::new();
+
+    for AgentLifetime { agent_id, lifetime } in lifetimes {
+        let started_at = match lifetime.start {
+            Bound::Included(t) | Bound::Excluded(t) => t,
+            Bound::Unbounded => continue,
+        };
+
+        let ended_at = match lifetime.end {
+            Bound::Included(t) | Bound::Excluded(t) => t,
+            Bound::Unbounded => continue,
+        };
+
+        intervals
+            .entry(agent_id)
+            .or_default()
+            .push((started_at, ended_at));
+    }
+
+    let mut attendance = intervals
+        .into_iter()
+        .filter_map(|(agent_id, intervals)| {
+            let intervals = merge_intervals(intervals);
+            let first_seen = intervals.first()?.0;
+            let last_seen = intervals.last()?.1;
+            let total_time = intervals
+                .iter()
+                .map(|(started_at, ended_at)| (*ended_at - *started_at).whole_milliseconds() as i64)
+                .sum();
+
+            Some(Attendance {
+                agent_id,
+                intervals: intervals
+                    .into_iter()
+                    .map(|(started_at, ended_at)| Interval {
+                        started_at: to_millis(started_at),
+                        ended_at: to_millis(ended_at),
+                    })
+                    .collect(),
+                total_time,
+                first_seen: to_millis(first_seen),
+                last_seen: to_millis(last_seen),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    attendance.sort_by_key(|a| a.first_seen);
+    attendance
+}
+
+fn merge_intervals(
+    mut intervals: Vec<(OffsetDateTime, OffsetDateTime)>,
+) -> Vec<(OffsetDateTime, OffsetDateTime)> {
+    intervals.sort_by_key(|(started_at, _)| *started_at);
+
+    let mut merged: Vec<(OffsetDateTime, OffsetDateTime)> = Vec::with_capacity(intervals.len());
+    for (started_at, ended_at) in intervals {
+        match merged.last_mut() {
+            Some((_, last_ended_at)) if started_at <= *last_ended_at => {
+                *last_ended_at = std::cmp::max(*last_ended_at, ended_at);
+            }
+            _ => merged.push((started_at, ended_at)),
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{agent_session, replica},
+        test_helpers::prelude::*,
+    };
+    use axum::body::HttpBody;
+    use serde_json::Value;
+    use sqlx::postgres::types::PgRange;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+    use uuid::Uuid;
+
+    #[test]
+    fn merge_overlapping_intervals() {
+        let t = OffsetDateTime::now_utc();
+        let min = |m: u64| t + Duration::from_secs(m * 60);
+
+        let merged = merge_intervals(vec![
+            (min(10), min(20)),
+            (min(0), min(5)),
+            (min(15), min(30)),
+            (min(30), min(35)),
+            (min(40), min(50)),
+        ]);
+
+        assert_eq!(
+            merged,
+            vec![(min(0), min(5)), (min(10), min(35)), (min(40), min(50))]
+        );
+    }
+
+    #[test]
+    fn build_attendance_per_agent() {
+        let t = OffsetDateTime::from_unix_timestamp(1_600_000_000).unwrap();
+        let min = |m: u64| t + Duration::from_secs(m * 60);
+        let agent_1 = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let agent_2 = TestAgent::new("web", "user2", USR_AUDIENCE);
+        let lifetime = |agent: &TestAgent, start, end| AgentLifetime {
+            agent_id: agent.agent_id().to_owned(),
+            lifetime: PgRange::from((Bound::Included(start), Bound::Excluded(end))),
+        };
+
+        let attendance = build_attendance(vec![
+            lifetime(&agent_1, min(0), min(10)),
+            lifetime(&agent_2, min(5), min(6)),
+            lifetime(&agent_1, min(5), min(15)),
+            lifetime(&agent_1, min(20), min(25)),
+        ]);
+
+        assert_eq!(
+            attendance,
+            vec![
+                Attendance {
+                    agent_id: agent_1.agent_id().to_owned(),
+                    intervals: vec![
+                        Interval {
+                            started_at: to_millis(min(0)),
+                            ended_at: to_millis(min(15)),
+                        },
+                        Interval {
+                            started_at: to_millis(min(20)),
+                            ended_at: to_millis(min(25)),
+                        },
+                    ],
+                    total_time: 20 * 60 * 1000,
+                    first_seen: to_millis(min(0)),
+                    last_seen: to_millis(min(25)),
+                },
+                Attendance {
+                    agent_id: agent_2.agent_id().to_owned(),
+                    intervals: vec![Interval {
+                        started_at: to_millis(min(5)),
+                        ended_at: to_millis(min(6)),
+                    }],
+                    total_time: 60 * 1000,
+                    first_seen: to_millis(min(5)),
+                    last_seen: to_millis(min(6)),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn list_attendance_unauthorized() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let state = TestState::new(db_pool, TestAuthz::new(), Uuid::new_v4());
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let resp = do_list_attendance(
+            state,
+            classroom_id,
+            agent.agent_id().to_owned(),
+            Payload::default(),
+        )
+        .await
+        .expect_err("Unexpectedly succeeded")
+        .into_response();
+
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn list_attendance_success() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let replica_id = {
+            let mut conn = db_pool.get_conn().await;
+
+            let replica_id = replica::InsertQuery::new(
+                "presence-1".into(),
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .expect("Failed to create insert query for replica")
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert a replica")
+            .id;
+
+            let past = OffsetDateTime::now_utc() - Duration::from_secs(60 * 60);
+            let session = agent_session::AgentSession {
+                id: 1.into(),
+                agent_id: agent.agent_id().to_owned(),
+                classroom_id,
+                replica_id,
+                started_at: past,
+                device_id: String::new(),
+            };
+
+            agent_session_history::InsertQuery::new(&session)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session history");
+
+            agent_session::InsertQuery::new(
+                agent.agent_id(),
+                classroom_id,
+                replica_id,
+                past + Duration::from_secs(30 * 60),
+            )
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert an agent session");
+
+            replica_id
+        };
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            agent.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "read",
+        );
+
+        let state = TestState::new(db_pool, authz, replica_id);
+
+        let resp = do_list_attendance(
+            state,
+            classroom_id,
+            agent.agent_id().to_owned(),
+            Payload::default(),
+        )
+        .await
+        .expect("Failed to get attendance");
+
+        assert_eq!(resp.status(), 200);
+
+        let mut body = resp.into_body();
+        let body = body.data().await.unwrap().expect("Failed to get body");
+        let json: Value = serde_json::from_slice(&body).expect("Failed to deserialize body");
+
+        assert_eq!(json.as_array().map(|a| a.len()), Some(1));
+        assert_eq!(json[0]["agent_id"], agent.agent_id().to_string());
+        assert_eq!(json[0]["intervals"].as_array().map(|a| a.len()), Some(1));
+    }
+}
@@ -1,13 +1,13 @@
 use crate::{
     app::{
-        api::AppResult,
+        api::{v1::attendance::from_millis, AppResult},
         error::{ErrorExt, ErrorKind},
         metrics::AuthzMeasure,
         state::State,
     },
     authz::AuthzObject,
     classroom::ClassroomId,
-    db::agent_session,
+    db::agent_session::{self, Agent, AgentFilter},
 };
 use anyhow::Context;
 use axum::{
@@ -17,16 +17,39 @@ use axum::{
     Json,
 };
 use serde::Deserialize;
+use serde_derive::Serialize;
 use svc_agent::AgentId;
 use svc_authn::Authenticable;
 use svc_utils::extractors::AgentIdExtractor;
 
 const MAX_LIMIT: usize = 1_000;
+/// Allows to see all agents if the redacted view is enabled.
+const LIST_AGENTS_ACTION: &str = "list_agents";
 
 #[derive(Deserialize, Default)]
 pub struct Payload {
     sequence_id: Option<usize>,
     limit: Option<usize>,
+    audience: Option<String>,
+    /// Label of the agent, e.g. `web`.
+    label: Option<String>,
+    /// Role from the metadata of the agent.
+    role: Option<String>,
+    /// Agents connected at this time or later (unix time in milliseconds).
+    connected_since: Option<i64>,
+    /// Returns an object with the number of agents matching the filters
+    /// instead of the plain list.
+    #[serde(default)]
+    with_count: bool,
+}
+
+#[derive(Serialize)]
+struct AgentListResponse {
+    /// The number of agents matching the filters.
+    count: i64,
+    /// A page of agents, in the redacted view only agents with roles
+    /// from `agent_list.redacted_view_roles`.
+    agents: Vec<Agent>,
 }
 
 pub async fn list_agents<S: State>(
@@ -45,35 +68,98 @@ async fn do_list_agents<S: State>(
     payload: Payload,
 ) -> AppResult {
     let account_id = agent_id.as_account_id();
-    let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
 
     let audience = state
         .lookup_known_authz_audience(account_id.audience())
         .unwrap_or(account_id.audience())
         .to_owned();
 
+    let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
     state
         .authz()
-        .authorize(audience, account_id.clone(), object, "read".into())
+        .authorize(audience.clone(), account_id.clone(), object, "read".into())
         .await
         .measure()?;
 
+    let redacted_view_roles = match &state.config().agent_list.redacted_view_roles {
+        Some(roles) => {
+            let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
+            let result = state
+                .authz()
+                .authorize(
+                    audience,
+                    account_id.clone(),
+                    object,
+                    LIST_AGENTS_ACTION.into(),
+                )
+                .await
+                .measure();
+
+            match result {
+                Ok(_) => None,
+                Err(err) if matches!(err.kind(), svc_authz::ErrorKind::Forbidden(_)) => Some(roles),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        None => None,
+    };
+
+    let connected_since = payload.connected_since.map(from_millis).transpose()?;
+    let role = payload.role.map(|role| vec![role]);
+
+    let mut filter = AgentFilter {
+        audience: payload.audience.as_deref(),
+        label: payload.label.as_deref(),
+        roles: role.as_deref(),
+        connected_since,
+    };
+
     let mut conn = state
         .get_conn()
         .await
         .error(ErrorKind::DbConnAcquisitionFailed)?;
 
-    let agents = agent_session::AgentList::new(
-        classroom_id,
-        payload.sequence_id.unwrap_or_default(),
-        std::cmp::min(payload.limit.unwrap_or(MAX_LIMIT), MAX_LIMIT),
-    )
-    .execute(&mut conn)
-    .await
-    .context("Failed to get list of agents")
-    .error(ErrorKind::DbQueryFailed)?;
-
-    Ok(Json(agents).into_response())
+    let sequence_id = payload.sequence_id.unwrap_or_default();
+    let limit = std::cmp::min(payload.limit.unwrap_or(MAX_LIMIT), MAX_LIMIT);
+
+    let count = if payload.with_count {
+        let count = agent_session::FilteredCountQuery::new(classroom_id, filter)
+            .execute(&mut conn)
+            .await
+            .context("Failed to count agents")
+            .error(ErrorKind::DbQueryFailed)?;
+
+        Some(count)
+    } else {
+        None
+    };
+
+    // Only agents with visible roles are listed, even if another role is requested
+    let roles = redacted_view_roles.map(|visible_roles| {
+        visible_roles
+            .iter()
+            .filter(|visible_role| match filter.roles {
+                Some(roles) => roles.contains(visible_role),
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    });
+    if let Some(roles) = &roles {
+        filter.roles = Some(roles);
+    }
+
+    let agents = agent_session::AgentList::new(classroom_id, sequence_id, limit)
+        .filter(filter)
+        .execute(&mut conn)
+        .await
+        .context("Failed to get list of agents")
+        .error(ErrorKind::DbQueryFailed)?;
+
+    match count {
+        Some(count) => Ok(Json(AgentListResponse { count, agents }).into_response()),
+        None => Ok(Json(agents).into_response()),
+    }
 }
 
 #[cfg(test)]
@@ -85,10 +171,11 @@ mod tests {
             agent_session::{self, Agent},
             replica,
         },
+        session::AgentStatus,
         test_helpers::prelude::*,
     };
     use axum::{body::HttpBody, response::IntoResponse};
-    use serde_json::Value;
+    use serde_json::{json, Value};
     use sqlx::types::time::OffsetDateTime;
     use std::net::{IpAddr, Ipv4Addr};
     use uuid::Uuid;
@@ -186,10 +273,249 @@ mod tests {
         let object = Agent {
             sequence_id: 2.into(),
             agent_id: agent2.agent_id().to_owned(),
+            status: AgentStatus::Online,
+            status_text: None,
+            idle: false,
+            metadata: None,
         };
 
         let json = serde_json::to_string(&vec![object]).expect("Failed to serialize an agent");
 
         assert_eq!(body, json);
     }
+
+    async fn insert_agents(
+        db_pool: &TestDb,
+        classroom_id: ClassroomId,
+        agents: &[(&TestAgent, Value)],
+    ) -> Uuid {
+        let mut conn = db_pool.get_conn().await;
+
+        let replica_id =
+            replica::InsertQuery::new("presence-1".into(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))
+                .expect("Failed to create insert query for replica")
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert a replica")
+                .id;
+
+        for (agent, metadata) in agents {
+            agent_session::InsertQuery::new(
+                agent.agent_id(),
+                classroom_id,
+                replica_id,
+                OffsetDateTime::now_utc(),
+            )
+            .metadata(Some(metadata))
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert an agent session");
+        }
+
+        replica_id
+    }
+
+    #[tokio::test]
+    async fn list_agents_filtered() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let teacher = TestAgent::new("web", "teacher", USR_AUDIENCE);
+        let student1 = TestAgent::new("web", "student1", USR_AUDIENCE);
+        let student2 = TestAgent::new("mobile", "student2", USR_AUDIENCE);
+
+        let replica_id = insert_agents(
+            &db_pool,
+            classroom_id,
+            &[
+                (&teacher, json!({ "role": "teacher" })),
+                (&student1, json!({ "role": "student" })),
+                (&student2, json!({ "role": "student" })),
+            ],
+        )
+        .await;
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            teacher.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "read",
+        );
+
+        let state = TestState::new(db_pool, authz, replica_id);
+
+        let resp = do_list_agents(
+            state,
+            classroom_id,
+            teacher.agent_id().to_owned(),
+            Payload {
+                label: Some("web".into()),
+                role: Some("student".into()),
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to get list of agents");
+
+        assert_eq!(resp.status(), 200);
+
+        let mut body = resp.into_body();
+        let body = body.data().await.unwrap().expect("Failed to get body");
+        let json: Value = serde_json::from_slice(&body).expect("Failed to deserialize body");
+
+        let agents = json.as_array().expect("Failed to get agents");
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0]["agent_id"], student1.agent_id().to_string());
+    }
+
+    #[tokio::test]
+    async fn list_agents_filtered_by_newer_device() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let replica_id = {
+            let mut conn = db_pool.get_conn().await;
+
+            let replica_id = replica::InsertQuery::new(
+                "presence-1".into(),
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .expect("Failed to create insert query for replica")
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert a replica")
+            .id;
+
+            // Only the newer device matches the filter
+            for (device_id, role) in [("phone", "student"), ("laptop", "teacher")] {
+                agent_session::InsertQuery::new(
+                    agent.agent_id(),
+                    classroom_id,
+                    replica_id,
+                    OffsetDateTime::now_utc(),
+                )
+                .device(device_id)
+                .metadata(Some(&json!({ "role": role })))
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session");
+            }
+
+            replica_id
+        };
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            agent.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "read",
+        );
+
+        let state = TestState::new(db_pool, authz, replica_id);
+
+        let resp = do_list_agents(
+            state,
+            classroom_id,
+            agent.agent_id().to_owned(),
+            Payload {
+                role: Some("teacher".into()),
+                with_count: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to get list of agents");
+
+        let mut body = resp.into_body();
+        let body = body.data().await.unwrap().expect("Failed to get body");
+        let json: Value = serde_json::from_slice(&body).expect("Failed to deserialize body");
+
+        let agents = json["agents"].as_array().expect("Failed to get agents");
+        assert_eq!(json["count"], 1);
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0]["metadata"]["role"], "teacher");
+    }
+
+    #[tokio::test]
+    async fn list_agents_redacted() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let teacher = TestAgent::new("web", "teacher", USR_AUDIENCE);
+        let student1 = TestAgent::new("web", "student1", USR_AUDIENCE);
+        let student2 = TestAgent::new("web", "student2", USR_AUDIENCE);
+
+        let replica_id = insert_agents(
+            &db_pool,
+            classroom_id,
+            &[
+                (&teacher, json!({ "role": "teacher" })),
+                (&student1, json!({ "role": "student" })),
+                (&student2, json!({ "role": "student" })),
+            ],
+        )
+        .await;
+
+        let mut authz = TestAuthz::new();
+        for agent in [&teacher, &student1] {
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "read",
+            );
+        }
+        authz.allow(
+            teacher.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            LIST_AGENTS_ACTION,
+        );
+
+        let mut state = TestState::new(db_pool, authz, replica_id);
+        state.config_mut().agent_list.redacted_view_roles = Some(vec!["teacher".into()]);
+
+        // Students see only the number of agents and teachers
+        let resp = do_list_agents(
+            state.clone(),
+            classroom_id,
+            student1.agent_id().to_owned(),
+            Payload {
+                with_count: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("Failed to get list of agents");
+
+        let mut body = resp.into_body();
+        let body = body.data().await.unwrap().expect("Failed to get body");
+        let json: Value = serde_json::from_slice(&body).expect("Failed to deserialize body");
+
+        assert_eq!(json["count"], 3);
+        let agents = json["agents"].as_array().expect("Failed to get agents");
+        assert_eq!(agents.len(), 1);
+        assert_eq!(agents[0]["agent_id"], teacher.agent_id().to_string());
+
+        // Teachers see everyone
+        let resp = do_list_agents(
+            state,
+            classroom_id,
+            teacher.agent_id().to_owned(),
+            Payload::default(),
+        )
+        .await
+        .expect("Failed to get list of agents");
+
+        let mut body = resp.into_body();
+        let body = body.data().await.unwrap().expect("Failed to get body");
+        let json: Value = serde_json::from_slice(&body).expect("Failed to deserialize body");
+
+        // The plain list is returned without `with_count`
+        let agents = json.as_array().expect("Failed to get agents");
+        assert_eq!(agents.len(), 3);
+    }
 }
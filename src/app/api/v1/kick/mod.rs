@@ -0,0 +1,248 @@
+use crate::{
+    app::{
+        api::{
+            v1::session::{Reason, Response as DeleteResponse},
+            AppResult,
+        },
+        error::{ErrorExt, ErrorKind},
+        metrics::AuthzMeasure,
+        replica,
+        session_manager::DeleteSession,
+        state::State,
+    },
+    authz::AuthzObject,
+    classroom::ClassroomId,
+    db::{self, classroom_ban},
+    session::SessionKey,
+};
+use anyhow::{anyhow, Context};
+use axum::{
+    extract::{Extension, Path},
+    response::IntoResponse,
+    Json,
+};
+use http::StatusCode;
+use serde_derive::Deserialize;
+use sqlx::types::time::OffsetDateTime;
+use std::time::Duration;
+use svc_agent::AgentId;
+use svc_authn::Authenticable;
+use svc_utils::extractors::AgentIdExtractor;
+
+/// A year is long enough for any ban.
+const MAX_BAN_DURATION: u64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize, Default)]
+pub struct KickPayload {
+    /// Seconds during which the account of the agent can't connect to the classroom again.
+    ban_duration: Option<u64>,
+}
+
+pub async fn kick_agent<S: State>(
+    Extension(state): Extension<S>,
+    Path((classroom_id, target_id)): Path<(ClassroomId, AgentId)>,
+    AgentIdExtractor(agent_id): AgentIdExtractor,
+    payload: Option<Json<KickPayload>>,
+) -> AppResult {
+    let payload = payload.map(|Json(payload)| payload).unwrap_or_default();
+    do_kick_agent(state, classroom_id, agent_id, target_id, payload).await
+}
+
+async fn do_kick_agent<S: State>(
+    state: S,
+    classroom_id: ClassroomId,
+    agent_id: AgentId,
+    target_id: AgentId,
+    payload: KickPayload,
+) -> AppResult {
+    let account_id = agent_id.as_account_id();
+    let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
+
+    let audience = state
+        .lookup_known_authz_audience(account_id.audience())
+        .unwrap_or(account_id.audience())
+        .to_owned();
+
+    state
+        .authz()
+        .authorize(audience, account_id.clone(), object, "kick".into())
+        .await
+        .measure()?;
+
+    let mut conn = state
+        .get_conn()
+        .await
+        .error(ErrorKind::DbConnAcquisitionFailed)?;
+
+    // The ban is recorded even if the agent isn't connected at the moment
+    if let Some(ban_duration) = payload.ban_duration {
+        if ban_duration > MAX_BAN_DURATION {
+            return Err(anyhow!("ban_duration is too large")).error(ErrorKind::InvalidPayload);
+        }
+
+        let expires_at = OffsetDateTime::now_utc() + Duration::from_secs(ban_duration);
+
+        classroom_ban::InsertQuery::new(classroom_id, target_id.as_account_id(), expires_at)
+            .execute(&mut conn)
+            .await
+            .context("Failed to ban agent")
+            .error(ErrorKind::DbQueryFailed)?;
+    }
+
+    let session_key = SessionKey::new(target_id, classroom_id);
+
+    // Attempt to kick the agent on the same replica, the agent is kicked on all devices
+    let result = state
+        .kick_session(session_key.clone())
+        .await
+        .error(ErrorKind::ReceivingResponseFailed)?;
+
+    let mut kicked = matches!(result, DeleteSession::Success(_));
+
+    // Attempt to kick the agent on other replicas
+    let replicas =
+        db::replica::ListByAgentQuery::new(classroom_id, &session_key.agent_id, state.replica_id())
+            .execute(&mut conn)
+            .await
+            .context("Failed to get replicas of the agent sessions")
+            .error(ErrorKind::DbQueryFailed)?;
+
+    for replica in replicas {
+        match replica::kick_connection(&state, &replica, session_key.clone())
+            .await
+            .context("Failed to kick agent on another replica")
+            .error(ErrorKind::ReceivingResponseFailed)?
+        {
+            DeleteResponse::DeleteSuccess(_) => kicked = true,
+            DeleteResponse::DeleteFailure(Reason::NotFound) => {}
+            DeleteResponse::DeleteFailure(reason) => {
+                return Err(anyhow!(
+                    "Failed to kick agent on another replica, reason = {}",
+                    reason
+                ))
+                .error(ErrorKind::ReceivingResponseFailed);
+            }
+        }
+    }
+
+    // The ban is done anyway, there is nothing the moderator has to retry
+    if !kicked && payload.ban_duration.is_none() {
+        return Err(anyhow!("Agent is not connected to the classroom"))
+            .error(ErrorKind::SessionNotFound);
+    }
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::prelude::*;
+    use axum::{body::HttpBody, response::IntoResponse};
+    use serde_json::Value;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn kick_agent_unauthorized() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let state = TestState::new(db_pool, TestAuthz::new(), Uuid::new_v4());
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let moderator = TestAgent::new("web", "moderator", USR_AUDIENCE);
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let resp = do_kick_agent(
+            state,
+            classroom_id,
+            moderator.agent_id().to_owned(),
+            agent.agent_id().to_owned(),
+            KickPayload::default(),
+        )
+        .await
+        .expect_err("Unexpectedly succeeded")
+        .into_response();
+
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[tokio::test]
+    async fn kick_agent_not_connected() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let moderator = TestAgent::new("web", "moderator", USR_AUDIENCE);
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            moderator.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "kick",
+        );
+
+        let state = TestState::new(db_pool, authz, Uuid::new_v4());
+
+        let resp = do_kick_agent(
+            state,
+            classroom_id,
+            moderator.agent_id().to_owned(),
+            agent.agent_id().to_owned(),
+            KickPayload { ban_duration: None },
+        )
+        .await
+        .expect_err("Unexpectedly succeeded")
+        .into_response();
+
+        assert_eq!(resp.status(), 404);
+
+        let mut body = resp.into_body();
+        let body = body.data().await.unwrap().expect("Failed to get body");
+        let json: Value = serde_json::from_slice(&body).expect("Failed to deserialize body");
+
+        assert_eq!(json["type"], "session_not_found");
+    }
+
+    #[tokio::test]
+    async fn kick_agent_not_connected_with_ban() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let moderator = TestAgent::new("web", "moderator", USR_AUDIENCE);
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            moderator.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "kick",
+        );
+
+        let state = TestState::new(db_pool.clone(), authz, Uuid::new_v4());
+
+        let resp = do_kick_agent(
+            state,
+            classroom_id,
+            moderator.agent_id().to_owned(),
+            agent.agent_id().to_owned(),
+            KickPayload {
+                ban_duration: Some(600),
+            },
+        )
+        .await
+        .expect("Failed to kick agent")
+        .into_response();
+
+        assert_eq!(resp.status(), 204);
+
+        let mut conn = db_pool.get_conn().await;
+        let ban = classroom_ban::FindQuery::new(classroom_id, agent.account_id())
+            .execute(&mut conn)
+            .await
+            .expect("Failed to find ban");
+
+        assert!(ban.is_some());
+    }
+}
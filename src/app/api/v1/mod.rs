@@ -1,7 +1,9 @@
 use axum::{body::Body, response::Response};
 
+pub mod attendance;
 pub mod classroom;
 pub mod counter;
+pub mod kick;
 pub mod session;
 
 pub async fn healthz() -> &'static str {
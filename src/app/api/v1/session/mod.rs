@@ -8,7 +8,7 @@ use crate::{
     session::{SessionId, SessionKey},
 };
 use anyhow::Context;
-use axum::{body, response::IntoResponse, Extension, Json};
+use axum::{body, Extension, Json};
 use http::StatusCode;
 use serde_derive::{Deserialize, Serialize};
 use std::fmt;
@@ -50,17 +50,55 @@ pub async fn delete<S: State>(
     do_delete(state, payload).await
 }
 
+pub async fn kick<S: State>(
+    Extension(state): Extension<S>,
+    Json(payload): Json<DeletePayload>,
+) -> AppResult {
+    do_kick(state, payload).await
+}
+
 async fn do_delete<S: State>(state: S, payload: DeletePayload) -> AppResult {
-    let (status, resp) = match state.delete_session(payload.session_key).await {
+    let result = state.delete_session(payload.session_key).await;
+    build_response(result, "Failed to delete session")
+}
+
+async fn do_kick<S: State>(state: S, payload: DeletePayload) -> AppResult {
+    let result = state.kick_session(payload.session_key).await;
+    build_response(result, "Failed to kick session")
+}
+
+fn build_response(result: anyhow::Result<DeleteSession>, error_msg: &str) -> AppResult {
+    let (status, resp) = to_response(result, error_msg);
+
+    let body = serde_json::to_string(&resp)
+        .context("Failed to serialize response")
+        .error(ErrorKind::SerializationFailed)?;
+
+    let resp = http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(body::boxed(body::Full::from(body)))
+        .context("Failed to build response for delete session")
+        .error(ErrorKind::ResponseBuildFailed)?;
+
+    Ok(resp)
+}
+
+/// Converts the result of a session command into the response to another replica.
+pub fn to_response(
+    result: anyhow::Result<DeleteSession>,
+    error_msg: &str,
+) -> (StatusCode, Response) {
+    match result {
         Ok(DeleteSession::Success(session_id)) => {
-            return Ok(Json(Response::DeleteSuccess(session_id)).into_response());
+            (StatusCode::OK, Response::DeleteSuccess(session_id))
         }
         Ok(DeleteSession::NotFound) => (
             StatusCode::NOT_FOUND,
             Response::DeleteFailure(Reason::NotFound),
         ),
         Err(e) => {
-            error!(error = %e, "Failed to delete session");
+            error!(error = %e, "{}", error_msg);
             Error::new(ErrorKind::ReceivingResponseFailed, e).notify_sentry();
 
             (
@@ -68,17 +106,5 @@ async fn do_delete<S: State>(state: S, payload: DeletePayload) -> AppResult {
                 Response::DeleteFailure(Reason::MessagingFailed),
             )
         }
-    };
-
-    let body = serde_json::to_string(&resp)
-        .context("Failed to serialize response")
-        .error(ErrorKind::SerializationFailed)?;
-
-    let resp = http::Response::builder()
-        .status(status)
-        .body(body::boxed(body::Full::from(body)))
-        .context("Failed to build response for delete session")
-        .error(ErrorKind::ResponseBuildFailed)?;
-
-    Ok(resp)
+    }
 }
@@ -1,8 +1,20 @@
-use anyhow::{Context, Result};
+use crate::config::AddressDiscovery;
+use anyhow::{anyhow, Context, Result};
 use std::net::IpAddr;
 
-#[cfg(not(feature = "local_ip"))]
-pub async fn get_ip(replica_label: &str) -> Result<IpAddr> {
+pub async fn get_ip(replica_label: &str, discovery: &AddressDiscovery) -> Result<IpAddr> {
+    match discovery {
+        AddressDiscovery::Kubernetes => get_pod_ip(replica_label).await,
+        AddressDiscovery::Address { address } => Ok(*address),
+        AddressDiscovery::Hostname { hostname } => resolve_hostname(hostname).await,
+        AddressDiscovery::Interface { name: Some(name) } => get_interface_ip(name),
+        AddressDiscovery::Interface { name: None } => {
+            local_ip_address::local_ip().context("Failed to get local ip")
+        }
+    }
+}
+
+async fn get_pod_ip(replica_label: &str) -> Result<IpAddr> {
     use k8s_openapi::api::core::v1 as api;
     use kube::{Api, Client};
 
@@ -23,9 +35,66 @@ pub async fn get_ip(replica_label: &str) -> Result<IpAddr> {
     Ok(ip)
 }
 
-#[cfg(feature = "local_ip")]
-pub async fn get_ip(_replica_label: &str) -> Result<IpAddr> {
-    let ip = local_ip_address::local_ip().context("Failed to get local ip")?;
+async fn resolve_hostname(hostname: &str) -> Result<IpAddr> {
+    let mut addrs = tokio::net::lookup_host((hostname, 0))
+        .await
+        .with_context(|| format!("Failed to resolve hostname = {hostname}"))?;
 
-    Ok(ip)
+    addrs
+        .next()
+        .map(|addr| addr.ip())
+        .ok_or_else(|| anyhow!("Hostname = {hostname} resolved to no addresses"))
+}
+
+fn get_interface_ip(name: &str) -> Result<IpAddr> {
+    let interfaces =
+        local_ip_address::list_afinet_netifas().context("Failed to list network interfaces")?;
+
+    interfaces
+        .into_iter()
+        .filter(|(iface, _)| iface == name)
+        .map(|(_, ip)| ip)
+        // IPv4 goes first, the same as for the first non-loopback interface
+        .min_by_key(|ip| ip.is_ipv6())
+        .ok_or_else(|| anyhow!("Network interface = {name} has no addresses"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn explicit_address() {
+        let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let ip = get_ip("presence-0", &AddressDiscovery::Address { address })
+            .await
+            .expect("Failed to get ip");
+
+        assert_eq!(ip, address);
+    }
+
+    #[tokio::test]
+    async fn resolve_localhost() {
+        let discovery = AddressDiscovery::Hostname {
+            hostname: "localhost".to_owned(),
+        };
+
+        let ip = get_ip("presence-0", &discovery)
+            .await
+            .expect("Failed to get ip");
+
+        assert!(ip.is_loopback());
+    }
+
+    #[tokio::test]
+    async fn unknown_interface() {
+        let discovery = AddressDiscovery::Interface {
+            name: Some("presence-unknown0".to_owned()),
+        };
+
+        get_ip("presence-0", &discovery)
+            .await
+            .expect_err("Unexpectedly succeeded");
+    }
 }
@@ -19,6 +19,7 @@ struct ErrorKindProperties {
 pub enum ErrorKind {
     DbConnAcquisitionFailed,
     DbQueryFailed,
+    InvalidPayload,
     AccessDenied,
     AuthorizationFailed,
     SerializationFailed,
@@ -26,6 +27,12 @@ pub enum ErrorKind {
     ShutdownFailed,
     MovingSessionToHistoryFailed,
     ReceivingResponseFailed,
+    SessionNotFound,
+    HeartbeatFailed,
+    Unauthenticated,
+    WebhookDeliveryFailed,
+    OccupancyUpdateFailed,
+    ReauthorizationFailed,
 }
 
 impl ErrorKind {
@@ -57,6 +64,12 @@ impl From<ErrorKind> for ErrorKindProperties {
                 title: "Database query failed",
                 is_notify_sentry: true,
             },
+            ErrorKind::InvalidPayload => ErrorKindProperties {
+                status: StatusCode::BAD_REQUEST,
+                kind: "invalid_payload",
+                title: "Invalid payload",
+                is_notify_sentry: false,
+            },
             ErrorKind::AccessDenied => ErrorKindProperties {
                 status: StatusCode::FORBIDDEN,
                 kind: "access_denied",
@@ -99,6 +112,42 @@ impl From<ErrorKind> for ErrorKindProperties {
                 title: "Receiving response failed",
                 is_notify_sentry: true,
             },
+            ErrorKind::SessionNotFound => ErrorKindProperties {
+                status: StatusCode::NOT_FOUND,
+                kind: "session_not_found",
+                title: "Session not found",
+                is_notify_sentry: false,
+            },
+            ErrorKind::HeartbeatFailed => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "heartbeat_failed",
+                title: "Heartbeat failed",
+                is_notify_sentry: true,
+            },
+            ErrorKind::Unauthenticated => ErrorKindProperties {
+                status: StatusCode::UNAUTHORIZED,
+                kind: "unauthenticated",
+                title: "Unauthenticated",
+                is_notify_sentry: false,
+            },
+            ErrorKind::WebhookDeliveryFailed => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "webhook_delivery_failed",
+                title: "Webhook delivery failed",
+                is_notify_sentry: true,
+            },
+            ErrorKind::OccupancyUpdateFailed => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "occupancy_update_failed",
+                title: "Occupancy update failed",
+                is_notify_sentry: true,
+            },
+            ErrorKind::ReauthorizationFailed => ErrorKindProperties {
+                status: StatusCode::INTERNAL_SERVER_ERROR,
+                kind: "reauthorization_failed",
+                title: "Reauthorization failed",
+                is_notify_sentry: true,
+            },
         }
     }
 }
@@ -1,14 +1,23 @@
 use crate::{
-    app::state::State,
-    db::{agent_session, agent_session_history},
+    app::{
+        occupancy::{self, Change},
+        state::State,
+    },
+    db::{
+        agent_session::{self, AgentSession},
+        agent_session_history, replica,
+    },
     session::SessionId,
 };
 use anyhow::{anyhow, Result};
-use sqlx::Connection;
+use sqlx::{types::time::OffsetDateTime, Connection, PgConnection};
+use tracing::warn;
 use uuid::Uuid;
 
 /// Moves all session from the `agent_session` table in `agent_session_history`.
-pub async fn move_all_sessions<S: State>(state: S, replica_id: Uuid) -> Result<()> {
+///
+/// Returns occupancy changes of their classrooms to publish.
+pub async fn move_all_sessions<S: State>(state: S, replica_id: Uuid) -> Result<Vec<Change>> {
     let mut conn = state
         .get_conn()
         .await
@@ -19,9 +28,78 @@ pub async fn move_all_sessions<S: State>(state: S, replica_id: Uuid) -> Result<(
         .await
         .map_err(|e| anyhow!("failed to acquire transaction: {:?}", e))?;
 
+    let sessions = agent_session::ListQuery::by_replica(replica_id)
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("failed to get sessions of replica: {:?}", e))?;
+
+    move_replica_sessions(&mut tx, replica_id).await?;
+
+    let changes = occupancy::update_sessions(&mut tx, &sessions).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| anyhow!("failed to commit transaction: {:?}", e))?;
+
+    Ok(changes)
+}
+
+/// Moves sessions of replicas which haven't sent a heartbeat since `heartbeat_before`
+/// to history and deletes the replicas.
+///
+/// Returns moved sessions, so other agents can be notified that these agents left,
+/// and occupancy changes of their classrooms to publish.
+pub async fn move_stale_replicas<S: State>(
+    state: S,
+    heartbeat_before: OffsetDateTime,
+) -> Result<(Vec<AgentSession>, Vec<Change>)> {
+    let mut conn = state
+        .get_conn()
+        .await
+        .map_err(|e| anyhow!("failed to get db connection: {:?}", e))?;
+
+    let mut tx = conn
+        .begin()
+        .await
+        .map_err(|e| anyhow!("failed to acquire transaction: {:?}", e))?;
+
+    let replica_ids = replica::StaleListQuery::new(heartbeat_before, state.replica_id())
+        .execute(&mut tx)
+        .await
+        .map_err(|e| anyhow!("failed to get stale replicas: {:?}", e))?;
+
+    let mut sessions = vec![];
+    for replica_id in replica_ids {
+        warn!(%replica_id, "replica is stale, moving its sessions to history");
+
+        let replica_sessions = agent_session::ListQuery::by_replica(replica_id)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| anyhow!("failed to get sessions of stale replica: {:?}", e))?;
+
+        move_replica_sessions(&mut tx, replica_id).await?;
+
+        replica::DeleteQuery::new(replica_id)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| anyhow!("failed to delete stale replica: {:?}", e))?;
+
+        sessions.extend(replica_sessions);
+    }
+
+    let changes = occupancy::update_sessions(&mut tx, &sessions).await?;
+
+    tx.commit()
+        .await
+        .map_err(|e| anyhow!("failed to commit transaction: {:?}", e))?;
+
+    Ok((sessions, changes))
+}
+
+async fn move_replica_sessions(conn: &mut PgConnection, replica_id: Uuid) -> Result<()> {
     // Update lifetime in existing histories
     let mut session_ids = agent_session_history::UpdateLifetimesQuery::by_replica(replica_id)
-        .execute(&mut tx)
+        .execute(conn)
         .await
         .map_err(|e| anyhow!("failed to update lifetime in existing histories: {:?}", e))?;
 
@@ -29,7 +107,7 @@ pub async fn move_all_sessions<S: State>(state: S, replica_id: Uuid) -> Result<(
     let inserted_session_ids =
         agent_session_history::InsertFromAgentSessionQuery::by_replica(replica_id)
             .except(&session_ids)
-            .execute(&mut tx)
+            .execute(conn)
             .await
             .map_err(|e| anyhow!("failed to create histories from agent_session: {:?}", e))?;
 
@@ -37,18 +115,22 @@ pub async fn move_all_sessions<S: State>(state: S, replica_id: Uuid) -> Result<(
 
     // Delete moved sessions
     agent_session::DeleteQuery::by_replica(replica_id, &session_ids)
-        .execute(&mut tx)
+        .execute(conn)
         .await
         .map_err(|e| anyhow!("failed to delete agent sessions: {:?}", e))?;
 
-    tx.commit()
-        .await
-        .map_err(|e| anyhow!("failed to commit transaction: {:?}", e))?;
-
     Ok(())
 }
 
-pub async fn move_single_session<S: State>(state: S, session_id: SessionId) -> Result<()> {
+/// Moves the session to history. If the history of the agent in the classroom overlaps the session,
+/// e.g. of another device or of a previous connection, the history is extended instead,
+/// so it starts at whichever of them started first.
+///
+/// Returns the occupancy change of the classroom to publish.
+pub async fn move_single_session<S: State>(
+    state: S,
+    session_id: SessionId,
+) -> Result<Option<Change>> {
     let mut conn = state
         .get_conn()
         .await
@@ -76,7 +158,7 @@ pub async fn move_single_session<S: State>(state: S, session_id: SessionId) -> R
 
     match session_history {
         Some(history) => {
-            agent_session_history::UpdateLifetimeQuery::new(history.id, history.lifetime.start)
+            agent_session_history::UpdateLifetimeQuery::new(history.id, session.started_at)
                 .execute(&mut tx)
                 .await
                 .map_err(|e| anyhow!("Failed to update agent_session_history lifetime: {:?}", e))?;
@@ -94,11 +176,13 @@ pub async fn move_single_session<S: State>(state: S, session_id: SessionId) -> R
         .await
         .map_err(|e| anyhow!("Failed to delete agent_session: {:?}", e))?;
 
+    let change = occupancy::update(&mut tx, session.classroom_id, &session.agent_id).await?;
+
     tx.commit()
         .await
         .map_err(|e| anyhow!("Failed to commit transaction: {:?}", e))?;
 
-    Ok(())
+    Ok(change)
 }
 
 #[cfg(test)]
@@ -106,7 +190,10 @@ mod tests {
     use super::*;
     use crate::{classroom::ClassroomId, db::replica, test_helpers::prelude::*};
     use sqlx::types::time::OffsetDateTime;
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        ops::Bound,
+    };
     use uuid::Uuid;
 
     mod move_all_sessions {
@@ -210,6 +297,7 @@ mod tests {
                     classroom_id: classroom_id_1,
                     replica_id,
                     started_at: past,
+                    device_id: String::new(),
                 };
 
                 agent_session_history::InsertQuery::new(&session)
@@ -260,6 +348,83 @@ mod tests {
         }
     }
 
+    mod move_stale_replicas {
+        use super::*;
+        use std::time::Duration;
+
+        #[tokio::test]
+        async fn move_sessions_of_stale_replicas() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+            let agent_1 = TestAgent::new("http", "user1", USR_AUDIENCE);
+            let agent_2 = TestAgent::new("http", "user2", USR_AUDIENCE);
+
+            let (replica_id, stale_replica_id) = {
+                let mut conn = db_pool.get_conn().await;
+
+                let mut replica_ids = vec![];
+                for (label, agent) in [("presence-1", &agent_1), ("presence-2", &agent_2)] {
+                    let replica_id = replica::InsertQuery::new(
+                        label.into(),
+                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                    )
+                    .expect("Failed to create insert query for replica")
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to insert a replica")
+                    .id;
+
+                    agent_session::InsertQuery::new(
+                        agent.agent_id(),
+                        classroom_id,
+                        replica_id,
+                        OffsetDateTime::now_utc(),
+                    )
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to insert an agent session");
+
+                    replica_ids.push(replica_id);
+                }
+
+                (replica_ids[0], replica_ids[1])
+            };
+
+            // Both replicas are stale, but the current one is never cleaned up
+            let heartbeat_before = OffsetDateTime::now_utc() + Duration::from_secs(60);
+            let state = TestState::new(db_pool.clone(), TestAuthz::new(), replica_id);
+            let (sessions, _) = move_stale_replicas(state, heartbeat_before)
+                .await
+                .expect("Failed to move stale replicas");
+
+            assert_eq!(sessions.len(), 1);
+            assert_eq!(&sessions[0].agent_id, agent_2.agent_id());
+            assert_eq!(sessions[0].replica_id, stale_replica_id);
+
+            let mut conn = db_pool.get_conn().await;
+            let agents_count = factory::agent_session::AgentSessionCounter::count(&mut conn)
+                .await
+                .expect("Failed to count agent session");
+
+            let history_count =
+                factory::agent_session_history::AgentSessionHistoryCounter::count(&mut conn)
+                    .await
+                    .expect("Failed to count agent session history");
+
+            assert_eq!(agents_count, 1);
+            assert_eq!(history_count, 1);
+
+            let result = replica::HeartbeatQuery::new(stale_replica_id)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to update heartbeat");
+
+            assert_eq!(result.rows_affected(), 0);
+        }
+    }
+
     mod move_single_session {
         use super::*;
 
@@ -368,5 +533,72 @@ mod tests {
 
             assert_eq!(history_count, 1);
         }
+
+        #[tokio::test]
+        async fn keep_start_of_earlier_history() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let history_started_at =
+                OffsetDateTime::now_utc() - std::time::Duration::from_secs(3600);
+
+            let (session, replica_id) = {
+                let mut conn = db_pool.get_conn().await;
+
+                let replica_id = replica::InsertQuery::new(
+                    "presence-1".into(),
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                )
+                .expect("Failed to create insert query for replica")
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert a replica")
+                .id;
+
+                let session = agent_session::InsertQuery::new(
+                    agent.agent_id(),
+                    classroom_id,
+                    replica_id,
+                    OffsetDateTime::now_utc() - std::time::Duration::from_secs(600),
+                )
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session");
+
+                // The single device reconnected, the history of the previous connection is still open
+                let previous = AgentSession {
+                    started_at: history_started_at,
+                    ..session.clone()
+                };
+                agent_session_history::InsertQuery::new(&previous)
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to insert an agent session history");
+
+                (session, replica_id)
+            };
+
+            let state = TestState::new(db_pool.clone(), TestAuthz::new(), replica_id);
+            move_single_session(state, session.id)
+                .await
+                .expect("Failed to move session to history");
+
+            let mut conn = db_pool.get_conn().await;
+            let history =
+                agent_session_history::LastLifetimeQuery::new(classroom_id, agent.agent_id())
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to get agent session history")
+                    .expect("History not found");
+
+            let started_at = match history.lifetime.start {
+                Bound::Included(t) | Bound::Excluded(t) => t,
+                Bound::Unbounded => panic!("History is unbounded"),
+            };
+            // Postgres keeps microseconds only
+            assert_eq!((started_at - history_started_at).whole_milliseconds(), 0);
+        }
     }
 }
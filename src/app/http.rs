@@ -1,10 +1,12 @@
 use crate::app::{
-    api::v1,
+    api::{internal_authn, v1},
     state::{AppState, State},
     ws,
 };
 use axum::{
+    body::Body,
     extract::Extension,
+    middleware,
     routing::{delete, get, post},
     Router,
 };
@@ -27,6 +29,14 @@ fn api_router() -> Router {
             "/api/v1/classrooms/:classroom_id/agents",
             get(v1::classroom::list_agents::<AppState>).options(v1::options),
         )
+        .metered_route(
+            "/api/v1/classrooms/:classroom_id/attendance",
+            get(v1::attendance::list_attendance::<AppState>).options(v1::options),
+        )
+        .metered_route(
+            "/api/v1/classrooms/:classroom_id/agents/:agent_id/kick",
+            post(v1::kick::kick_agent::<AppState>).options(v1::options),
+        )
         .metered_route(
             "/api/v1/counters/agent",
             post(v1::counter::count_agents::<AppState>),
@@ -34,12 +44,19 @@ fn api_router() -> Router {
 }
 
 fn ws_router() -> Router {
-    Router::new().route("/ws", get(ws::handler::<AppState>))
+    Router::new()
+        .route("/ws", get(ws::handler::<AppState>))
+        .route("/sse", get(ws::sse_handler::<AppState>))
 }
 
-pub fn internal_router<S: State>(state: S) -> Router {
+pub fn internal_router<S: State>(state: S, authn: svc_authn::jose::ConfigMap) -> Router {
     Router::new()
         .route("/api/v1/sessions", delete(v1::session::delete::<AppState>))
+        .route("/api/v1/sessions/kick", post(v1::session::kick::<AppState>))
+        .layer(middleware::from_fn(
+            internal_authn::authenticate_replica::<AppState, Body>,
+        ))
+        .layer(Extension(Arc::new(authn)))
         .layer(Extension(state))
         .layer(LogLayer::new())
 }
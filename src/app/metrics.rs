@@ -51,12 +51,27 @@ impl Metrics {
     pub fn ws_connection_success(&self) -> &IntCounter {
         &self.inner.ws_connection_success
     }
+
+    pub fn sse_connection_total(&self) -> &IntGauge {
+        &self.inner.sse_connection_total
+    }
+
+    pub fn sse_connection_error(&self) -> &IntCounter {
+        &self.inner.sse_connection_error
+    }
+
+    pub fn sse_connection_success(&self) -> &IntCounter {
+        &self.inner.sse_connection_success
+    }
 }
 
 struct InnerMetrics {
     ws_connection_total: IntGauge,
     ws_connection_error: IntCounter,
     ws_connection_success: IntCounter,
+    sse_connection_total: IntGauge,
+    sse_connection_error: IntCounter,
+    sse_connection_success: IntCounter,
 }
 
 impl Metrics {
@@ -64,6 +79,9 @@ impl Metrics {
         let counter =
             register_int_counter_vec!("ws_connection", "WebSocket connection types", &["status"])
                 .expect("failed to register ws_counter");
+        let sse_counter =
+            register_int_counter_vec!("sse_connection", "SSE connection types", &["status"])
+                .expect("failed to register sse_counter");
 
         Self {
             inner: Arc::new(InnerMetrics {
@@ -74,6 +92,13 @@ impl Metrics {
                 .expect("failed to register ws_connection_total"),
                 ws_connection_error: counter.with_label_values(&["error"]),
                 ws_connection_success: counter.with_label_values(&["success"]),
+                sse_connection_total: register_int_gauge!(
+                    "sse_connection_total",
+                    "SSE connection total"
+                )
+                .expect("failed to register sse_connection_total"),
+                sse_connection_error: sse_counter.with_label_values(&["error"]),
+                sse_connection_success: sse_counter.with_label_values(&["success"]),
             }),
         }
     }
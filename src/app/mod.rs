@@ -5,37 +5,43 @@ use crate::{
         state::AppState,
     },
     authz::AuthzCache,
+    config::ReplicaTransport,
 };
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use futures_util::StreamExt;
 use signal_hook::consts::TERM_SIGNALS;
 use sqlx::PgPool;
 use std::env::var;
-use tokio::sync::{mpsc, watch};
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::{error, info, warn};
 
+mod access;
 mod api;
 mod history_manager;
 mod http;
-mod replica;
+mod occupancy;
+mod reauthorization;
+mod webhook;
 mod ws;
 
 pub mod cluster_ip;
 pub mod error;
 pub mod metrics;
 pub mod nats;
+pub mod rate_limiter;
+pub mod replica;
 pub mod session_manager;
 pub mod state;
 pub mod util;
 
 pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
-    let replica_label = var("APP_AGENT_LABEL").expect("APP_AGENT_LABEL must be specified");
-    let replica_id = replica::register(&db, replica_label).await?;
-    info!(%replica_id, "replica successfully registered");
-
     let config = crate::config::load()?;
     info!(?config, "app config");
 
+    let replica_label = var("APP_AGENT_LABEL").expect("APP_AGENT_LABEL must be specified");
+    let replica_id = replica::register(&db, replica_label, &config).await?;
+    info!(%replica_id, "replica successfully registered");
+
     if let Some(sentry_config) = config.sentry.as_ref() {
         svc_error::extension::sentry::init(sentry_config);
     }
@@ -57,12 +63,13 @@ pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
         cmd_tx,
         nats_client.clone(),
         Metrics::new(),
-    );
+    )?;
 
     // Move hanging sessions from the last time to history
-    history_manager::move_all_sessions(state.clone(), replica_id)
+    let changes = history_manager::move_all_sessions(state.clone(), replica_id)
         .await
         .context("failed to move all sessions to history")?;
+    occupancy::publish_all(&state, changes).await;
 
     let metrics_server = svc_utils::metrics::MetricsServer::new(config.metrics_listener_address);
 
@@ -76,8 +83,35 @@ pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
         config.websocket.wait_before_close_connection,
     );
 
+    // Keeps the replica alive and cleans up dead ones
+    let (unregistered_tx, unregistered_rx) = oneshot::channel();
+    let heartbeat = replica::run_heartbeat(state.clone(), shutdown_rx.clone(), unregistered_tx);
+
+    // Notifies external systems about agents entering and leaving classrooms
+    let webhooks = if config.webhooks.audiences.is_empty() {
+        None
+    } else {
+        Some(webhook::run(state.clone(), shutdown_rx.clone())?)
+    };
+
+    // Disconnects agents whose access to classrooms is revoked
+    let reauthorization = config
+        .websocket
+        .reauthorization_interval
+        .map(|interval| reauthorization::run(state.clone(), interval, shutdown_rx.clone()));
+
+    // Commands of other replicas over NATS instead of the internal API
+    let command_listener = match config.internal_api.transport {
+        ReplicaTransport::Nats => Some(
+            replica::run_command_listener(state.clone(), config.authn.clone(), shutdown_rx.clone())
+                .await
+                .context("failed to subscribe to replica commands")?,
+        ),
+        ReplicaTransport::Http => None,
+    };
+
     let router = http::router(state.clone(), config.authn.clone());
-    let internal_router = http::internal_router(state.clone());
+    let internal_router = http::internal_router(state.clone(), config.authn.clone());
 
     // Public API
     let mut shutdown_server_rx = shutdown_rx.clone();
@@ -112,7 +146,12 @@ pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
     // Waiting for signals for graceful shutdown
     let mut signals_stream = signal_hook_tokio::Signals::new(TERM_SIGNALS)?.fuse();
     let signals = signals_stream.next();
-    let _ = signals.await;
+    // The replica is shut down as well if it has been considered dead by other replicas,
+    // so its agents reconnect to other replicas
+    let unregistered = tokio::select! {
+        _ = signals => false,
+        Ok(_) = unregistered_rx => true,
+    };
     // Initiating graceful shutdown
     shutdown_tx.send(()).ok();
     warn!("shutdown started");
@@ -126,6 +165,34 @@ pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
         );
     }
 
+    if let Err(e) = heartbeat.await {
+        report_error(
+            ErrorKind::ShutdownFailed,
+            "failed to await heartbeat completion",
+            e.into(),
+        );
+    }
+
+    if let Some(webhooks) = webhooks {
+        if let Err(e) = webhooks.await {
+            report_error(
+                ErrorKind::ShutdownFailed,
+                "failed to await webhooks completion",
+                e.into(),
+            );
+        }
+    }
+
+    if let Some(reauthorization) = reauthorization {
+        if let Err(e) = reauthorization.await {
+            report_error(
+                ErrorKind::ShutdownFailed,
+                "failed to await reauthorization completion",
+                e.into(),
+            );
+        }
+    }
+
     if let Err(e) = server.await {
         report_error(
             ErrorKind::ShutdownFailed,
@@ -142,15 +209,26 @@ pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
         );
     }
 
+    if let Some(command_listener) = command_listener {
+        if let Err(e) = command_listener.await {
+            report_error(
+                ErrorKind::ShutdownFailed,
+                "failed to await replica command listener completion",
+                e.into(),
+            );
+        }
+    }
+
     // Move hanging sessions to history
     // NOTE: This process should be started after the completion of the internal API
     // Otherwise, presence won't send the `replaced` error to the agent
-    if let Err(e) = history_manager::move_all_sessions(state.clone(), replica_id).await {
-        report_error(
+    match history_manager::move_all_sessions(state.clone(), replica_id).await {
+        Ok(changes) => occupancy::publish_all(&state, changes).await,
+        Err(e) => report_error(
             ErrorKind::MovingSessionToHistoryFailed,
             "failed to move all sessions to history",
             e,
-        );
+        ),
     }
 
     if let Err(e) = replica::terminate(&db, replica_id).await {
@@ -163,6 +241,12 @@ pub async fn run(db: PgPool, authz_cache: Option<AuthzCache>) -> Result<()> {
 
     metrics_server.shutdown().await;
 
+    if unregistered {
+        return Err(anyhow!(
+            "replica has been considered dead by other replicas"
+        ));
+    }
+
     Ok(())
 }
 
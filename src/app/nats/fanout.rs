@@ -0,0 +1,365 @@
+use crate::classroom::ClassroomId;
+use futures_util::future;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+use svc_nats_client::Message;
+use tokio::{
+    sync::mpsc,
+    time::{interval, MissedTickBehavior},
+};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{info, warn};
+
+/// Capacity of the channel of a single session.
+const SUBSCRIBER_CAPACITY: usize = 50;
+/// How often sessions which are gone are removed if there are no new messages.
+const GC_INTERVAL: Duration = Duration::from_secs(5);
+/// A session which doesn't take a message for this long is evicted,
+/// so it doesn't hold back other sessions in the classroom.
+const SEND_TIMEOUT: Duration = Duration::from_secs(1);
+
+type Subscribers<T> = Vec<mpsc::Sender<T>>;
+
+/// What happens to a message for a session which doesn't keep up with the subscription.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Delivery {
+    /// The subscription waits for the session up to `SEND_TIMEOUT`, so events are never lost.
+    /// Sessions which are stuck are evicted and their connections are closed,
+    /// agents catch up by replay after reconnecting.
+    #[default]
+    Reliable,
+    /// The message is dropped for the session, it's used for signals which can be lost anyway.
+    Lossy,
+}
+
+/// Shares a single NATS subscription per classroom between all sessions on the replica.
+///
+/// The subscription is dropped as soon as there are no sessions left in the classroom.
+pub struct Registry<T = Arc<Message>> {
+    classrooms: Arc<Mutex<HashMap<ClassroomId, Subscribers<T>>>>,
+    delivery: Delivery,
+}
+
+impl<T> Clone for Registry<T> {
+    fn clone(&self) -> Self {
+        Self {
+            classrooms: self.classrooms.clone(),
+            delivery: self.delivery,
+        }
+    }
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new(Delivery::default())
+    }
+}
+
+impl<T> Registry<T> {
+    pub fn new(delivery: Delivery) -> Self {
+        Self {
+            classrooms: Default::default(),
+            delivery,
+        }
+    }
+}
+
+impl<T: Clone + Send + 'static> Registry<T> {
+    /// Adds a session to the existing subscription of the classroom.
+    /// Returns `None` if there is no subscription yet.
+    pub fn join(&self, classroom_id: ClassroomId) -> Option<mpsc::Receiver<T>> {
+        let mut classrooms = self.lock();
+        let subscribers = classrooms.get_mut(&classroom_id)?;
+
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        subscribers.push(tx);
+
+        Some(rx)
+    }
+
+    /// Starts broadcasting messages of the subscription to sessions in the classroom
+    /// and adds the first session.
+    pub fn start<St>(&self, classroom_id: ClassroomId, messages: St) -> mpsc::Receiver<T>
+    where
+        St: Stream<Item = T> + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+
+        {
+            let mut classrooms = self.lock();
+            // Another session has subscribed meanwhile, so the new subscription is dropped
+            if let Some(subscribers) = classrooms.get_mut(&classroom_id) {
+                subscribers.push(tx);
+                return rx;
+            }
+
+            classrooms.insert(classroom_id, vec![tx]);
+        }
+
+        tokio::spawn(fan_out(self.clone(), classroom_id, messages));
+
+        rx
+    }
+
+    /// Sends the message to all sessions in the classroom.
+    /// Returns `false` if there are no sessions left, the classroom is removed then.
+    ///
+    /// Sessions are removed if they are gone or stuck.
+    async fn broadcast(&self, classroom_id: ClassroomId, message: T) -> bool {
+        let subscribers = match self.lock().get(&classroom_id) {
+            Some(subscribers) => subscribers.clone(),
+            None => return false,
+        };
+
+        match self.delivery {
+            Delivery::Reliable => {
+                let sent = future::join_all(
+                    subscribers
+                        .iter()
+                        .map(|tx| tokio::time::timeout(SEND_TIMEOUT, tx.send(message.clone()))),
+                )
+                .await;
+
+                let stuck = subscribers
+                    .iter()
+                    .zip(sent)
+                    .filter(|(_, sent)| sent.is_err())
+                    .map(|(tx, _)| tx)
+                    .collect::<Vec<_>>();
+
+                if !stuck.is_empty() {
+                    warn!(%classroom_id, count = stuck.len(), "sessions don't keep up with events, evicting them");
+                    self.retain(classroom_id, |tx| {
+                        !stuck.iter().any(|stuck| stuck.same_channel(tx))
+                    });
+                }
+            }
+            Delivery::Lossy => {
+                for tx in &subscribers {
+                    tx.try_send(message.clone()).ok();
+                }
+            }
+        }
+
+        self.collect_garbage(classroom_id)
+    }
+
+    /// Removes sessions which are gone.
+    /// Returns `false` if there are no sessions left, the classroom is removed then.
+    fn collect_garbage(&self, classroom_id: ClassroomId) -> bool {
+        self.retain(classroom_id, |tx| !tx.is_closed())
+    }
+
+    fn retain<F>(&self, classroom_id: ClassroomId, f: F) -> bool
+    where
+        F: FnMut(&mpsc::Sender<T>) -> bool,
+    {
+        let mut classrooms = self.lock();
+        let subscribers = match classrooms.get_mut(&classroom_id) {
+            Some(subscribers) => subscribers,
+            None => return false,
+        };
+
+        subscribers.retain(f);
+
+        if subscribers.is_empty() {
+            classrooms.remove(&classroom_id);
+            return false;
+        }
+
+        true
+    }
+
+    fn remove(&self, classroom_id: ClassroomId) {
+        self.lock().remove(&classroom_id);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<ClassroomId, Subscribers<T>>> {
+        // The map stays consistent even if a thread panicked while holding the lock
+        self.classrooms
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+async fn fan_out<T, St>(registry: Registry<T>, classroom_id: ClassroomId, mut messages: St)
+where
+    T: Clone + Send + 'static,
+    St: Stream<Item = T> + Unpin,
+{
+    let mut gc_interval = interval(GC_INTERVAL);
+    gc_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            message = messages.next() => {
+                let message = match message {
+                    Some(message) => message,
+                    None => {
+                        warn!(%classroom_id, "nats subscription is over");
+                        break;
+                    }
+                };
+
+                if !registry.broadcast(classroom_id, message).await {
+                    info!(%classroom_id, "no sessions left, unsubscribed from nats");
+                    return;
+                }
+            }
+            _ = gc_interval.tick() => {
+                if !registry.collect_garbage(classroom_id) {
+                    info!(%classroom_id, "no sessions left, unsubscribed from nats");
+                    return;
+                }
+            }
+        }
+    }
+
+    // Closes channels of all sessions, so they can notice that the subscription is over
+    registry.remove(classroom_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn join_existing_subscription() {
+        let registry = Registry::<u64>::default();
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        assert!(registry.join(classroom_id).is_none());
+
+        let (tx, _rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        registry.lock().insert(classroom_id, vec![tx]);
+
+        let _rx_2 = registry
+            .join(classroom_id)
+            .expect("Failed to join subscription");
+        assert_eq!(registry.lock()[&classroom_id].len(), 2);
+    }
+
+    #[test]
+    fn remove_classroom_without_sessions() {
+        let registry = Registry::<u64>::default();
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let (tx, rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        registry.lock().insert(classroom_id, vec![tx]);
+        let rx_2 = registry
+            .join(classroom_id)
+            .expect("Failed to join subscription");
+
+        drop(rx);
+        assert!(registry.collect_garbage(classroom_id));
+        assert_eq!(registry.lock()[&classroom_id].len(), 1);
+
+        drop(rx_2);
+        assert!(!registry.collect_garbage(classroom_id));
+        assert!(registry.join(classroom_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_session_keeps_subscription() {
+        let registry = Registry::<u64>::default();
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let (tx, mut slow_rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        registry.lock().insert(classroom_id, vec![tx]);
+        let mut rx = registry
+            .join(classroom_id)
+            .expect("Failed to join subscription");
+
+        // More messages than the channel of the slow session can hold
+        let count = SUBSCRIBER_CAPACITY as u64 * 2;
+        let publisher = {
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                for message in 0..count {
+                    assert!(registry.broadcast(classroom_id, message).await);
+                }
+            })
+        };
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for message in 0..count {
+            assert_eq!(slow_rx.recv().await, Some(message));
+            assert_eq!(rx.recv().await, Some(message));
+        }
+
+        publisher.await.expect("Failed to broadcast messages");
+        assert_eq!(registry.lock()[&classroom_id].len(), 2);
+    }
+
+    #[tokio::test]
+    async fn stuck_session_is_evicted() {
+        let registry = Registry::<u64>::default();
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let (tx, mut stuck_rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        registry.lock().insert(classroom_id, vec![tx]);
+        let mut rx = registry
+            .join(classroom_id)
+            .expect("Failed to join subscription");
+
+        let count = SUBSCRIBER_CAPACITY as u64 + 1;
+        let reader = tokio::spawn(async move {
+            for message in 0..count {
+                assert_eq!(rx.recv().await, Some(message));
+            }
+
+            rx
+        });
+
+        for message in 0..count {
+            assert!(registry.broadcast(classroom_id, message).await);
+        }
+
+        let _rx = reader.await.expect("Failed to receive messages");
+        assert_eq!(registry.lock()[&classroom_id].len(), 1);
+
+        // The channel of the stuck session is closed after the messages it got
+        for message in 0..SUBSCRIBER_CAPACITY as u64 {
+            assert_eq!(stuck_rx.recv().await, Some(message));
+        }
+        assert_eq!(stuck_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn lossy_delivery_to_slow_session() {
+        let registry = Registry::<u64>::new(Delivery::Lossy);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let (tx, mut rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        registry.lock().insert(classroom_id, vec![tx]);
+
+        let count = SUBSCRIBER_CAPACITY as u64 * 2;
+        for message in 0..count {
+            assert!(registry.broadcast(classroom_id, message).await);
+        }
+
+        // Messages which didn't fit are dropped, but the session stays subscribed
+        for message in 0..SUBSCRIBER_CAPACITY as u64 {
+            assert_eq!(rx.recv().await, Some(message));
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(registry.lock()[&classroom_id].len(), 1);
+    }
+
+    #[test]
+    fn start_existing_subscription() {
+        let registry = Registry::<u64>::default();
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let (tx, _rx) = mpsc::channel(SUBSCRIBER_CAPACITY);
+        registry.lock().insert(classroom_id, vec![tx]);
+
+        let _rx_2 = registry.start(classroom_id, tokio_stream::pending());
+        assert_eq!(registry.lock()[&classroom_id].len(), 2);
+    }
+}
@@ -1,20 +1,49 @@
-use crate::{classroom::ClassroomId, session::Session};
+use crate::{
+    classroom::ClassroomId,
+    event::{self, EventV1 as Event},
+    session::Session,
+};
 use anyhow::{anyhow, Context, Result};
+use async_nats::jetstream::consumer::{push, PushConsumer};
 use async_trait::async_trait;
-use std::sync::Arc;
-use svc_events::{EventId, EventV1 as Event};
+use sqlx::types::time::OffsetDateTime;
+use std::{sync::Arc, time::Duration};
+use svc_agent::AgentId;
+use svc_events::EventId;
 use svc_nats_client::{AckPolicy, DeliverPolicy, Message, NatsClient as AsyncNatsClient};
 use tokio::sync::{mpsc, oneshot};
 use tokio_stream::StreamExt;
 use tracing::{error, info, warn};
+use uuid::Uuid;
+
+mod fanout;
 
 const SUBJECT_PREFIX: &str = "classroom";
 const ENTITY_TYPE: &str = "agent";
+pub const ENTERED_OPERATION: &str = "entered";
+pub const LEFT_OPERATION: &str = "left";
+const CLASSROOM_ENTITY_TYPE: &str = "classroom";
+pub const OCCUPIED_OPERATION: &str = "occupied";
+pub const VACATED_OPERATION: &str = "vacated";
+const MESSAGE_ENTITY_TYPE: &str = "message";
+const PUBLISHED_OPERATION: &str = "published";
+/// The `classroom.>` subjects are persisted by JetStream,
+/// so signals are published to core NATS outside of them.
+const SIGNAL_SUBJECT_PREFIX: &str = "ephemeral.classroom";
+const SIGNAL_ENTITY_TYPE: &str = "signal";
+const SENT_OPERATION: &str = "sent";
+/// Subjects of replica commands are outside of `classroom.>`, so they aren't persisted.
+const REPLICA_SUBJECT_PREFIX: &str = "presence.replica";
+/// Commands are processed one by one, so there is no need in a large buffer.
+const REPLICA_COMMAND_CAPACITY: usize = 10;
+/// Pending messages of the replay are delivered right away,
+/// so the replay fails if the consumer stalls for this time.
+const REPLAY_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 struct Subscribe {
     classroom_id: ClassroomId,
-    resp_chan: oneshot::Sender<Result<mpsc::Receiver<Message>>>,
+    resp_chan: oneshot::Sender<Result<mpsc::Receiver<Arc<Message>>>>,
 }
 
 #[derive(Debug)]
@@ -28,13 +57,76 @@ pub struct Client {
     tx: mpsc::UnboundedSender<Subscribe>,
     shutdown_tx: mpsc::Sender<oneshot::Sender<()>>,
     inner: svc_nats_client::Client,
+    /// Core NATS connection for ephemeral signals.
+    core: async_nats::Client,
+    /// Shares a single subscription to signals per classroom.
+    signals: fanout::Registry<Arc<async_nats::Message>>,
+    /// Replays need the state of the consumer, which `svc_nats_client` doesn't expose.
+    jetstream: async_nats::jetstream::Context,
+    /// The stream of ephemeral subscriptions.
+    stream: Option<String>,
 }
 
 #[async_trait]
 pub trait NatsClient: Send + Sync {
-    async fn subscribe(&self, classroom_id: ClassroomId) -> Result<mpsc::Receiver<Message>>;
+    /// Subscribes to events of the classroom.
+    /// Messages are shared with other sessions in the classroom on the replica.
+    async fn subscribe(&self, classroom_id: ClassroomId) -> Result<mpsc::Receiver<Arc<Message>>>;
+    /// Returns events of the classroom published after the given stream sequence,
+    /// but not earlier than `window` ago.
+    async fn replay(
+        &self,
+        classroom_id: ClassroomId,
+        from_sequence: u64,
+        window: Duration,
+    ) -> Result<Vec<Message>>;
     async fn publish_event(&self, session: &Session, event: Event, operation: String)
         -> Result<()>;
+    /// Publishes the event of the classroom itself, `occupancy_id` identifies
+    /// the period during which the classroom is occupied.
+    async fn publish_classroom_event(
+        &self,
+        classroom_id: ClassroomId,
+        event: Event,
+        operation: &str,
+        occupancy_id: i64,
+        sender_id: AgentId,
+    ) -> Result<()>;
+    /// Publishes the message of the agent to the classroom of the session
+    /// or only to `receiver_id` if it's set.
+    async fn publish_message(
+        &self,
+        session: &Session,
+        event: Event,
+        receiver_id: Option<AgentId>,
+    ) -> Result<()>;
+    /// Subscribes to ephemeral signals of the classroom.
+    async fn subscribe_signals(
+        &self,
+        classroom_id: ClassroomId,
+    ) -> Result<mpsc::Receiver<Arc<async_nats::Message>>>;
+    /// Publishes the ephemeral signal of the agent to core NATS,
+    /// it's neither persisted nor deduplicated.
+    async fn publish_signal(
+        &self,
+        session: &Session,
+        event: Event,
+        receiver_id: Option<AgentId>,
+    ) -> Result<()>;
+    /// Subscribes to commands sent to the replica by other replicas.
+    async fn subscribe_replica_commands(
+        &self,
+        replica_id: Uuid,
+    ) -> Result<mpsc::Receiver<async_nats::Message>>;
+    /// Sends the command to the replica and waits for the reply.
+    async fn request_replica(
+        &self,
+        replica_id: Uuid,
+        command: &str,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>>;
+    async fn reply(&self, subject: String, payload: Vec<u8>) -> Result<()>;
 }
 
 impl Client {
@@ -42,6 +134,14 @@ impl Client {
         let (tx, mut rx) = mpsc::unbounded_channel::<Subscribe>();
         let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<oneshot::Sender<()>>(1);
 
+        let core = async_nats::ConnectOptions::with_credentials_file(cfg.creds.clone().into())
+            .await?
+            .connect(&cfg.url)
+            .await?;
+
+        let jetstream = async_nats::jetstream::new(core.clone());
+        let stream = cfg.subscribe_ephemeral.as_ref().map(|c| c.stream.clone());
+
         let nats_client = svc_nats_client::Client::new(cfg).await?;
         info!("Connected to NATS");
 
@@ -80,9 +180,84 @@ impl Client {
             tx,
             shutdown_tx,
             inner: nats_client,
+            core,
+            signals: fanout::Registry::new(fanout::Delivery::Lossy),
+            jetstream,
+            stream,
         })
     }
 
+    /// Reads all messages of the classroom pending for a new consumer with the policy.
+    /// Returns `None` if the first message is published before `not_before`.
+    async fn read_pending(
+        &self,
+        classroom_id: ClassroomId,
+        deliver_policy: DeliverPolicy,
+        not_before: Option<OffsetDateTime>,
+    ) -> Result<Option<Vec<Message>>> {
+        let stream = self
+            .stream
+            .as_ref()
+            .ok_or_else(|| anyhow!("Ephemeral subscriptions are not configured"))?;
+
+        let subject = svc_nats_client::Subject::new(
+            SUBJECT_PREFIX.to_string(),
+            classroom_id.into(),
+            "*".to_string(),
+        );
+
+        let consumer: PushConsumer = self
+            .jetstream
+            .get_stream(stream)
+            .await
+            .map_err(|e| anyhow!("Failed to get stream: {}", e))?
+            .create_consumer(push::Config {
+                deliver_subject: self.core.new_inbox(),
+                filter_subject: subject.to_string(),
+                ack_policy: AckPolicy::None,
+                deliver_policy,
+                ..Default::default()
+            })
+            .await
+            .map_err(|e| anyhow!("Failed to create a consumer for replay: {}", e))?;
+
+        // The consumer knows how many messages it's going to deliver,
+        // so there is no need to wait for messages which will never come
+        let mut pending = consumer.cached_info().num_pending;
+        let mut replayed = Vec::with_capacity(pending as usize);
+        if pending == 0 {
+            return Ok(Some(replayed));
+        }
+
+        let mut messages = consumer
+            .messages()
+            .await
+            .map_err(|e| anyhow!("Failed to create a stream of messages for replay: {}", e))?;
+
+        while pending > 0 {
+            let message = tokio::time::timeout(REPLAY_TIMEOUT, messages.next())
+                .await
+                .context("Replay timed out")?
+                .ok_or_else(|| anyhow!("Replay is over unexpectedly"))?
+                .map_err(|e| anyhow!("Failed to replay: {}", e))?;
+
+            let info = message
+                .info()
+                .map_err(|e| anyhow!("Failed to get message info: {}", e))?;
+
+            if let Some(not_before) = not_before {
+                if replayed.is_empty() && info.published < not_before {
+                    return Ok(None);
+                }
+            }
+
+            pending = info.pending;
+            replayed.push(message);
+        }
+
+        Ok(Some(replayed))
+    }
+
     pub async fn shutdown(&self) -> Result<()> {
         let (wait_tx, wait_rx) = oneshot::channel();
         self.shutdown_tx
@@ -95,7 +270,7 @@ impl Client {
 
 #[async_trait]
 impl NatsClient for Client {
-    async fn subscribe(&self, classroom_id: ClassroomId) -> Result<mpsc::Receiver<Message>> {
+    async fn subscribe(&self, classroom_id: ClassroomId) -> Result<mpsc::Receiver<Arc<Message>>> {
         let (resp_chan, resp_rx) = oneshot::channel();
         self.tx
             .send(Subscribe {
@@ -109,6 +284,39 @@ impl NatsClient for Client {
             .context("Subscription failed")
     }
 
+    async fn replay(
+        &self,
+        classroom_id: ClassroomId,
+        from_sequence: u64,
+        window: Duration,
+    ) -> Result<Vec<Message>> {
+        let not_before = OffsetDateTime::now_utc() - window;
+
+        let deliver_policy = DeliverPolicy::ByStartSequence {
+            start_sequence: from_sequence + 1,
+        };
+        let replayed = match self
+            .read_pending(classroom_id, deliver_policy, Some(not_before))
+            .await?
+        {
+            Some(replayed) => replayed,
+            // The agent missed more than the window, so only the window is replayed
+            None => {
+                let deliver_policy = DeliverPolicy::ByStartTime {
+                    start_time: not_before,
+                };
+
+                self.read_pending(classroom_id, deliver_policy, None)
+                    .await?
+                    .unwrap_or_default()
+            }
+        };
+
+        info!(%classroom_id, from_sequence, count = replayed.len(), "Replayed events");
+
+        Ok(replayed)
+    }
+
     async fn publish_event(
         &self,
         session: &Session,
@@ -121,7 +329,7 @@ impl NatsClient for Client {
             ENTITY_TYPE.to_string(),
         );
 
-        let event = svc_events::Event::from(event);
+        let event = event::Event::from(event);
         let payload = serde_json::to_vec(&event)?;
 
         let event_id = EventId::from((ENTITY_TYPE.to_string(), operation, session.id().into()));
@@ -140,16 +348,212 @@ impl NatsClient for Client {
 
         Ok(())
     }
+
+    async fn publish_classroom_event(
+        &self,
+        classroom_id: ClassroomId,
+        event: Event,
+        operation: &str,
+        occupancy_id: i64,
+        sender_id: AgentId,
+    ) -> Result<()> {
+        let subject = svc_nats_client::Subject::new(
+            SUBJECT_PREFIX.to_string(),
+            classroom_id.into(),
+            CLASSROOM_ENTITY_TYPE.to_string(),
+        );
+
+        let event = event::Event::from(event);
+        let payload = serde_json::to_vec(&event)?;
+
+        let event_id = EventId::from((
+            CLASSROOM_ENTITY_TYPE.to_string(),
+            operation.to_string(),
+            occupancy_id,
+        ));
+
+        let event = svc_nats_client::event::Builder::new(subject, payload, event_id, sender_id)
+            .internal(false)
+            .build();
+
+        self.inner
+            .publish(&event)
+            .await
+            .context("Failed to publish a classroom event")?;
+
+        Ok(())
+    }
+
+    async fn publish_message(
+        &self,
+        session: &Session,
+        event: Event,
+        receiver_id: Option<AgentId>,
+    ) -> Result<()> {
+        let subject = svc_nats_client::Subject::new(
+            SUBJECT_PREFIX.to_string(),
+            session.key().classroom_id.into(),
+            MESSAGE_ENTITY_TYPE.to_string(),
+        );
+
+        let event = build_agent_event(session, subject, PUBLISHED_OPERATION, event, receiver_id)?;
+        self.inner.publish(&event).await?;
+
+        Ok(())
+    }
+
+    async fn subscribe_signals(
+        &self,
+        classroom_id: ClassroomId,
+    ) -> Result<mpsc::Receiver<Arc<async_nats::Message>>> {
+        let subject = svc_nats_client::Subject::new(
+            SIGNAL_SUBJECT_PREFIX.to_string(),
+            classroom_id.into(),
+            SIGNAL_ENTITY_TYPE.to_string(),
+        );
+
+        if let Some(rx) = self.signals.join(classroom_id) {
+            return Ok(rx);
+        }
+
+        let subscriber = self
+            .core
+            .subscribe(subject.to_string())
+            .await
+            .context("Failed to subscribe to signals")?;
+
+        info!(%subject, "Subscribed to signals");
+
+        // Signals are ephemeral, so they are dropped for sessions which don't keep up
+        Ok(self.signals.start(classroom_id, subscriber.map(Arc::new)))
+    }
+
+    async fn publish_signal(
+        &self,
+        session: &Session,
+        event: Event,
+        receiver_id: Option<AgentId>,
+    ) -> Result<()> {
+        let subject = svc_nats_client::Subject::new(
+            SIGNAL_SUBJECT_PREFIX.to_string(),
+            session.key().classroom_id.into(),
+            SIGNAL_ENTITY_TYPE.to_string(),
+        );
+
+        let event = build_agent_event(session, subject, SENT_OPERATION, event, receiver_id)?;
+        self.core
+            .publish_with_headers(
+                event.subject().to_string(),
+                event.headers().to_owned().into(),
+                event.payload().to_owned().into(),
+            )
+            .await
+            .context("Failed to publish a signal")?;
+
+        Ok(())
+    }
+
+    async fn subscribe_replica_commands(
+        &self,
+        replica_id: Uuid,
+    ) -> Result<mpsc::Receiver<async_nats::Message>> {
+        let subject = format!("{}.{}.*", REPLICA_SUBJECT_PREFIX, replica_id);
+
+        let mut subscriber = self
+            .core
+            .subscribe(subject)
+            .await
+            .context("Failed to subscribe to replica commands")?;
+
+        let (tx, rx) = mpsc::channel(REPLICA_COMMAND_CAPACITY);
+
+        tokio::spawn(async move {
+            while let Some(message) = subscriber.next().await {
+                // Unlike signals, commands mustn't be lost
+                if tx.send(message).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(rx)
+    }
+
+    async fn request_replica(
+        &self,
+        replica_id: Uuid,
+        command: &str,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        let subject = format!("{}.{}.{}", REPLICA_SUBJECT_PREFIX, replica_id, command);
+        let request = async_nats::Request::new()
+            .payload(payload.into())
+            .timeout(Some(timeout));
+
+        let message = self
+            .core
+            .send_request(subject, request)
+            .await
+            .map_err(|e| anyhow!("Failed to send request to replica: {}", e))?;
+
+        Ok(message.payload.to_vec())
+    }
+
+    async fn reply(&self, subject: String, payload: Vec<u8>) -> Result<()> {
+        self.core
+            .publish(subject, payload.into())
+            .await
+            .context("Failed to reply")
+    }
+}
+
+/// Builds an event published by the agent on its own, such as messages and signals.
+fn build_agent_event(
+    session: &Session,
+    subject: svc_nats_client::Subject,
+    operation: &str,
+    event: Event,
+    receiver_id: Option<AgentId>,
+) -> Result<svc_nats_client::Event> {
+    let entity_type = subject.entity_type().to_string();
+    let event = event::Event::from(event);
+    let payload = serde_json::to_vec(&event)?;
+
+    // An agent publishes many events within a session,
+    // so the publication time distinguishes them
+    let sequence_id = OffsetDateTime::now_utc().unix_timestamp_nanos() as i64;
+    let event_id = EventId::from((entity_type, operation.to_string(), sequence_id));
+
+    let mut builder = svc_nats_client::event::Builder::new(
+        subject,
+        payload,
+        event_id,
+        session.key().clone().agent_id,
+    )
+    .internal(false)
+    .disable_deduplication();
+
+    if let Some(receiver_id) = receiver_id {
+        builder = builder.receiver_id(receiver_id);
+    }
+
+    Ok(builder.build())
 }
 
 async fn nats_loop(client: svc_nats_client::Client, mut rx: mpsc::UnboundedReceiver<Cmd>) {
+    let registry = fanout::Registry::default();
+
     while let Some(cmd) = rx.recv().await {
         match cmd {
             Cmd::Subscribe(Subscribe {
                 classroom_id,
                 resp_chan,
             }) => {
-                let sub = add_new_subscription(&client, classroom_id).await;
+                let sub = match registry.join(classroom_id) {
+                    Some(rx) => Ok(rx),
+                    None => add_new_subscription(&client, &registry, classroom_id).await,
+                };
                 resp_chan.send(sub).ok();
             }
             Cmd::Shutdown => break,
@@ -159,15 +563,16 @@ async fn nats_loop(client: svc_nats_client::Client, mut rx: mpsc::UnboundedRecei
 
 async fn add_new_subscription(
     client: &svc_nats_client::Client,
+    registry: &fanout::Registry,
     classroom_id: ClassroomId,
-) -> Result<mpsc::Receiver<Message>> {
+) -> Result<mpsc::Receiver<Arc<Message>>> {
     let subject = svc_nats_client::Subject::new(
         SUBJECT_PREFIX.to_string(),
         classroom_id.into(),
         "*".to_string(),
     );
 
-    let mut messages = client
+    let messages = client
         .subscribe_ephemeral(subject.clone(), DeliverPolicy::New, AckPolicy::None)
         .await
         .map_err(|err| {
@@ -183,16 +588,14 @@ async fn add_new_subscription(
 
     info!(%subject, "Subscribed to JetStream");
 
-    let (tx, rx) = mpsc::channel(50);
-
-    tokio::spawn(async move {
-        while let Some(Ok(message)) = messages.next().await {
-            if tx.send(message).await.is_err() {
-                error!(%classroom_id, "Failed to send message from nats subscription to receiver");
-                return;
-            }
+    // Sessions notice that the subscription is over and close their connections
+    let messages = messages.map_while(move |message| match message {
+        Ok(message) => Some(Arc::new(message)),
+        Err(err) => {
+            error!(%classroom_id, %err, "failed to get message from nats subscription");
+            None
         }
     });
 
-    Ok(rx)
+    Ok(registry.start(classroom_id, messages))
 }
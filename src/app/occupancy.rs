@@ -0,0 +1,255 @@
+use crate::{
+    app::{
+        error::{Error, ErrorKind},
+        nats::{OCCUPIED_OPERATION, VACATED_OPERATION},
+        state::State,
+    },
+    classroom::ClassroomId,
+    db::{agent_session::AgentSession, classroom_occupancy},
+    event::{ClassroomEventV1 as ClassroomEvent, EventV1 as Event},
+};
+use anyhow::{Context, Result};
+use sqlx::PgConnection;
+use std::collections::HashMap;
+use svc_agent::AgentId;
+use tracing::error;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Occupied,
+    Vacated,
+}
+
+/// A change of the classroom occupancy which is published once its transaction is committed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub classroom_id: ClassroomId,
+    pub transition: Transition,
+    occupancy_id: i64,
+    agent_id: AgentId,
+}
+
+/// Marks the classroom as occupied if the first agent entered it or as vacated if the last one left it.
+/// Must be called in the transaction of every insertion or removal of an agent session in the classroom,
+/// so the occupancy is committed or rolled back along with the session.
+///
+/// Changes of the classroom are serialized by an advisory lock until the end of the transaction,
+/// so each of them is made once even if agents enter and leave on different replicas.
+pub async fn update(
+    conn: &mut PgConnection,
+    classroom_id: ClassroomId,
+    agent_id: &AgentId,
+) -> Result<Option<Change>> {
+    classroom_occupancy::LockQuery::new(classroom_id)
+        .execute(conn)
+        .await
+        .context("Failed to lock classroom occupancy")?;
+
+    let occupied = classroom_occupancy::OccupyQuery::new(classroom_id)
+        .execute(conn)
+        .await
+        .context("Failed to occupy classroom")?;
+
+    let (transition, occupancy_id) = match occupied {
+        Some(occupancy_id) => (Transition::Occupied, occupancy_id),
+        None => {
+            let vacated = classroom_occupancy::VacateQuery::new(classroom_id)
+                .execute(conn)
+                .await
+                .context("Failed to vacate classroom")?;
+
+            match vacated {
+                Some(occupancy_id) => (Transition::Vacated, occupancy_id),
+                None => return Ok(None),
+            }
+        }
+    };
+
+    Ok(Some(Change {
+        classroom_id,
+        transition,
+        occupancy_id,
+        agent_id: agent_id.to_owned(),
+    }))
+}
+
+/// Updates occupancy of classrooms of sessions moved to history in bulk, see [`update`].
+///
+/// Classrooms are locked in the same order by every transaction, so they can't deadlock.
+pub async fn update_sessions(
+    conn: &mut PgConnection,
+    sessions: &[AgentSession],
+) -> Result<Vec<Change>> {
+    let mut classrooms = sessions
+        .iter()
+        .map(|session| (session.classroom_id, &session.agent_id))
+        .collect::<HashMap<_, _>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    classrooms.sort_by_key(|(classroom_id, _)| Uuid::from(*classroom_id));
+
+    let mut changes = Vec::new();
+    for (classroom_id, agent_id) in classrooms {
+        if let Some(change) = update(conn, classroom_id, agent_id).await? {
+            changes.push(change);
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Publishes `classroom.occupied` or `classroom.vacated` of the committed change.
+///
+/// It's published outside of the lock, so NATS doesn't slow down changes of the classroom.
+/// Events of quick changes on different replicas may arrive out of order,
+/// the occupancy id tells which of them is the latest.
+pub async fn publish<S: State>(state: &S, change: Change) -> Result<()> {
+    let Change {
+        classroom_id,
+        transition,
+        occupancy_id,
+        agent_id,
+    } = change;
+
+    let (event, operation) = match transition {
+        Transition::Occupied => (
+            ClassroomEvent::Occupied { classroom_id },
+            OCCUPIED_OPERATION,
+        ),
+        Transition::Vacated => (ClassroomEvent::Vacated { classroom_id }, VACATED_OPERATION),
+    };
+
+    state
+        .nats_client()
+        .publish_classroom_event(
+            classroom_id,
+            Event::from(event),
+            operation,
+            occupancy_id,
+            agent_id,
+        )
+        .await
+}
+
+/// Publishes committed changes, failures are only reported since the changes can't be undone.
+pub async fn publish_all<S: State>(state: &S, changes: Vec<Change>) {
+    for change in changes {
+        let classroom_id = change.classroom_id;
+
+        if let Err(e) = publish(state, change).await {
+            error!(error = %e, %classroom_id, "Failed to publish classroom occupancy");
+            Error::new(ErrorKind::OccupancyUpdateFailed, e).notify_sentry();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        db::{agent_session, replica},
+        test_helpers::prelude::*,
+    };
+    use sqlx::types::time::OffsetDateTime;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    async fn occupancy_transition(
+        db_pool: &TestDb,
+        classroom_id: ClassroomId,
+        agent_id: &AgentId,
+    ) -> Option<Transition> {
+        let mut conn = db_pool.get_conn().await;
+
+        update(&mut conn, classroom_id, agent_id)
+            .await
+            .expect("Failed to update occupancy")
+            .map(|change| change.transition)
+    }
+
+    #[tokio::test]
+    async fn occupied_and_vacated_once() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let agent_1 = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let agent_2 = TestAgent::new("web", "user2", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let replica_id = {
+            let mut conn = db_pool.get_conn().await;
+
+            replica::InsertQuery::new("presence-1".into(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)))
+                .expect("Failed to create insert query for replica")
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert a replica")
+                .id
+        };
+
+        // Nobody is in the classroom yet
+        let transition = occupancy_transition(&db_pool, classroom_id, agent_1.agent_id()).await;
+        assert_eq!(transition, None);
+
+        let (session_1, session_2) = {
+            let mut conn = db_pool.get_conn().await;
+
+            let session_1 = agent_session::InsertQuery::new(
+                agent_1.agent_id(),
+                classroom_id,
+                replica_id,
+                OffsetDateTime::now_utc(),
+            )
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert first agent session");
+
+            let session_2 = agent_session::InsertQuery::new(
+                agent_2.agent_id(),
+                classroom_id,
+                replica_id,
+                OffsetDateTime::now_utc(),
+            )
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert second agent session");
+
+            (session_1, session_2)
+        };
+
+        let transition = occupancy_transition(&db_pool, classroom_id, agent_1.agent_id()).await;
+        assert_eq!(transition, Some(Transition::Occupied));
+
+        // The second agent doesn't occupy the classroom again
+        let transition = occupancy_transition(&db_pool, classroom_id, agent_2.agent_id()).await;
+        assert_eq!(transition, None);
+
+        {
+            let mut conn = db_pool.get_conn().await;
+
+            agent_session::DeleteQuery::by_replica(replica_id, &[session_1.id])
+                .execute(&mut conn)
+                .await
+                .expect("Failed to delete first agent session");
+        }
+
+        let transition = occupancy_transition(&db_pool, classroom_id, agent_1.agent_id()).await;
+        assert_eq!(transition, None);
+
+        {
+            let mut conn = db_pool.get_conn().await;
+
+            agent_session::DeleteQuery::by_replica(replica_id, &[session_2.id])
+                .execute(&mut conn)
+                .await
+                .expect("Failed to delete second agent session");
+        }
+
+        let transition = occupancy_transition(&db_pool, classroom_id, agent_2.agent_id()).await;
+        assert_eq!(transition, Some(Transition::Vacated));
+
+        // The classroom is vacated only once
+        let transition = occupancy_transition(&db_pool, classroom_id, agent_2.agent_id()).await;
+        assert_eq!(transition, None);
+    }
+}
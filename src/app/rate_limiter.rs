@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use svc_agent::AgentId;
+
+/// How often limiters of agents which stopped sending requests are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// A bucket refills in a second, so a limiter unused for longer is the same as a new one.
+const REFILL_TIME: Duration = Duration::from_secs(1);
+
+/// Limits the rate of requests of each agent across all of its connections to the replica,
+/// so opening more connections doesn't let the agent send more.
+pub struct AgentRateLimiter {
+    rate: u32,
+    inner: Mutex<Limiters>,
+}
+
+struct Limiters {
+    by_agent: HashMap<AgentId, RateLimiter>,
+    cleaned_up_at: Instant,
+}
+
+impl AgentRateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            inner: Mutex::new(Limiters {
+                by_agent: HashMap::new(),
+                cleaned_up_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token of the agent if there is one.
+    pub fn try_acquire(&self, agent_id: &AgentId) -> bool {
+        self.try_acquire_at(agent_id, Instant::now())
+    }
+
+    fn try_acquire_at(&self, agent_id: &AgentId, now: Instant) -> bool {
+        let mut inner = self.inner.lock().expect("rate limiter lock is poisoned");
+
+        if now.saturating_duration_since(inner.cleaned_up_at) >= CLEANUP_INTERVAL {
+            inner.by_agent.retain(|_, limiter| {
+                now.saturating_duration_since(limiter.updated_at) < REFILL_TIME
+            });
+            inner.cleaned_up_at = now;
+        }
+
+        let rate = self.rate;
+        inner
+            .by_agent
+            .entry(agent_id.to_owned())
+            .or_insert_with(|| RateLimiter::new_at(rate, now))
+            .try_acquire_at(now)
+    }
+}
+
+/// Limits the rate of requests with the token bucket algorithm.
+///
+/// Up to `rate` requests are allowed at once, then `rate` requests per second.
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    fn new_at(rate: u32, now: Instant) -> Self {
+        Self {
+            rate: f64::from(rate),
+            tokens: f64::from(rate),
+            updated_at: now,
+        }
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.updated_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use svc_authn::AccountId;
+
+    fn len(limiter: &AgentRateLimiter) -> usize {
+        limiter.inner.lock().expect("Failed to lock").by_agent.len()
+    }
+
+    #[test]
+    fn limit_burst_and_refill() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new_at(2, now);
+
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now));
+
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now));
+
+        // Tokens don't pile up over the rate
+        let now = now + Duration::from_secs(10);
+        assert!(limiter.try_acquire_at(now));
+        assert!(limiter.try_acquire_at(now));
+        assert!(!limiter.try_acquire_at(now));
+    }
+
+    #[test]
+    fn limit_agents_separately() {
+        let limiter = AgentRateLimiter::new(1);
+        let now = Instant::now();
+        let agent1 = AgentId::new("web", AccountId::new("user1", "example.com"));
+        let agent2 = AgentId::new("web", AccountId::new("user2", "example.com"));
+
+        assert!(limiter.try_acquire_at(&agent1, now));
+        assert!(!limiter.try_acquire_at(&agent1, now));
+        assert!(limiter.try_acquire_at(&agent2, now));
+        assert_eq!(len(&limiter), 2);
+
+        // Limiters of idle agents are removed on cleanup
+        let now = now + CLEANUP_INTERVAL;
+        assert!(limiter.try_acquire_at(&agent1, now));
+        assert_eq!(len(&limiter), 1);
+    }
+}
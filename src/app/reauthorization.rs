@@ -0,0 +1,155 @@
+use crate::{
+    app::{
+        access::{self, CONNECT_ACTION},
+        error::{Error, ErrorKind},
+        state::State,
+    },
+    classroom::ClassroomId,
+    db::agent_session,
+};
+use anyhow::{Context, Result};
+use futures_util::{stream, StreamExt};
+use std::{collections::HashSet, time::Duration};
+use svc_authn::{AccountId, Authenticable};
+use tokio::{sync::watch, task::JoinHandle, time::MissedTickBehavior};
+use tracing::{error, warn};
+
+/// Max number of authz requests in flight, so large replicas don't flood authz.
+const CONCURRENCY: usize = 10;
+
+/// Re-checks access of agents connected to the replica, since it may be revoked
+/// while they are in the classroom. Agents without access are disconnected with `access_denied`.
+pub fn run<S: State>(
+    state: S,
+    interval: Duration,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = reauthorize(&state).await {
+                        error!(error = %e, "failed to re-authorize agents");
+                        Error::new(ErrorKind::ReauthorizationFailed, e).notify_sentry();
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Returns accounts which have lost access to classrooms.
+async fn reauthorize<S: State>(state: &S) -> Result<Vec<(ClassroomId, AccountId)>> {
+    let sessions = {
+        let mut conn = state.get_conn().await?;
+
+        agent_session::ListQuery::by_replica(state.replica_id())
+            .execute(&mut conn)
+            .await
+            .context("Failed to get sessions of replica")?
+    };
+
+    // Agents and devices of an account share access, so it's checked once per classroom
+    let accounts = sessions
+        .into_iter()
+        .map(|session| {
+            (
+                session.classroom_id,
+                session.agent_id.as_account_id().to_owned(),
+            )
+        })
+        .collect::<HashSet<_>>();
+
+    let results = stream::iter(accounts)
+        .map(|(classroom_id, account_id)| async move {
+            let result = access::authorize(state, classroom_id, &account_id, CONNECT_ACTION).await;
+            (classroom_id, account_id, result)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut denied = Vec::new();
+    for (classroom_id, account_id, result) in results {
+        match result {
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), svc_authz::ErrorKind::Forbidden(_)) => {
+                warn!(%classroom_id, %account_id, "Access to the classroom is revoked");
+                state.deny_sessions(classroom_id, account_id.clone())?;
+                denied.push((classroom_id, account_id));
+            }
+            // The access is unknown, so agents stay connected until the next check
+            Err(err) => {
+                error!(error = %err, %classroom_id, %account_id, "Failed to re-authorize account");
+            }
+        }
+    }
+
+    Ok(denied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::replica, test_helpers::prelude::*};
+    use sqlx::types::time::OffsetDateTime;
+    use std::net::{IpAddr, Ipv4Addr};
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn deny_revoked_accounts() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let allowed = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let revoked = TestAgent::new("web", "user2", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let replica_id = {
+            let mut conn = db_pool.get_conn().await;
+
+            let replica_id =
+                replica::InsertQuery::new("presence-1".into(), IpAddr::V4(Ipv4Addr::LOCALHOST))
+                    .expect("Failed to create insert query for replica")
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to insert a replica")
+                    .id;
+
+            for agent in [&allowed, &revoked] {
+                agent_session::InsertQuery::new(
+                    agent.agent_id(),
+                    classroom_id,
+                    replica_id,
+                    OffsetDateTime::now_utc(),
+                )
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session");
+            }
+
+            replica_id
+        };
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            allowed.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "connect",
+        );
+        let state = TestState::new(db_pool, authz, replica_id);
+
+        let denied = reauthorize(&state).await.expect("Failed to re-authorize");
+
+        assert_eq!(
+            denied,
+            vec![(classroom_id, revoked.account_id().to_owned())]
+        );
+    }
+}
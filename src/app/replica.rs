@@ -1,19 +1,58 @@
 use crate::{
     app::{
-        api::v1::session::{DeletePayload, Response},
+        api::{
+            internal_authn::verify_replica_token,
+            v1::session::{to_response, DeletePayload, Response},
+        },
+        error::{Error, ErrorKind},
+        history_manager,
+        nats::LEFT_OPERATION,
+        occupancy,
         state::State,
+        webhook,
     },
-    db,
-    session::SessionKey,
+    config::{Config, InternalApiConfig, ReplicaTransport},
+    db::{self, agent_session::AgentSession, replica::SessionReplica},
+    event::{AgentEventV1 as AgentEvent, EventV1 as Event},
+    session::{Session, SessionKey, SessionKind},
 };
-use anyhow::{Context, Result};
-use sqlx::PgPool;
-use std::net::IpAddr;
-use tracing::info;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::time::OffsetDateTime, PgPool};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
+use svc_authn::{jose::ConfigMap, token::jws_compact::TokenBuilder, AccountId};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::MissedTickBehavior,
+};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
-pub async fn register(db_pool: &PgPool, label: String) -> Result<Uuid> {
-    let ip = super::cluster_ip::get_ip(&label).await?;
+/// Seconds during which a token of the internal API is valid.
+const TOKEN_EXPIRATION: i64 = 60;
+const DELETE_COMMAND: &str = "delete";
+const KICK_COMMAND: &str = "kick";
+
+/// Payload of a command over NATS. Anyone with access to NATS can publish to the subject,
+/// so it carries the token of the service like requests to the internal API do.
+#[derive(Deserialize, Serialize)]
+struct CommandPayload {
+    token: String,
+    session_key: SessionKey,
+}
+
+pub async fn register(db_pool: &PgPool, label: String, config: &Config) -> Result<Uuid> {
+    let ip = match config.internal_api.transport {
+        ReplicaTransport::Http => {
+            super::cluster_ip::get_ip(&label, &config.replica.address).await?
+        }
+        // Replicas are found by ids, so they can run outside of Kubernetes
+        ReplicaTransport::Nats => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
     info!("Replica IP: {}", ip);
 
     let mut conn = db_pool
@@ -46,27 +85,481 @@ pub async fn terminate(db_pool: &PgPool, id: Uuid) -> Result<()> {
     Ok(())
 }
 
-pub async fn close_connection<S: State>(
+/// Sends heartbeats of the replica and cleans up replicas which stopped sending them,
+/// e.g. because they were killed without a graceful shutdown.
+///
+/// If other replicas have already considered this one dead, its sessions are in history
+/// and new ones can't be created, so `unregistered_tx` is notified to shut the replica down.
+/// Agents reconnect to other replicas then.
+pub fn run_heartbeat<S: State>(
     state: S,
-    replica_ip: IpAddr,
+    mut shutdown_rx: watch::Receiver<()>,
+    unregistered_tx: oneshot::Sender<()>,
+) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
+        let config = state.config().replica.clone();
+        let mut heartbeat_interval = tokio::time::interval(config.heartbeat_interval);
+        heartbeat_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                _ = heartbeat_interval.tick() => {
+                    match heartbeat(&state).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            let e = anyhow!("replica is not registered anymore, shutting down");
+                            error!(error = %e, replica_id = %state.replica_id());
+                            Error::new(ErrorKind::HeartbeatFailed, e).notify_sentry();
+
+                            unregistered_tx.send(()).ok();
+                            break;
+                        }
+                        Err(e) => {
+                            error!(error = %e, "failed to send replica heartbeat");
+                            Error::new(ErrorKind::HeartbeatFailed, e).notify_sentry();
+                        }
+                    }
+
+                    if let Err(e) = clean_up_stale_replicas(state.clone(), config.stale_threshold).await {
+                        error!(error = %e, "failed to clean up stale replicas");
+                        Error::new(ErrorKind::MovingSessionToHistoryFailed, e).notify_sentry();
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Returns `false` if another replica has already considered this one dead
+/// and moved its sessions to history.
+async fn heartbeat<S: State>(state: &S) -> Result<bool> {
+    let mut conn = state.get_conn().await?;
+
+    let result = db::replica::HeartbeatQuery::new(state.replica_id())
+        .execute(&mut conn)
+        .await
+        .context("Failed to update replica heartbeat")?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+async fn clean_up_stale_replicas<S: State>(state: S, stale_threshold: Duration) -> Result<()> {
+    let heartbeat_before = OffsetDateTime::now_utc() - stale_threshold;
+    let (sessions, changes) =
+        history_manager::move_stale_replicas(state.clone(), heartbeat_before).await?;
+    if sessions.is_empty() {
+        return Ok(());
+    }
+
+    // Other agents are notified that these agents left before the classrooms are vacated
+    let result = publish_left(&state, &sessions).await;
+    occupancy::publish_all(&state, changes).await;
+    result
+}
+
+async fn publish_left<S: State>(state: &S, sessions: &[AgentSession]) -> Result<()> {
+    let mut conn = state.get_conn().await?;
+
+    for agent_session in sessions.iter().cloned() {
+        let agent_id = agent_session.agent_id;
+        let session_key = SessionKey::new(agent_id.clone(), agent_session.classroom_id)
+            .with_device(agent_session.device_id);
+
+        // The agent is still in the classroom on another device
+        let count = db::agent_session::DeviceCountQuery::new(
+            session_key.classroom_id,
+            &session_key.agent_id,
+            &session_key.device_id,
+        )
+        .execute(&mut conn)
+        .await
+        .context("Failed to count devices of the agent")?;
+
+        if count > 0 {
+            continue;
+        }
+
+        let session = Session::new(agent_session.id, session_key, SessionKind::New);
+        let event = Event::from(AgentEvent::Left { agent_id });
+
+        if let Err(e) = state
+            .nats_client()
+            .publish_event(&session, event, LEFT_OPERATION.into())
+            .await
+        {
+            error!(error = %e, %session, "Failed to send agent.left notification");
+            Error::new(ErrorKind::MovingSessionToHistoryFailed, e).notify_sentry();
+        }
+
+        if let Err(e) = webhook::notify_left(state, session.key()).await {
+            error!(error = %e, %session, "Failed to queue agent.left webhook notification");
+            Error::new(ErrorKind::WebhookDeliveryFailed, e).notify_sentry();
+        }
+    }
+
+    Ok(())
+}
+
+/// Closes the connection of the session on another replica.
+pub async fn close_connection<S: State>(
+    state: &S,
+    replica: &SessionReplica,
     session_key: SessionKey,
 ) -> Result<Response> {
-    let url = format!(
-        "http://{}:{}/api/v1/sessions",
-        replica_ip,
-        state.config().internal_listener_address.port()
-    );
-
-    info!(replica_ip = %replica_ip, "Trying to close connection on another replica");
-
-    let payload = DeletePayload { session_key };
-    let resp = reqwest::Client::new()
-        .delete(url)
-        .json(&payload)
-        .send()
+    match state.config().internal_api.transport {
+        ReplicaTransport::Http => {
+            state
+                .internal_api()
+                .close_connection(replica.ip(), session_key)
+                .await
+        }
+        ReplicaTransport::Nats => {
+            request_over_nats(state, replica.id, DELETE_COMMAND, session_key).await
+        }
+    }
+}
+
+/// Closes the connection of the session on another replica with the `kicked` error.
+pub async fn kick_connection<S: State>(
+    state: &S,
+    replica: &SessionReplica,
+    session_key: SessionKey,
+) -> Result<Response> {
+    match state.config().internal_api.transport {
+        ReplicaTransport::Http => {
+            state
+                .internal_api()
+                .kick_connection(replica.ip(), session_key)
+                .await
+        }
+        ReplicaTransport::Nats => {
+            request_over_nats(state, replica.id, KICK_COMMAND, session_key).await
+        }
+    }
+}
+
+async fn request_over_nats<S: State>(
+    state: &S,
+    replica_id: Uuid,
+    command: &str,
+    session_key: SessionKey,
+) -> Result<Response> {
+    info!(%replica_id, command, "Sending command to another replica over nats");
+
+    let payload = serde_json::to_vec(&CommandPayload {
+        token: state.internal_api().token()?,
+        session_key,
+    })?;
+    let reply = state
+        .nats_client()
+        .request_replica(
+            replica_id,
+            command,
+            payload,
+            state.config().internal_api.timeout,
+        )
         .await?;
 
-    let resp = resp.json::<Response>().await?;
+    let resp = serde_json::from_slice::<Response>(&reply)?;
 
     Ok(resp)
 }
+
+/// Handles commands sent over NATS by other replicas, the same ones as in the internal API.
+///
+/// Commands without a valid token of the service are rejected without a reply.
+///
+/// Commands are handled until `wait_before_close_connection` is over after the shutdown,
+/// so other replicas can take over sessions during a graceful shutdown.
+pub async fn run_command_listener<S: State>(
+    state: S,
+    authn: ConfigMap,
+    mut shutdown_rx: watch::Receiver<()>,
+) -> Result<JoinHandle<()>> {
+    let mut commands = state
+        .nats_client()
+        .subscribe_replica_commands(state.replica_id())
+        .await?;
+
+    let handle = tokio::task::spawn(async move {
+        let wait_before_close_connection = state.config().websocket.wait_before_close_connection;
+        let shutdown = async move {
+            shutdown_rx.changed().await.ok();
+            tokio::time::sleep(wait_before_close_connection).await;
+        };
+        tokio::pin!(shutdown);
+
+        loop {
+            tokio::select! {
+                Some(message) = commands.recv() => {
+                    let reply = match message.reply.clone() {
+                        Some(reply) => reply,
+                        None => {
+                            warn!(subject = %message.subject, "replica command without reply subject");
+                            continue;
+                        }
+                    };
+
+                    let resp = match handle_command(&state, &authn, &message).await {
+                        Ok(resp) => resp,
+                        Err(e) => {
+                            error!(error = %e, subject = %message.subject, "failed to handle replica command");
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = state.nats_client().reply(reply, resp).await {
+                        error!(error = %e, "failed to reply to replica command");
+                    }
+                }
+                _ = &mut shutdown => {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(handle)
+}
+
+async fn handle_command<S: State>(
+    state: &S,
+    authn: &ConfigMap,
+    message: &async_nats::Message,
+) -> Result<Vec<u8>> {
+    let payload = serde_json::from_slice::<CommandPayload>(&message.payload)
+        .context("Failed to parse replica command")?;
+
+    verify_replica_token(&payload.token, authn, &state.config().id)
+        .context("Failed to authenticate replica command")?;
+
+    let (_, resp) = match message.subject.rsplit('.').next() {
+        Some(DELETE_COMMAND) => to_response(
+            state.delete_session(payload.session_key).await,
+            "Failed to delete session",
+        ),
+        Some(KICK_COMMAND) => to_response(
+            state.kick_session(payload.session_key).await,
+            "Failed to kick session",
+        ),
+        _ => return Err(anyhow!("Unknown replica command")),
+    };
+
+    serde_json::to_vec(&resp).context("Failed to serialize response")
+}
+
+/// Client of the internal API of other replicas.
+#[derive(Clone)]
+pub struct InternalApiClient {
+    http_client: reqwest::Client,
+    port: u16,
+    issuer: String,
+    account_id: AccountId,
+    config: InternalApiConfig,
+}
+
+impl InternalApiClient {
+    pub fn new(config: &Config) -> Result<Self> {
+        let http_client = reqwest::Client::builder()
+            .timeout(config.internal_api.timeout)
+            .build()
+            .context("Failed to build http client")?;
+
+        Ok(Self {
+            http_client,
+            port: config.internal_listener_address.port(),
+            issuer: config.svc_audience.clone(),
+            account_id: config.id.clone(),
+            config: config.internal_api.clone(),
+        })
+    }
+
+    pub async fn close_connection(
+        &self,
+        replica_ip: IpAddr,
+        session_key: SessionKey,
+    ) -> Result<Response> {
+        info!(replica_ip = %replica_ip, "Trying to close connection on another replica");
+
+        let url = self.url(replica_ip, "/api/v1/sessions");
+        let payload = DeletePayload { session_key };
+        let resp = self
+            .http_client
+            .delete(url)
+            .bearer_auth(self.token()?)
+            .json(&payload)
+            .send()
+            .await?;
+
+        let resp = resp.json::<Response>().await?;
+
+        Ok(resp)
+    }
+
+    pub async fn kick_connection(
+        &self,
+        replica_ip: IpAddr,
+        session_key: SessionKey,
+    ) -> Result<Response> {
+        info!(replica_ip = %replica_ip, "Trying to kick agent on another replica");
+
+        let url = self.url(replica_ip, "/api/v1/sessions/kick");
+        let payload = DeletePayload { session_key };
+        let resp = self
+            .http_client
+            .post(url)
+            .bearer_auth(self.token()?)
+            .json(&payload)
+            .send()
+            .await?;
+
+        let resp = resp.json::<Response>().await?;
+
+        Ok(resp)
+    }
+
+    /// Builds a short-lived token of the service, so a leaked token is useless soon.
+    pub fn token(&self) -> Result<String> {
+        TokenBuilder::new()
+            .issuer(&self.issuer)
+            .subject(&self.account_id)
+            .expires_in(TOKEN_EXPIRATION)
+            .key(self.config.algorithm, &self.config.key)
+            .build()
+            .map_err(|e| anyhow!("Failed to build internal api token: {}", e))
+    }
+
+    fn url(&self, replica_ip: IpAddr, path: &str) -> String {
+        format!("http://{}:{}{}", replica_ip, self.port, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::api::{internal_authn::tests::authn, v1::session::Reason},
+        classroom::ClassroomId,
+        test_helpers::prelude::*,
+    };
+
+    fn command(subject: &str, token: String, session_key: SessionKey) -> async_nats::Message {
+        let payload = serde_json::to_vec(&CommandPayload { token, session_key })
+            .expect("Failed to serialize payload");
+
+        async_nats::Message {
+            subject: subject.to_string(),
+            reply: Some("_INBOX.1".to_string()),
+            payload: payload.into(),
+            headers: None,
+            status: None,
+            description: None,
+            length: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_nats_command() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let replica_id = Uuid::new_v4();
+        let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let session_key = SessionKey::new(agent.agent_id().to_owned(), classroom_id);
+
+        let token = state.internal_api().token().expect("Failed to build token");
+
+        for cmd in [DELETE_COMMAND, KICK_COMMAND] {
+            let subject = format!("presence.replica.{}.{}", replica_id, cmd);
+            let message = command(&subject, token.clone(), session_key.clone());
+            let reply = handle_command(&state, &authn(), &message)
+                .await
+                .expect("Failed to handle command");
+            let resp =
+                serde_json::from_slice::<Response>(&reply).expect("Failed to parse response");
+
+            assert!(matches!(resp, Response::DeleteFailure(Reason::NotFound)));
+        }
+
+        let subject = format!("presence.replica.{}.unknown", replica_id);
+        handle_command(&state, &authn(), &command(&subject, token, session_key))
+            .await
+            .expect_err("Unexpectedly succeeded");
+    }
+
+    #[tokio::test]
+    async fn reject_unsigned_nats_command() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let replica_id = Uuid::new_v4();
+        let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let session_key = SessionKey::new(agent.agent_id().to_owned(), classroom_id);
+        let subject = format!("presence.replica.{}.{}", replica_id, KICK_COMMAND);
+
+        // Without a token at all
+        let payload = serde_json::json!({ "session_key": session_key });
+        let mut message = command(&subject, String::new(), session_key.clone());
+        message.payload = payload.to_string().into();
+        handle_command(&state, &authn(), &message)
+            .await
+            .expect_err("Unexpectedly succeeded");
+
+        // Tokens of agents and garbage
+        for token in [agent.token(), "invalid".to_owned()] {
+            let message = command(&subject, token, session_key.clone());
+            handle_command(&state, &authn(), &message)
+                .await
+                .expect_err("Unexpectedly succeeded");
+        }
+    }
+
+    #[tokio::test]
+    async fn shut_down_unregistered_replica() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+
+        let replica_id = {
+            let mut conn = db_pool.get_conn().await;
+
+            db::replica::InsertQuery::new("presence-1".into(), IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .expect("Failed to create insert query for replica")
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert a replica")
+                .id
+        };
+        let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+
+        assert!(heartbeat(&state).await.expect("Failed to send heartbeat"));
+
+        // Another replica has considered this one dead
+        {
+            let mut conn = state.get_conn().await.expect("Failed to get connection");
+
+            db::replica::DeleteQuery::new(replica_id)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to delete replica");
+        }
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(());
+        let (unregistered_tx, unregistered_rx) = oneshot::channel();
+        let handle = run_heartbeat(state, shutdown_rx, unregistered_tx);
+
+        tokio::time::timeout(Duration::from_secs(5), unregistered_rx)
+            .await
+            .expect("Replica is not shut down")
+            .expect("Heartbeat is stopped without notification");
+
+        handle.await.expect("Failed to stop heartbeat");
+    }
+}
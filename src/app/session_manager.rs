@@ -1,5 +1,9 @@
-use crate::session::{SessionId, SessionKey};
+use crate::{
+    classroom::ClassroomId,
+    session::{SessionId, SessionKey},
+};
 use std::{collections::HashMap, time::Duration};
+use svc_authn::{AccountId, Authenticable};
 use tokio::{
     sync::{mpsc, oneshot, watch},
     task::JoinHandle,
@@ -15,12 +19,18 @@ pub enum SessionCommand {
     Terminate(SessionKey, oneshot::Sender<TerminateSession>),
     // To close connections on another replica (via internal API)
     Delete(SessionKey, oneshot::Sender<DeleteSession>),
+    // To forcibly disconnect an agent on this replica, on all devices
+    Kick(SessionKey, oneshot::Sender<DeleteSession>),
+    // To disconnect all agents of an account on this replica whose access to the classroom is revoked
+    Deny(ClassroomId, AccountId),
 }
 
 #[derive(Debug)]
 pub enum ConnectionCommand {
     Close,
     Terminate,
+    Kick,
+    Deny,
 }
 
 #[derive(Debug)]
@@ -82,6 +92,41 @@ pub fn run(
                                 }
                             }
                         }
+                        // Forcibly disconnect an agent on this replica, on all devices
+                        SessionCommand::Kick(session_key, resp) => {
+                            let keys = sessions
+                                .keys()
+                                .filter(|key| key.is_same_agent(&session_key))
+                                .cloned()
+                                .collect::<Vec<_>>();
+
+                            let mut result = DeleteSession::NotFound;
+                            for key in keys {
+                                if let Some((session_id, cmd)) = sessions.remove(&key) {
+                                    result = DeleteSession::Success(session_id);
+                                    cmd.send(ConnectionCommand::Kick).await.ok();
+                                }
+                            }
+
+                            resp.send(result).ok();
+                        }
+                        // Disconnect all agents of an account whose access is revoked
+                        SessionCommand::Deny(classroom_id, account_id) => {
+                            let keys = sessions
+                                .keys()
+                                .filter(|key| {
+                                    key.classroom_id == classroom_id
+                                        && key.agent_id.as_account_id() == &account_id
+                                })
+                                .cloned()
+                                .collect::<Vec<_>>();
+
+                            for key in keys {
+                                if let Some((_, cmd)) = sessions.remove(&key) {
+                                    cmd.send(ConnectionCommand::Deny).await.ok();
+                                }
+                            }
+                        }
                     }
                 }
                 // Graceful shutdown
@@ -2,8 +2,11 @@ use crate::{
     app::{
         metrics::Metrics,
         nats::NatsClient,
+        rate_limiter::AgentRateLimiter,
+        replica::InternalApiClient,
         session_manager::{ConnectionCommand, DeleteSession, SessionCommand, TerminateSession},
     },
+    classroom::ClassroomId,
     config::Config,
     session::{SessionId, SessionKey},
 };
@@ -11,6 +14,7 @@ use anyhow::{Context, Result};
 use async_trait::async_trait;
 use sqlx::{pool::PoolConnection, PgPool, Postgres};
 use std::sync::Arc;
+use svc_authn::AccountId;
 use svc_authz::ClientMap as Authz;
 use tokio::sync::{
     mpsc::{self, UnboundedSender},
@@ -33,9 +37,13 @@ pub trait State: Send + Sync + Clone + 'static {
     ) -> Result<mpsc::Receiver<ConnectionCommand>>;
     async fn terminate_session(&self, session_key: SessionKey) -> Result<TerminateSession>;
     async fn delete_session(&self, session_key: SessionKey) -> Result<DeleteSession>;
+    async fn kick_session(&self, session_key: SessionKey) -> Result<DeleteSession>;
+    fn deny_sessions(&self, classroom_id: ClassroomId, account_id: AccountId) -> Result<()>;
     async fn get_conn(&self) -> Result<PoolConnection<Postgres>>;
     fn nats_client(&self) -> &dyn NatsClient;
     fn lookup_known_authz_audience(&self, aud: &str) -> Option<&str>;
+    fn internal_api(&self) -> &InternalApiClient;
+    fn signal_limiter(&self) -> &AgentRateLimiter;
 }
 
 #[derive(Clone)]
@@ -52,6 +60,8 @@ struct InnerState {
     nats_client: Box<dyn NatsClient>,
     metrics: Metrics,
     audience_estimator: AudienceEstimator,
+    internal_api: InternalApiClient,
+    signal_limiter: AgentRateLimiter,
 }
 
 impl AppState {
@@ -63,9 +73,12 @@ impl AppState {
         cmd_sender: UnboundedSender<SessionCommand>,
         nats_client: N,
         metrics: Metrics,
-    ) -> Self {
+    ) -> Result<Self> {
         let audience_estimator = AudienceEstimator::new(&config.authz);
-        Self {
+        let internal_api = InternalApiClient::new(&config)?;
+        let signal_limiter = AgentRateLimiter::new(config.websocket.signal_rate_limit);
+
+        Ok(Self {
             inner: Arc::new(InnerState {
                 config,
                 db_pool,
@@ -75,8 +88,10 @@ impl AppState {
                 nats_client: Box::new(nats_client),
                 metrics,
                 audience_estimator,
+                internal_api,
+                signal_limiter,
             }),
-        }
+        })
     }
 }
 
@@ -129,6 +144,23 @@ impl State for AppState {
         rx.await.context("Failed to receive a response of deletion")
     }
 
+    async fn kick_session(&self, session_key: SessionKey) -> Result<DeleteSession> {
+        let (tx, rx) = oneshot::channel::<DeleteSession>();
+        self.inner
+            .cmd_sender
+            .send(SessionCommand::Kick(session_key, tx))?;
+
+        rx.await.context("Failed to receive a response of kick")
+    }
+
+    fn deny_sessions(&self, classroom_id: ClassroomId, account_id: AccountId) -> Result<()> {
+        self.inner
+            .cmd_sender
+            .send(SessionCommand::Deny(classroom_id, account_id))?;
+
+        Ok(())
+    }
+
     async fn get_conn(&self) -> Result<PoolConnection<Postgres>> {
         self.inner
             .db_pool
@@ -144,4 +176,12 @@ impl State for AppState {
     fn lookup_known_authz_audience(&self, aud: &str) -> Option<&str> {
         self.inner.audience_estimator.estimate(aud)
     }
+
+    fn internal_api(&self) -> &InternalApiClient {
+        &self.inner.internal_api
+    }
+
+    fn signal_limiter(&self) -> &AgentRateLimiter {
+        &self.inner.signal_limiter
+    }
 }
@@ -0,0 +1,475 @@
+use crate::{
+    app::{
+        error::{Error, ErrorKind},
+        state::State,
+    },
+    classroom::ClassroomId,
+    config::{WebhookConfig, WebhooksConfig},
+    db::{agent_session_history, webhook_delivery},
+    session::SessionKey,
+};
+use anyhow::{Context, Result};
+use futures_util::{stream, StreamExt};
+use hmac::{Hmac, Mac};
+use serde_derive::Serialize;
+use sha2::Sha256;
+use sqlx::types::time::OffsetDateTime;
+use std::{collections::Bound, time::Duration};
+use svc_agent::AgentId;
+use svc_authn::Authenticable;
+use tokio::{sync::watch, task::JoinHandle, time::MissedTickBehavior};
+use tracing::{error, warn};
+
+const SIGNATURE_HEADER: &str = "X-Presence-Signature";
+/// Signed along with the body, so receivers can reject replayed requests.
+const TIMESTAMP_HEADER: &str = "X-Presence-Timestamp";
+
+/// Notifications of external systems, times are in milliseconds.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum Notification {
+    #[serde(rename = "agent.entered")]
+    AgentEntered {
+        classroom_id: ClassroomId,
+        agent_id: AgentId,
+        occurred_at: i64,
+    },
+    #[serde(rename = "agent.left")]
+    AgentLeft {
+        classroom_id: ClassroomId,
+        agent_id: AgentId,
+        occurred_at: i64,
+        /// How long the agent was in the classroom, from the first connect
+        /// to the last disconnect of its devices.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        duration: Option<i64>,
+    },
+}
+
+/// Queues `agent.entered` for the webhook of the agent's audience, if any.
+pub async fn notify_entered<S: State>(state: &S, session_key: &SessionKey) -> Result<()> {
+    let notification = Notification::AgentEntered {
+        classroom_id: session_key.classroom_id,
+        agent_id: session_key.agent_id.clone(),
+        occurred_at: now_millis(),
+    };
+
+    enqueue(state, &session_key.agent_id, &notification).await
+}
+
+/// Queues `agent.left` for the webhook of the agent's audience, if any.
+/// The session must be already moved to history.
+pub async fn notify_left<S: State>(state: &S, session_key: &SessionKey) -> Result<()> {
+    if !is_enabled(state, &session_key.agent_id) {
+        return Ok(());
+    }
+
+    let history = {
+        let mut conn = state.get_conn().await?;
+
+        agent_session_history::LastLifetimeQuery::new(
+            session_key.classroom_id,
+            &session_key.agent_id,
+        )
+        .execute(&mut conn)
+        .await
+        .context("Failed to get history of the agent")?
+    };
+
+    let duration =
+        history.and_then(
+            |history| match (history.lifetime.start, history.lifetime.end) {
+                (Bound::Included(start), Bound::Excluded(end)) => {
+                    Some((end - start).whole_milliseconds() as i64)
+                }
+                _ => None,
+            },
+        );
+
+    let notification = Notification::AgentLeft {
+        classroom_id: session_key.classroom_id,
+        agent_id: session_key.agent_id.clone(),
+        occurred_at: now_millis(),
+        duration,
+    };
+
+    enqueue(state, &session_key.agent_id, &notification).await
+}
+
+fn is_enabled<S: State>(state: &S, agent_id: &AgentId) -> bool {
+    let audience = agent_id.as_account_id().audience();
+    state.config().webhooks.audiences.contains_key(audience)
+}
+
+async fn enqueue<S: State>(
+    state: &S,
+    agent_id: &AgentId,
+    notification: &Notification,
+) -> Result<()> {
+    if !is_enabled(state, agent_id) {
+        return Ok(());
+    }
+
+    let payload = serde_json::to_string(notification)?;
+    let mut conn = state.get_conn().await?;
+
+    webhook_delivery::InsertQuery::new(agent_id.as_account_id().audience(), &payload)
+        .execute(&mut conn)
+        .await
+        .context("Failed to queue webhook notification")?;
+
+    Ok(())
+}
+
+/// Sends queued notifications to webhooks and retries failed ones with exponential backoff.
+/// Any replica may send any notification.
+pub fn run<S: State>(state: S, mut shutdown_rx: watch::Receiver<()>) -> Result<JoinHandle<()>> {
+    let config = state.config().webhooks.clone();
+    let client = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .build()
+        .context("Failed to build webhook client")?;
+
+    let handle = tokio::task::spawn(async move {
+        let mut poll_interval = tokio::time::interval(config.poll_interval);
+        poll_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+        loop {
+            tokio::select! {
+                _ = poll_interval.tick() => {
+                    if let Err(e) = deliver_pending(&state, &client, &config).await {
+                        error!(error = %e, "failed to deliver webhook notifications");
+                        Error::new(ErrorKind::WebhookDeliveryFailed, e).notify_sentry();
+                    }
+                }
+                _ = shutdown_rx.changed() => {
+                    break;
+                }
+            }
+        }
+    });
+
+    Ok(handle)
+}
+
+async fn deliver_pending<S: State>(
+    state: &S,
+    client: &reqwest::Client,
+    config: &WebhooksConfig,
+) -> Result<()> {
+    let deliveries = {
+        let mut conn = state.get_conn().await?;
+
+        let lease_until = OffsetDateTime::now_utc() + lease(config);
+        webhook_delivery::ClaimQuery::new(config.batch_size, lease_until)
+            .execute(&mut conn)
+            .await
+            .context("Failed to claim webhook deliveries")?
+    };
+
+    // Connections are only taken to record results, not for the requests
+    stream::iter(deliveries)
+        .for_each_concurrent(config.concurrency.max(1), |delivery| async move {
+            let id = delivery.id;
+            if let Err(e) = deliver(state, client, config, delivery).await {
+                error!(error = %e, id, "failed to deliver webhook notification");
+                Error::new(ErrorKind::WebhookDeliveryFailed, e).notify_sentry();
+            }
+        })
+        .await;
+
+    Ok(())
+}
+
+async fn deliver<S: State>(
+    state: &S,
+    client: &reqwest::Client,
+    config: &WebhooksConfig,
+    delivery: webhook_delivery::WebhookDelivery,
+) -> Result<()> {
+    let webhook = match config.audiences.get(&delivery.audience) {
+        Some(webhook) => webhook,
+        None => {
+            warn!(audience = %delivery.audience, "webhook is not configured anymore, dropping notification");
+            let mut conn = state.get_conn().await?;
+            webhook_delivery::DeleteQuery::new(delivery.id)
+                .execute(&mut conn)
+                .await?;
+
+            return Ok(());
+        }
+    };
+
+    let result = send(client, webhook, delivery.payload).await;
+    let mut conn = state.get_conn().await?;
+
+    match result {
+        Ok(()) => {
+            webhook_delivery::DeleteQuery::new(delivery.id)
+                .execute(&mut conn)
+                .await?;
+        }
+        Err(e) if delivery.attempts >= config.max_attempts => {
+            error!(error = %e, id = delivery.id, attempts = delivery.attempts, "webhook notification is dropped");
+            Error::new(ErrorKind::WebhookDeliveryFailed, e).notify_sentry();
+
+            webhook_delivery::DeleteQuery::new(delivery.id)
+                .execute(&mut conn)
+                .await?;
+        }
+        Err(e) => {
+            warn!(error = %e, id = delivery.id, attempts = delivery.attempts, "failed to send webhook notification");
+
+            let next_attempt_at = OffsetDateTime::now_utc() + backoff(config, delivery.attempts);
+            webhook_delivery::RescheduleQuery::new(delivery.id, next_attempt_at)
+                .execute(&mut conn)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Deliveries are leased until the whole batch is sent,
+/// so other replicas don't pick them up in the meantime.
+/// One more request timeout is a margin for recording the results.
+fn lease(config: &WebhooksConfig) -> Duration {
+    let concurrency = config.concurrency.max(1) as u64;
+    let batch_size = config.batch_size.max(1) as u64;
+    let rounds = (batch_size - 1) / concurrency + 1;
+
+    config
+        .timeout
+        .saturating_mul(u32::try_from(rounds + 1).unwrap_or(u32::MAX))
+}
+
+async fn send(client: &reqwest::Client, webhook: &WebhookConfig, payload: String) -> Result<()> {
+    // Every attempt is signed anew, so retries aren't rejected as stale
+    let timestamp = OffsetDateTime::now_utc().unix_timestamp();
+    let signature = sign(&webhook.secret, timestamp, payload.as_bytes())?;
+
+    client
+        .post(&webhook.url)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, format!("sha256={}", signature))
+        .body(payload)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Hex-encoded HMAC-SHA256 of the timestamp and the payload joined by a dot.
+fn sign(secret: &str, timestamp: i64, payload: &[u8]) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())?;
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(payload);
+
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// The delay before the next attempt after the given number of failed ones.
+fn backoff(config: &WebhooksConfig, attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 31) as u32;
+
+    config
+        .min_backoff
+        .saturating_mul(2u32.saturating_pow(exponent))
+        .min(config.max_backoff)
+}
+
+fn now_millis() -> i64 {
+    (OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        app::history_manager,
+        db::{agent_session, replica},
+        test_helpers::prelude::*,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+    use uuid::Uuid;
+
+    #[test]
+    fn sign_payload() {
+        let signature =
+            sign("secret", 1700000000, br#"{"type":"agent.entered"}"#).expect("Failed to sign");
+
+        assert_eq!(
+            signature,
+            "5a7ff020f56d387ac98a75dfb168482f5667aece730ab2c84432e233a954de8f"
+        );
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let config = WebhooksConfig {
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        assert_eq!(backoff(&config, 1), Duration::from_secs(1));
+        assert_eq!(backoff(&config, 2), Duration::from_secs(2));
+        assert_eq!(backoff(&config, 4), Duration::from_secs(8));
+        assert_eq!(backoff(&config, 5), Duration::from_secs(10));
+        assert_eq!(backoff(&config, 100), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn lease_covers_batch() {
+        let config = WebhooksConfig {
+            timeout: Duration::from_secs(5),
+            batch_size: 100,
+            concurrency: 10,
+            ..Default::default()
+        };
+        assert_eq!(lease(&config), Duration::from_secs(55));
+
+        let config = WebhooksConfig {
+            batch_size: 15,
+            ..config
+        };
+        assert_eq!(lease(&config), Duration::from_secs(15));
+    }
+
+    #[tokio::test]
+    async fn left_duration_spans_all_devices() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let now = OffsetDateTime::now_utc();
+
+        let (replica_id, sessions) = {
+            let mut conn = db_pool.get_conn().await;
+
+            let replica_id = replica::InsertQuery::new(
+                "presence-1".into(),
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            )
+            .expect("Failed to create insert query for replica")
+            .execute(&mut conn)
+            .await
+            .expect("Failed to insert a replica")
+            .id;
+
+            // The laptop is connected for the whole hour, the phone only for a while
+            let mut sessions = Vec::new();
+            for (device_id, started_at) in [
+                ("laptop", now - Duration::from_secs(60 * 60)),
+                ("phone", now - Duration::from_secs(10 * 60)),
+            ] {
+                let session = agent_session::InsertQuery::new(
+                    agent.agent_id(),
+                    classroom_id,
+                    replica_id,
+                    started_at,
+                )
+                .device(device_id)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session");
+                sessions.push(session.id);
+            }
+
+            (replica_id, sessions)
+        };
+
+        let mut state = TestState::new(db_pool.clone(), TestAuthz::new(), replica_id);
+        state.config_mut().webhooks.audiences.insert(
+            USR_AUDIENCE.to_string(),
+            WebhookConfig {
+                url: "http://127.0.0.1:9/".to_string(),
+                secret: "secret".to_string(),
+            },
+        );
+
+        // The phone leaves first, then the laptop
+        for session_id in sessions.into_iter().rev() {
+            history_manager::move_single_session(state.clone(), session_id)
+                .await
+                .expect("Failed to move session to history");
+        }
+
+        let session_key = SessionKey::new(agent.agent_id().to_owned(), classroom_id);
+        notify_left(&state, &session_key)
+            .await
+            .expect("Failed to queue notification");
+
+        let mut conn = db_pool.get_conn().await;
+        let payload: String = sqlx::query_scalar("SELECT payload FROM webhook_delivery")
+            .fetch_one(&mut conn)
+            .await
+            .expect("Failed to get delivery");
+        let notification: serde_json::Value =
+            serde_json::from_str(&payload).expect("Failed to parse notification");
+
+        assert_eq!(notification["type"], "agent.left");
+        let duration = notification["duration"]
+            .as_i64()
+            .expect("Failed to get duration");
+        assert!(duration >= 60 * 60 * 1000, "duration = {duration}");
+    }
+
+    #[tokio::test]
+    async fn failed_delivery_is_rescheduled() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let agent = TestAgent::new("web", "user1", USR_AUDIENCE);
+        let other = TestAgent::new("web", "user2", "other.example.org");
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let mut state = TestState::new(db_pool.clone(), TestAuthz::new(), Uuid::new_v4());
+        state.config_mut().webhooks.min_backoff = Duration::from_secs(60 * 60);
+        state.config_mut().webhooks.audiences.insert(
+            USR_AUDIENCE.to_string(),
+            WebhookConfig {
+                // Nothing listens on the port
+                url: "http://127.0.0.1:9/".to_string(),
+                secret: "secret".to_string(),
+            },
+        );
+
+        let session_key = SessionKey::new(agent.agent_id().to_owned(), classroom_id);
+        notify_entered(&state, &session_key)
+            .await
+            .expect("Failed to queue notification");
+
+        // The audience of the agent has no webhook
+        let session_key = SessionKey::new(other.agent_id().to_owned(), classroom_id);
+        notify_entered(&state, &session_key)
+            .await
+            .expect("Failed to queue notification");
+
+        let client = reqwest::Client::new();
+        let config = state.config().webhooks.clone();
+        deliver_pending(&state, &client, &config)
+            .await
+            .expect("Failed to deliver notifications");
+
+        let mut conn = db_pool.get_conn().await;
+        let far_future = OffsetDateTime::now_utc() + Duration::from_secs(60 * 60 * 24);
+
+        // Not due yet
+        let deliveries = webhook_delivery::ClaimQuery::new(10, far_future)
+            .execute(&mut conn)
+            .await
+            .expect("Failed to claim deliveries");
+        assert!(deliveries.is_empty());
+
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM webhook_delivery WHERE attempts = 1")
+                .fetch_one(&mut conn)
+                .await
+                .expect("Failed to count deliveries");
+        assert_eq!(count, 1);
+    }
+}
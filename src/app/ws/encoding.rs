@@ -0,0 +1,99 @@
+use anyhow::Result;
+use axum::extract::ws::Message;
+use serde::{Deserialize, Serialize};
+
+/// Encoding of frames after `connect_request`, which is always JSON.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// Text frames.
+    #[default]
+    Json,
+    /// Binary frames, they are smaller than JSON for large classrooms.
+    Msgpack,
+}
+
+impl Encoding {
+    /// Structs are encoded as maps and ids as strings, the same as in JSON.
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Message> {
+        match self {
+            Encoding::Json => Ok(Message::Text(serde_json::to_string(value)?)),
+            Encoding::Msgpack => {
+                let mut buf = Vec::new();
+                let mut serializer = rmp_serde::Serializer::new(&mut buf)
+                    .with_struct_map()
+                    .with_human_readable();
+                value.serialize(&mut serializer)?;
+
+                Ok(Message::Binary(buf))
+            }
+        }
+    }
+
+    /// Decodes a binary frame, text frames are always JSON.
+    pub fn decode<T: for<'de> Deserialize<'de>>(self, msg: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(msg)?),
+            Encoding::Msgpack => {
+                let mut deserializer =
+                    rmp_serde::Deserializer::from_read_ref(msg).with_human_readable();
+
+                Ok(T::deserialize(&mut deserializer)?)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{app::ws::Request, classroom::ClassroomId};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    #[test]
+    fn msgpack_roundtrip() {
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+        let request = json!({
+            "type": "join_classroom",
+            "payload": { "classroom_id": classroom_id }
+        });
+
+        let msg = match Encoding::Msgpack.encode(&request) {
+            Ok(Message::Binary(msg)) => msg,
+            _ => panic!("Expected a binary frame"),
+        };
+
+        // Ids are strings rather than raw bytes
+        let value: serde_json::Value = Encoding::Msgpack
+            .decode(&msg)
+            .expect("Failed to decode value");
+        assert_eq!(value, request);
+
+        let request: Request = Encoding::Msgpack
+            .decode(&msg)
+            .expect("Failed to decode request");
+        assert!(matches!(request, Request::JoinClassroom(r) if r.classroom_id == classroom_id));
+    }
+
+    #[test]
+    fn json_is_text() {
+        let msg = Encoding::Json.encode(&json!({ "type": "connect_success" }));
+
+        assert!(matches!(msg, Ok(Message::Text(text)) if text == r#"{"type":"connect_success"}"#));
+    }
+
+    #[test]
+    fn encoding_failed() {
+        struct Unserializable;
+
+        impl Serialize for Unserializable {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                Err(serde::ser::Error::custom("unserializable"))
+            }
+        }
+
+        assert!(Encoding::Json.encode(&Unserializable).is_err());
+        assert!(Encoding::Msgpack.encode(&Unserializable).is_err());
+    }
+}
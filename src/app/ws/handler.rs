@@ -1,24 +1,35 @@
 use crate::{
     app::{
-        self, history_manager,
-        metrics::AuthzMeasure,
-        replica,
+        self,
+        access::{self, CONNECT_ACTION},
+        history_manager,
+        nats::{ENTERED_OPERATION, LEFT_OPERATION},
+        occupancy, replica,
         session_manager::ConnectionCommand,
         session_manager::TerminateSession,
         state::State,
+        webhook,
         ws::{
-            ConnectRequest, RecoverableSessionError, Request, Response, UnrecoverableSessionError,
+            restriction::{AuthzDecisions, Overflow, Restriction},
+            ClassroomRequest, ConnectOptions, ConnectRequest, Encoding, PublishRequest,
+            RecoverableSessionError, RefreshTokenRequest, Request, RequestError, Response,
+            SetStatusRequest, UnrecoverableSessionError, MAX_STATUS_TEXT_LENGTH,
         },
     },
-    authz::AuthzObject,
     classroom::ClassroomId,
     db::{
         self,
-        agent_session::{self, InsertResult},
+        agent_session::{self, Agent, InsertResult},
+        classroom_occupancy,
+    },
+    event::{
+        AgentEventV1 as AgentEvent, EventV1 as Event, MessageEventV1 as MessageEvent,
+        SignalEventV1 as SignalEvent,
     },
     session::*,
 };
 use anyhow::{anyhow, Result};
+use async_nats::Message as SignalMessage;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
@@ -27,28 +38,34 @@ use axum::{
     response::IntoResponse,
     Error,
 };
-use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use futures_util::{
+    future,
+    stream::{self, SplitSink},
+    SinkExt, Stream, StreamExt,
+};
 use serde::Serialize;
 use serde_json::json;
-use sqlx::types::time::OffsetDateTime;
-use std::{future::Future, sync::Arc};
+use sqlx::{types::time::OffsetDateTime, Connection, PgConnection};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
 use svc_agent::AgentId;
 use svc_authn::{
     jose::ConfigMap, token::jws_compact::extract::decode_jws_compact_with_config, AccountId,
     Authenticable,
 };
 use svc_error::extension::sentry;
-use svc_events::{AgentEventV1 as AgentEvent, EventV1 as Event};
 use svc_nats_client::Message as NatsMessage;
 use tokio::{
     sync::mpsc::Receiver,
-    time::{interval, MissedTickBehavior},
+    time::{interval, sleep, sleep_until, Instant, MissedTickBehavior},
 };
-use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{wrappers::ReceiverStream, StreamMap};
 use tracing::{error, info, warn};
 
-const ENTERED_OPERATION: &str = "entered";
-const LEFT_OPERATION: &str = "left";
+const STATUS_CHANGED_OPERATION: &str = "status_changed";
+const IDLE_OPERATION: &str = "idle";
+const ACTIVE_OPERATION: &str = "active";
+const PRESENCE_SNAPSHOT_PAGE_SIZE: usize = 1_000;
+const PUBLISH_ACTION: &str = "publish";
 
 pub async fn handler<S: State>(
     ws: WebSocketUpgrade,
@@ -58,25 +75,185 @@ pub async fn handler<S: State>(
     ws.on_upgrade(|socket| handle_socket(socket, authn, state))
 }
 
+/// Sessions of the connection in joined classrooms.
+///
+/// Events and commands of each session are merged into single streams,
+/// so the connection loop doesn't depend on the number of classrooms.
+#[derive(Default)]
+struct Subscriptions {
+    /// The device of the connection, the same in all joined classrooms.
+    device_id: String,
+    /// Metadata of the agent from `connect_request`, the same in all joined classrooms.
+    metadata: Option<serde_json::Value>,
+    sessions: HashMap<ClassroomId, Session>,
+    nats_streams: StreamMap<ClassroomId, SessionStream<Arc<NatsMessage>>>,
+    signal_streams: StreamMap<ClassroomId, SessionStream<Arc<SignalMessage>>>,
+    cmd_streams: StreamMap<ClassroomId, SessionStream<ConnectionCommand>>,
+    /// The last stream sequence replayed in the classroom after reconnecting.
+    replayed: HashMap<ClassroomId, u64>,
+    activity: Activity,
+    token: TokenExpiry,
+    authz_decisions: AuthzDecisions,
+}
+
+/// Expiration of the token of the connection, it's extended by `refresh_token`.
+#[derive(Default)]
+pub(super) struct TokenExpiry {
+    expires_at: Option<Instant>,
+    /// `token_expiring` is sent once per token.
+    notified: bool,
+}
+
+impl TokenExpiry {
+    /// Tokens without `exp` never expire.
+    pub(super) fn new(exp: Option<u64>) -> Self {
+        let expires_at = exp.map(|exp| {
+            let now = OffsetDateTime::now_utc().unix_timestamp();
+            let remaining = (exp as i64).saturating_sub(now).max(0) as u64;
+            Instant::now() + Duration::from_secs(remaining)
+        });
+
+        Self {
+            expires_at,
+            notified: false,
+        }
+    }
+
+    /// When `token_expiring` must be sent or, after that, the connection must be closed.
+    pub(super) fn deadline(&self, refresh_window: Duration) -> Option<Instant> {
+        let expires_at = self.expires_at?;
+        if self.notified {
+            return Some(expires_at);
+        }
+
+        Some(expires_at.checked_sub(refresh_window).unwrap_or(expires_at))
+    }
+
+    /// Returns `true` if the token is expired, otherwise marks it as notified.
+    pub(super) fn notify(&mut self) -> bool {
+        if self.notified {
+            return true;
+        }
+
+        self.notified = true;
+        false
+    }
+}
+
+/// Activity reported by the client, the same in all joined classrooms.
+struct Activity {
+    last_reported_at: Instant,
+    idle: bool,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self {
+            last_reported_at: Instant::now(),
+            idle: false,
+        }
+    }
+}
+
+impl Subscriptions {
+    fn insert(
+        &mut self,
+        session: Session,
+        nats_rx: ReceiverStream<Arc<NatsMessage>>,
+        signal_rx: Receiver<Arc<SignalMessage>>,
+        close_rx: Receiver<ConnectionCommand>,
+    ) {
+        let classroom_id = session.key().classroom_id;
+
+        self.nats_streams.insert(classroom_id, with_end(nats_rx));
+        self.signal_streams
+            .insert(classroom_id, with_end(ReceiverStream::new(signal_rx)));
+        self.cmd_streams
+            .insert(classroom_id, with_end(ReceiverStream::new(close_rx)));
+        self.sessions.insert(classroom_id, session);
+    }
+
+    fn remove(&mut self, classroom_id: &ClassroomId) -> Option<Session> {
+        self.nats_streams.remove(classroom_id);
+        self.signal_streams.remove(classroom_id);
+        self.cmd_streams.remove(classroom_id);
+        self.replayed.remove(classroom_id);
+        self.sessions.remove(classroom_id)
+    }
+
+    /// Checks whether the live event is already sent to the agent by the replay.
+    fn is_replayed(&mut self, classroom_id: ClassroomId, sequence: Option<u64>) -> bool {
+        let (replayed, sequence) = match (self.replayed.get(&classroom_id), sequence) {
+            (Some(replayed), Some(sequence)) => (*replayed, sequence),
+            _ => return false,
+        };
+
+        if sequence > replayed {
+            // Live events have caught up with the replay
+            self.replayed.remove(&classroom_id);
+            return false;
+        }
+
+        true
+    }
+
+    fn get(&self, classroom_id: &ClassroomId) -> Option<&Session> {
+        self.sessions.get(classroom_id)
+    }
+
+    fn contains(&self, classroom_id: &ClassroomId) -> bool {
+        self.sessions.contains_key(classroom_id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    fn sessions(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    fn into_sessions(self) -> impl Iterator<Item = Session> {
+        self.sessions.into_values()
+    }
+}
+
+type SessionStream<T> = Pin<Box<dyn Stream<Item = Option<T>> + Send + Sync>>;
+
+/// Yields `None` when the stream is over,
+/// since `StreamMap` silently drops finished streams.
+fn with_end<T, St>(stream: St) -> SessionStream<T>
+where
+    T: Send + Sync + 'static,
+    St: Stream<Item = T> + Send + Sync + 'static,
+{
+    Box::pin(stream.map(Some).chain(stream::once(future::ready(None))))
+}
+
 async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state: S) {
     let (mut sender, mut receiver) = socket.split();
 
-    let result = authenticate_and_create_session(state.clone(), receiver.next(), authn).await;
-    let session = match result {
-        Ok(session) => session,
+    let result =
+        authenticate_and_create_session(state.clone(), receiver.next(), authn.clone()).await;
+    let (session, options) = match result {
+        Ok(result) => result,
         Err(error) => {
             if error != UnrecoverableSessionError::AuthTimedOut {
                 error!(?error, "connection is closed (unsuccessful request)");
                 state.metrics().ws_connection_error().inc();
             }
 
-            close_conn_with_msg(sender, Response::from(error)).await;
+            close_conn_with_msg(sender, Encoding::Json, Response::from(error)).await;
             return;
         }
     };
 
-    let result = register_and_subscribe_session(state.clone(), &session).await;
-    let (mut nats_rx, mut close_rx) = match result {
+    // Frames after `connect_request` are sent in the negotiated encoding
+    let encoding = options.encoding;
+
+    let result =
+        register_and_subscribe_session(state.clone(), &session, options.metadata.as_ref()).await;
+    let (nats_rx, signal_rx, close_rx) = match result {
         Ok(result) => result,
         Err(err) => {
             let error = anyhow!(
@@ -86,31 +263,64 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
             error!(%error, %session);
             send_to_sentry(error);
 
-            // Delete the agent session from the replica
-            // If this is not done, then the next time the agent is connected, it won't be created in DB
-            if let Err(error) = state.terminate_session(session.key().clone()).await {
-                error!(%error, %session, "failed to terminate session");
-                send_to_sentry(error);
-            }
-
-            // Delete the agent session from DB
-            if let Err(error) = history_manager::move_single_session(state, session.id()).await {
-                error!(%error, %session, "failed to move session to history");
-                send_to_sentry(error);
-            }
+            let change = discard_session(state.clone(), &session).await;
+            publish_occupancy(&state, change).await;
 
-            close_conn_with_msg(sender, Response::from(err)).await;
+            close_conn_with_msg(sender, encoding, Response::from(err)).await;
             return;
         }
     };
 
     info!(%session, "successful registration");
-    let success = serialize_to_json(&Response::ConnectSuccess);
-    let _ = sender.send(Message::Text(success)).await;
+    let _ = send_message(&mut sender, encoding, &Response::ConnectSuccess).await;
+
+    // Missed events are replayed after subscribing to NATS,
+    // live events which are replayed are skipped then
+    let mut authz_decisions = AuthzDecisions::default();
+    let mut replayed_sequence = None;
+    if let Some(resume_from) = options.resume_from {
+        match replay_events(state.clone(), &session, resume_from, &mut authz_decisions).await {
+            Ok((envelopes, sequence)) => {
+                for envelope in envelopes {
+                    let _ = send_message(&mut sender, encoding, &envelope).await;
+                }
+
+                replayed_sequence = sequence;
+            }
+            Err(err) => {
+                let _ = send_message(&mut sender, encoding, &Response::from(err)).await;
+            }
+        }
+    }
+
+    // The snapshot is taken after subscribing to NATS,
+    // so there is no gap between the snapshot and the events
+    if options.presence_snapshot {
+        let resp = get_presence_snapshot(state.clone(), &session)
+            .await
+            .map(Response::PresenceSnapshot)
+            .unwrap_or_else(Response::from);
+        let _ = send_message(&mut sender, encoding, &resp).await;
+    }
 
     state.metrics().ws_connection_success().inc();
     state.metrics().ws_connection_total().inc();
 
+    // The agent of the connection is the same in all joined classrooms
+    let agent_id = session.key().agent_id.clone();
+    let mut subscriptions = Subscriptions {
+        device_id: session.key().device_id.clone(),
+        metadata: options.metadata,
+        token: TokenExpiry::new(options.token_expires_at),
+        authz_decisions,
+        ..Default::default()
+    };
+    let classroom_id = session.key().classroom_id;
+    subscriptions.insert(session, nats_rx, signal_rx, close_rx);
+    if let Some(sequence) = replayed_sequence {
+        subscriptions.replayed.insert(classroom_id, sequence);
+    }
+
     let mut ping_sent = false;
     // Mark a connection as terminating on graceful shutdown
     let mut connect_terminating = false;
@@ -123,68 +333,89 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
     ping_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
     pong_expiration_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
+    // Pings only prove that the connection is alive, so a human must be proven by `activity`
+    let idle_timeout = state.config().websocket.idle_timeout;
+    let idle_timer = sleep(idle_timeout);
+    tokio::pin!(idle_timer);
+
+    let token_refresh_window = state.config().websocket.token_refresh_window;
+
     loop {
+        // The token may be refreshed by any request, so the deadline is taken on every iteration
+        let token_deadline = subscriptions.token.deadline(token_refresh_window);
+
         tokio::select! {
-            msg = nats_rx.next() => {
-                tracing::debug!("got new event from nats");
+            Some((classroom_id, msg)) = subscriptions.nats_streams.next() => {
+                tracing::debug!(%classroom_id, "got new event from nats");
                 let msg = match msg {
                     Some(msg) => msg,
                     _ => {
-                        warn!("nats stream is over");
+                        warn!(%classroom_id, "nats stream is over");
                         break;
                     },
                 };
 
-                let headers = match svc_nats_client::Headers::try_from(msg.headers.clone().unwrap_or_default()) {
-                    Ok(headers) => headers,
-                    Err(err) => {
-                        error!(%err, "failed to parse nats headers");
-                        send_to_sentry(err.into());
-                        continue;
-                    }
-                };
-
-                // Don't send internal messages
-                if headers.is_internal() {
+                let sequence = msg.info().ok().map(|info| info.stream_sequence);
+                if subscriptions.is_replayed(classroom_id, sequence) {
                     continue;
                 }
 
-                // Don't send events to yourself
-                if session.key().agent_id == headers.sender_id().clone() {
-                    continue;
-                }
+                let restriction = Restriction::from_headers(msg.message.headers.as_ref());
+                let envelope = match build_envelope(&agent_id, classroom_id, &msg.message, sequence) {
+                    Some(envelope) => subscriptions.authz_decisions.admit(&state, &agent_id, classroom_id, &restriction, (envelope, sequence)),
+                    None => Ok(None),
+                };
+                let envelope = match envelope {
+                    Ok(envelope) => envelope,
+                    Err(Overflow) => {
+                        warn!(%classroom_id, "closing connection to resume it after authz catches up");
+                        break;
+                    }
+                };
 
-                if let Some(receiver_id) = headers.receiver_id() {
-                    // If there is a receiver_id in the nats headers,
-                    // then we send a message only to this agent
-                    if session.key().agent_id != receiver_id.clone() {
-                        continue;
+                if let Some((envelope, _)) = envelope {
+                    if let Err(err) = send_message(&mut sender, encoding, &envelope).await {
+                        error!(%err, "failed to send notification");
+                        send_to_sentry(err.into());
                     }
                 }
-
-                let payload = match serde_json::from_slice::<serde_json::Value>(&msg.payload) {
-                    Ok(json) => json,
-                    Err(error) => {
-                        error!(%error, "failed to deserialize nats payload");
-                        send_to_sentry(error.into());
+            }
+            Some((classroom_id, msg)) = subscriptions.signal_streams.next() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        // Signals are optional, so the connection stays open
+                        warn!(%classroom_id, "signal stream is over");
                         continue;
-                    }
+                    },
                 };
 
-                let envelope = json!({
-                    "id": headers.event_id(),
-                    "payload": payload
-                });
+                // Signals are not persisted, so they have no stream sequence
+                let restriction = Restriction::from_headers(msg.headers.as_ref());
+                let envelope = match build_envelope(&agent_id, classroom_id, &msg, None) {
+                    Some(envelope) => subscriptions.authz_decisions.admit(&state, &agent_id, classroom_id, &restriction, (envelope, None)),
+                    None => Ok(None),
+                };
+                let envelope = match envelope {
+                    Ok(envelope) => envelope,
+                    Err(Overflow) => {
+                        warn!(%classroom_id, "closing connection to resume it after authz catches up");
+                        break;
+                    }
+                };
 
-                match serde_json::to_string(&envelope) {
-                    Ok(s) => {
-                        if let Err(err) = sender.send(Message::Text(s)).await {
-                            error!(%err, "failed to send notification");
-                            send_to_sentry(err.into());
-                        }
+                if let Some((envelope, _)) = envelope {
+                    if let Err(err) = send_message(&mut sender, encoding, &envelope).await {
+                        error!(%err, "failed to send signal");
+                        send_to_sentry(err.into());
                     }
-                    Err(err) => {
-                        error!(%err, "failed to serialize envelope to json");
+                }
+            }
+            // Events deferred until authz decides whether the agent may receive them
+            envelopes = subscriptions.authz_decisions.resolved() => {
+                for (envelope, _) in envelopes {
+                    if let Err(err) = send_message(&mut sender, encoding, &envelope).await {
+                        error!(%err, "failed to send notification");
                         send_to_sentry(err.into());
                     }
                 }
@@ -204,6 +435,16 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
                     Ok(Message::Pong(_)) => {
                         ping_sent = false;
                     },
+                    Ok(msg @ (Message::Text(_) | Message::Binary(_))) => {
+                        let request = decode_request(msg, encoding);
+                        let resp = handle_request(state.clone(), authn.clone(), &agent_id, &mut subscriptions, request).await;
+                        if let Some(resp) = resp {
+                            if let Err(err) = send_message(&mut sender, encoding, &resp).await {
+                                error!(%err, "failed to send response");
+                                send_to_sentry(err.into());
+                            }
+                        }
+                    },
                     Ok(Message::Close(frame)) => {
                         match frame {
                             Some(f) => {
@@ -221,7 +462,7 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
                         break;
                     },
                     _ => {
-                        // Ping/Text/Binary messages - do nothing
+                        // Ping messages - do nothing
                     }
                 }
             }
@@ -241,29 +482,102 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
                 tracing::debug!("ping expiration");
                 if ping_sent {
                     warn!("Connection is closed (pong timeout exceeded)");
-                    close_conn_with_msg(sender, Response::from(UnrecoverableSessionError::PongTimedOut)).await;
+                    close_conn_with_msg(sender, encoding, Response::from(UnrecoverableSessionError::PongTimedOut)).await;
                     break;
                 }
             }
-            // Close connections
-            cmd = close_rx.recv() => {
+            // Mark the agent idle if there was no activity since the timer was set
+            _ = &mut idle_timer, if !subscriptions.activity.idle => {
+                let deadline = subscriptions.activity.last_reported_at + idle_timeout;
+                if deadline > Instant::now() {
+                    idle_timer.as_mut().reset(deadline);
+                    continue;
+                }
+
+                subscriptions.activity.idle = true;
+                set_idle(state.clone(), &subscriptions, true).await.ok();
+            }
+            // Ask for a new token before the current one expires, close the connection after
+            _ = sleep_until(token_deadline.unwrap_or_else(Instant::now)), if token_deadline.is_some() => {
+                if !subscriptions.token.notify() {
+                    let resp = Response::from(RecoverableSessionError::TokenExpiring);
+                    send_message(&mut sender, encoding, &resp).await.ok();
+                    continue;
+                }
+
+                warn!(%agent_id, "Connection is closed (token expired)");
+                close_conn_with_msg(sender, encoding, Response::from(UnrecoverableSessionError::Unauthenticated)).await;
+                break;
+            }
+            // Close sessions
+            Some((classroom_id, cmd)) = subscriptions.cmd_streams.next() => {
                 let cmd = match cmd {
                     Some(cmd) => cmd,
                     None => {
-                        warn!("cmd channel is closed, leaving...");
+                        warn!(%classroom_id, "cmd channel is closed, leaving...");
                         break;
                     }
                 };
 
                 match cmd {
                     ConnectionCommand::Close => {
-                        close_conn_with_msg(sender, Response::from(UnrecoverableSessionError::Replaced)).await;
+                        // The session is taken over by another connection,
+                        // so it must not be moved to history here
+                        subscriptions.remove(&classroom_id);
+
+                        if !subscriptions.is_empty() {
+                            info!(%classroom_id, "Session is replaced");
+                            let resp = Response::ClassroomReplaced { classroom_id };
+                            send_message(&mut sender, encoding, &resp).await.ok();
+
+                            continue;
+                        }
+
+                        close_conn_with_msg(sender, encoding, Response::from(UnrecoverableSessionError::Replaced)).await;
+                    }
+                    ConnectionCommand::Kick | ConnectionCommand::Deny => {
+                        // The session manager has already forgotten the session,
+                        // so it's only left in the classroom
+                        if let Some(session) = subscriptions.remove(&classroom_id) {
+                            let change = move_to_history(state.clone(), &session).await;
+                            publish_left(state.clone(), &session).await;
+                            publish_occupancy(&state, change).await;
+                        }
+
+                        let kicked = matches!(cmd, ConnectionCommand::Kick);
+                        if !subscriptions.is_empty() {
+                            let resp = if kicked {
+                                info!(%classroom_id, "Agent is kicked from the classroom");
+                                Response::ClassroomKicked { classroom_id }
+                            } else {
+                                info!(%classroom_id, "Access to the classroom is revoked");
+                                Response::ClassroomAccessDenied { classroom_id }
+                            };
+                            send_message(&mut sender, encoding, &resp).await.ok();
+
+                            continue;
+                        }
+
+                        let error = if kicked {
+                            UnrecoverableSessionError::Kicked
+                        } else {
+                            UnrecoverableSessionError::AccessDenied
+                        };
+                        close_conn_with_msg(sender, encoding, Response::from(error)).await;
+
+                        info!("Connection is closed (kicked or access denied)");
+                        state.metrics().ws_connection_total().dec();
+
+                        return;
                     }
                     ConnectionCommand::Terminate => {
-                        connect_terminating = true;
-                        let msg = serialize_to_json(&Response::from(RecoverableSessionError::Terminated));
-                        sender.send(Message::Text(msg)).await.ok();
-                        tracing::debug!("terminating, notification sent");
+                        // The command is sent to every session of the connection
+                        if !connect_terminating {
+                            connect_terminating = true;
+                            let resp = Response::from(RecoverableSessionError::Terminated);
+                            send_message(&mut sender, encoding, &resp).await.ok();
+                            tracing::debug!("terminating, notification sent");
+                        }
 
                         continue;
                     }
@@ -285,6 +599,136 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
         return;
     }
 
+    for session in subscriptions.into_sessions() {
+        leave_session(state.clone(), &session).await;
+    }
+}
+
+/// Builds the envelope of the event from NATS.
+/// Returns `None` if the event must not be sent to the agent.
+pub(super) fn build_envelope(
+    agent_id: &AgentId,
+    classroom_id: ClassroomId,
+    msg: &SignalMessage,
+    sequence: Option<u64>,
+) -> Option<serde_json::Value> {
+    let headers = match svc_nats_client::Headers::try_from(msg.headers.clone().unwrap_or_default())
+    {
+        Ok(headers) => headers,
+        Err(err) => {
+            error!(%err, "failed to parse nats headers");
+            send_to_sentry(err.into());
+            return None;
+        }
+    };
+
+    // Don't send internal messages
+    if headers.is_internal() {
+        return None;
+    }
+
+    // Don't send events to yourself
+    if *agent_id == headers.sender_id().clone() {
+        return None;
+    }
+
+    if let Some(receiver_id) = headers.receiver_id() {
+        // If there is a receiver_id in the nats headers,
+        // then we send a message only to this agent
+        if agent_id != receiver_id {
+            return None;
+        }
+    }
+
+    let payload = match serde_json::from_slice::<serde_json::Value>(&msg.payload) {
+        Ok(json) => json,
+        Err(error) => {
+            error!(%error, "failed to deserialize nats payload");
+            send_to_sentry(error.into());
+            return None;
+        }
+    };
+
+    let envelope = json!({
+        "id": headers.event_id(),
+        "classroom_id": classroom_id,
+        "sequence": sequence,
+        "payload": payload
+    });
+
+    Some(envelope)
+}
+
+/// Returns envelopes of events missed by the agent since `resume_from`
+/// and the last replayed stream sequence.
+pub(super) async fn replay_events<S: State>(
+    state: S,
+    session: &Session,
+    resume_from: u64,
+    authz_decisions: &mut AuthzDecisions,
+) -> Result<(Vec<serde_json::Value>, Option<u64>), RequestError> {
+    let messages = state
+        .nats_client()
+        .replay(
+            session.key().classroom_id,
+            resume_from,
+            state.config().websocket.resume_window,
+        )
+        .await
+        .map_err(|e| {
+            error!(error = %e, %session, "Failed to replay events");
+            send_to_sentry(e);
+            RequestError::InternalServerError
+        })?;
+
+    let sequence = messages
+        .iter()
+        .filter_map(|msg| msg.info().ok().map(|info| info.stream_sequence))
+        .max();
+
+    let agent_id = &session.key().agent_id;
+    let classroom_id = session.key().classroom_id;
+
+    let mut envelopes = Vec::with_capacity(messages.len());
+    for msg in &messages {
+        let restriction = Restriction::from_headers(msg.message.headers.as_ref());
+        if !authz_decisions
+            .allows(&state, agent_id, classroom_id, &restriction)
+            .await
+        {
+            continue;
+        }
+
+        let sequence = msg.info().ok().map(|info| info.stream_sequence);
+        if let Some(envelope) = build_envelope(agent_id, classroom_id, &msg.message, sequence) {
+            envelopes.push(envelope);
+        }
+    }
+
+    Ok((envelopes, sequence))
+}
+
+/// Removes the session and notifies other agents that the agent left the classroom.
+pub(super) async fn leave_session<S: State>(state: S, session: &Session) {
+    let change = discard_session(state.clone(), session).await;
+    publish_left(state.clone(), session).await;
+    publish_occupancy(&state, change).await;
+}
+
+/// Sends `agent.left` unless the agent is still in the classroom on another device.
+///
+/// It's checked after the session is removed, so concurrently closed sessions
+/// may send it twice but never skip it.
+pub(super) async fn publish_left<S: State>(state: S, session: &Session) {
+    match count_other_devices(state.clone(), session.key()).await {
+        Ok(0) => {}
+        Ok(_) => return,
+        Err(e) => {
+            error!(error = %e, %session, "Failed to count devices of the agent");
+            send_to_sentry(e);
+        }
+    }
+
     let event = AgentEvent::Left {
         agent_id: session.key().clone().agent_id,
     };
@@ -292,13 +736,52 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
 
     if let Err(e) = state
         .nats_client()
-        .publish_event(&session, event, LEFT_OPERATION.into())
+        .publish_event(session, event, LEFT_OPERATION.into())
         .await
     {
         error!(error = %e, "Failed to send agent.left notification");
         send_to_sentry(e);
     }
 
+    if let Err(e) = webhook::notify_left(&state, session.key()).await {
+        error!(error = %e, %session, "Failed to queue agent.left webhook notification");
+        send_to_sentry(e);
+    }
+}
+
+async fn count_other_devices<S: State>(state: S, session_key: &SessionKey) -> Result<i64> {
+    let mut conn = state.get_conn().await?;
+
+    let count = agent_session::DeviceCountQuery::new(
+        session_key.classroom_id,
+        &session_key.agent_id,
+        &session_key.device_id,
+    )
+    .execute(&mut conn)
+    .await?;
+
+    Ok(count)
+}
+
+/// Publishes `classroom.occupied` or `classroom.vacated` if the session
+/// was the first or the last one in the classroom.
+pub(super) async fn publish_occupancy<S: State>(state: &S, change: Option<occupancy::Change>) {
+    if let Some(change) = change {
+        let classroom_id = change.classroom_id;
+
+        if let Err(e) = occupancy::publish(state, change).await {
+            error!(error = %e, %classroom_id, "Failed to publish classroom occupancy");
+            send_to_sentry(e);
+        }
+    }
+}
+
+/// Removes the session from the replica and moves it to history.
+/// Returns the occupancy change of the classroom to publish.
+pub(super) async fn discard_session<S: State>(
+    state: S,
+    session: &Session,
+) -> Option<occupancy::Change> {
     // Delete the agent session from the replica
     // If this is not done, then the next time the agent is connected,
     // other agents won't receive the `agent.entered` message
@@ -307,31 +790,65 @@ async fn handle_socket<S: State>(socket: WebSocket, authn: Arc<ConfigMap>, state
         send_to_sentry(e);
     }
 
-    // Delete the agent session from DB
-    if let Err(e) = history_manager::move_single_session(state, session.id()).await {
-        error!(error = %e, "Failed to move session to history");
-        send_to_sentry(e);
+    move_to_history(state, session).await
+}
+
+/// Deletes the agent session from DB.
+/// Returns the occupancy change of the classroom to publish.
+pub(super) async fn move_to_history<S: State>(
+    state: S,
+    session: &Session,
+) -> Option<occupancy::Change> {
+    match history_manager::move_single_session(state, session.id()).await {
+        Ok(change) => change,
+        Err(e) => {
+            error!(error = %e, "Failed to move session to history");
+            send_to_sentry(e);
+            None
+        }
     }
 }
 
-fn send_to_sentry(error: anyhow::Error) {
+pub(super) fn send_to_sentry(error: anyhow::Error) {
     if let Err(e) = sentry::send(Arc::new(error)) {
         error!(error = %e, "Failed to send error to sentry");
     }
 }
 
 /// Closes the WebSocket connection with a message
-async fn close_conn_with_msg<T: Serialize>(mut sender: SplitSink<WebSocket, Message>, resp: T) {
-    let resp = serialize_to_json(&resp);
-    sender.send(Message::Text(resp)).await.ok();
+async fn close_conn_with_msg<T: Serialize>(
+    mut sender: SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    resp: T,
+) {
+    send_message(&mut sender, encoding, &resp).await.ok();
     sender.close().await.ok();
 }
 
+/// Sends the message in the encoding of the connection.
+/// Messages which can't be encoded are skipped rather than sent empty.
+async fn send_message<T: Serialize>(
+    sender: &mut SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    value: &T,
+) -> Result<(), Error> {
+    let msg = match encoding.encode(value) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!(error = %e, "Failed to encode message");
+            send_to_sentry(e);
+            return Ok(());
+        }
+    };
+
+    sender.send(msg).await
+}
+
 async fn authenticate_and_create_session<S, F>(
     state: S,
     future: F,
     authn: Arc<ConfigMap>,
-) -> Result<Session, UnrecoverableSessionError>
+) -> Result<(Session, ConnectOptions), UnrecoverableSessionError>
 where
     S: State,
     F: Future<Output = Option<Result<Message, Error>>>,
@@ -355,10 +872,15 @@ where
     handle_authn_message(message, authn, state.clone()).await
 }
 
-async fn register_and_subscribe_session<S: State>(
+pub(super) async fn register_and_subscribe_session<S: State>(
     state: S,
     session: &Session,
-) -> Result<(ReceiverStream<NatsMessage>, Receiver<ConnectionCommand>)> {
+    metadata: Option<&serde_json::Value>,
+) -> Result<(
+    ReceiverStream<Arc<NatsMessage>>,
+    Receiver<Arc<SignalMessage>>,
+    Receiver<ConnectionCommand>,
+)> {
     // To close old connections from the same agents
     let close_rx = state.register_session(session.key().clone(), session.id())?;
 
@@ -368,13 +890,20 @@ async fn register_and_subscribe_session<S: State>(
         .await
         .map(ReceiverStream::new)?;
 
-    // Send `agent.entered` to others only if the session is new and not replaced
-    if session.kind() == SessionKind::Replaced {
-        return Ok((nats_rx, close_rx));
+    let signal_rx = state
+        .nats_client()
+        .subscribe_signals(session.key().classroom_id)
+        .await?;
+
+    // Send `agent.entered` to others only if the session is new,
+    // not replaced and not on another device of the agent
+    if session.kind() != SessionKind::New {
+        return Ok((nats_rx, signal_rx, close_rx));
     }
 
     let event = Event::from(AgentEvent::Entered {
         agent_id: session.key().clone().agent_id,
+        metadata: metadata.cloned(),
     });
 
     state
@@ -382,14 +911,19 @@ async fn register_and_subscribe_session<S: State>(
         .publish_event(session, event, ENTERED_OPERATION.into())
         .await?;
 
-    Ok((nats_rx, close_rx))
+    if let Err(e) = webhook::notify_entered(&state, session.key()).await {
+        error!(error = %e, %session, "Failed to queue agent.entered webhook notification");
+        send_to_sentry(e);
+    }
+
+    Ok((nats_rx, signal_rx, close_rx))
 }
 
 async fn handle_authn_message<S: State>(
     message: Message,
     authn: Arc<ConfigMap>,
     state: S,
-) -> Result<Session, UnrecoverableSessionError> {
+) -> Result<(Session, ConnectOptions), UnrecoverableSessionError> {
     let msg = match message {
         Message::Text(msg) => msg,
         _ => return Err(UnrecoverableSessionError::UnsupportedRequest),
@@ -397,24 +931,8 @@ async fn handle_authn_message<S: State>(
 
     let result = serde_json::from_str::<Request>(&msg);
     match result {
-        Ok(Request::ConnectRequest(ConnectRequest {
-            token,
-            classroom_id,
-            agent_label,
-        })) => {
-            let agent_id = get_agent_id_from_token(token, authn, agent_label).map_err(|e| {
-                warn!(error = %e, "Failed to authenticate an agent");
-                UnrecoverableSessionError::Unauthenticated
-            })?;
-
-            authorize_agent(state.clone(), &agent_id, &classroom_id).await?;
-
-            let (session_id, session_kind) =
-                create_or_replace_agent_session(state, classroom_id, &agent_id).await?;
-            let session_key = SessionKey::new(agent_id, classroom_id);
-
-            Ok(Session::new(session_id, session_key, session_kind))
-        }
+        Ok(Request::ConnectRequest(request)) => connect(request, authn, state).await,
+        Ok(_) => Err(UnrecoverableSessionError::UnsupportedRequest),
         Err(e) => {
             error!(error = %e, "Failed to deserialize a message");
             send_to_sentry(e.into());
@@ -423,43 +941,549 @@ async fn handle_authn_message<S: State>(
     }
 }
 
-async fn create_or_replace_agent_session<S: State>(
+/// Authenticates and authorizes the agent and creates its session in the classroom.
+/// The same for all transports.
+pub(super) async fn connect<S: State>(
+    request: ConnectRequest,
+    authn: Arc<ConfigMap>,
     state: S,
-    classroom_id: ClassroomId,
-    agent_id: &AgentId,
-) -> Result<(SessionId, SessionKind), UnrecoverableSessionError> {
-    let mut conn = state.get_conn().await.map_err(|e| {
-        error!(error = %e, "Failed to get db connection");
-        send_to_sentry(e);
-        UnrecoverableSessionError::InternalServerError
+) -> Result<(Session, ConnectOptions), UnrecoverableSessionError> {
+    let ConnectRequest {
+        token,
+        classroom_id,
+        agent_label,
+        presence_snapshot,
+        resume_from,
+        device_id,
+        metadata,
+        encoding,
+    } = request;
+
+    let (agent_id, token_expires_at) = decode_token(token, authn, agent_label).map_err(|e| {
+        warn!(error = %e, "Failed to authenticate an agent");
+        UnrecoverableSessionError::Unauthenticated
     })?;
 
-    // Attempt to close old session on the same replica
-    let session_key = SessionKey::new(agent_id.clone(), classroom_id);
-    // If the session is found, don't create a new session and return the previous id
-    match state.terminate_session(session_key.clone()).await {
-        Ok(TerminateSession::Found(session_id)) => {
-            return Ok((session_id, SessionKind::Replaced));
+    authorize_agent(state.clone(), &agent_id, &classroom_id, CONNECT_ACTION).await?;
+
+    // Agents of other audiences have a single session, so the device is ignored
+    let audience = agent_id.as_account_id().audience();
+    let device_id = match state.config().devices.limit(audience) {
+        Some(_) => device_id.unwrap_or_default(),
+        None => String::new(),
+    };
+    let session_key = SessionKey::new(agent_id, classroom_id).with_device(device_id);
+
+    let (session_id, session_kind) =
+        create_or_replace_agent_session(state, &session_key, metadata.as_ref()).await?;
+
+    let session = Session::new(session_id, session_key, session_kind);
+    let options = ConnectOptions {
+        presence_snapshot,
+        resume_from,
+        metadata,
+        token_expires_at,
+        encoding,
+    };
+
+    Ok((session, options))
+}
+
+/// Decodes a request sent after the session is established.
+/// Text frames are always JSON, binary frames are accepted in the negotiated binary encoding only.
+fn decode_request(msg: Message, encoding: Encoding) -> Result<Request> {
+    match msg {
+        Message::Text(msg) => Ok(serde_json::from_str(&msg)?),
+        Message::Binary(msg) if encoding != Encoding::Json => encoding.decode(&msg),
+        _ => Err(anyhow!("unsupported frame")),
+    }
+}
+
+/// Handles requests sent by the agent after the session is established.
+async fn handle_request<S: State>(
+    state: S,
+    authn: Arc<ConfigMap>,
+    agent_id: &AgentId,
+    subscriptions: &mut Subscriptions,
+    request: Result<Request>,
+) -> Option<Response> {
+    let result = match request {
+        Ok(Request::SetStatus(request)) => set_status(state, subscriptions, request)
+            .await
+            .map(|_| Response::SetStatusSuccess),
+        Ok(Request::JoinClassroom(ClassroomRequest { classroom_id })) => {
+            join_classroom(state, agent_id, subscriptions, classroom_id)
+                .await
+                .map(|_| Response::JoinClassroomSuccess { classroom_id })
+        }
+        Ok(Request::LeaveClassroom(ClassroomRequest { classroom_id })) => {
+            leave_classroom(state, subscriptions, classroom_id)
+                .await
+                .map(|_| Response::LeaveClassroomSuccess { classroom_id })
+        }
+        Ok(Request::Publish(request)) => publish(state, agent_id, subscriptions, request)
+            .await
+            .map(|_| Response::PublishSuccess),
+        Ok(Request::Signal(request)) => {
+            // Signals are too frequent to confirm each of them, only errors are sent
+            return signal(state, agent_id, subscriptions, request)
+                .await
+                .err()
+                .map(Response::from);
+        }
+        Ok(Request::Activity) => {
+            // Activity is reported too often to confirm it, only errors are sent
+            return report_activity(state, subscriptions)
+                .await
+                .err()
+                .map(Response::from);
+        }
+        Ok(Request::RefreshToken(request)) => {
+            refresh_token(authn, agent_id, subscriptions, request)
+                .map(|_| Response::RefreshTokenSuccess)
         }
+        Ok(Request::ConnectRequest(_)) => Err(RequestError::UnsupportedRequest),
         Err(e) => {
-            error!(error = %e, "Failed to terminate session: {}", &session_key);
-            send_to_sentry(e);
-            return Err(UnrecoverableSessionError::InternalServerError);
+            warn!(error = %e, %agent_id, "Failed to deserialize a request");
+            Err(RequestError::SerializationFailed)
         }
-        _ => {}
-    }
+    };
 
-    let insert_query = agent_session::InsertQuery::new(
-        agent_id,
-        classroom_id,
-        state.replica_id(),
-        OffsetDateTime::now_utc(),
-    );
+    Some(result.unwrap_or_else(Response::from))
+}
 
-    match insert_query.execute(&mut conn).await {
-        InsertResult::Ok(agent_session) => Ok((agent_session.id, SessionKind::New)),
-        InsertResult::Error(e) => {
-            error!(error = %e, "Failed to create an agent session");
+/// Creates a session in one more classroom on the established connection.
+/// Joining an already joined classroom does nothing.
+async fn join_classroom<S: State>(
+    state: S,
+    agent_id: &AgentId,
+    subscriptions: &mut Subscriptions,
+    classroom_id: ClassroomId,
+) -> Result<(), RequestError> {
+    if subscriptions.contains(&classroom_id) {
+        return Ok(());
+    }
+
+    authorize_agent(state.clone(), agent_id, &classroom_id, CONNECT_ACTION).await?;
+
+    let session_key = SessionKey::new(agent_id.clone(), classroom_id)
+        .with_device(subscriptions.device_id.clone());
+    let (session_id, session_kind) = create_or_replace_agent_session(
+        state.clone(),
+        &session_key,
+        subscriptions.metadata.as_ref(),
+    )
+    .await?;
+    let session = Session::new(session_id, session_key, session_kind);
+
+    match register_and_subscribe_session(state.clone(), &session, subscriptions.metadata.as_ref())
+        .await
+    {
+        Ok((nats_rx, signal_rx, close_rx)) => {
+            info!(%session, "successful joining");
+
+            // Joining a classroom isn't activity, so the agent stays idle there as well
+            if subscriptions.activity.idle {
+                update_idle(state, &session, true).await.ok();
+            }
+
+            subscriptions.insert(session, nats_rx, signal_rx, close_rx);
+
+            Ok(())
+        }
+        Err(err) => {
+            let error = anyhow!(
+                "an error occurred during the registration of the session, {}",
+                &err
+            );
+            error!(%error, %session);
+            send_to_sentry(error);
+
+            let change = discard_session(state.clone(), &session).await;
+            publish_occupancy(&state, change).await;
+
+            Err(RequestError::InternalServerError)
+        }
+    }
+}
+
+/// Removes the session in the classroom, the connection stays open.
+async fn leave_classroom<S: State>(
+    state: S,
+    subscriptions: &mut Subscriptions,
+    classroom_id: ClassroomId,
+) -> Result<(), RequestError> {
+    let session = subscriptions
+        .remove(&classroom_id)
+        .ok_or(RequestError::ClassroomNotJoined)?;
+
+    leave_session(state, &session).await;
+    info!(%session, "successful leaving");
+
+    Ok(())
+}
+
+/// Publishes the message of the agent to the joined classroom.
+async fn publish<S: State>(
+    state: S,
+    agent_id: &AgentId,
+    subscriptions: &Subscriptions,
+    request: PublishRequest,
+) -> Result<(), RequestError> {
+    let PublishRequest {
+        classroom_id,
+        receiver_id,
+        data,
+    } = request;
+
+    let session = subscriptions
+        .get(&classroom_id)
+        .ok_or(RequestError::ClassroomNotJoined)?;
+
+    authorize_agent(state.clone(), agent_id, &classroom_id, PUBLISH_ACTION).await?;
+
+    let event = Event::from(MessageEvent::Published {
+        agent_id: agent_id.clone(),
+        data,
+    });
+
+    state
+        .nats_client()
+        .publish_message(session, event, receiver_id)
+        .await
+        .map_err(|e| {
+            error!(error = %e, %session, "Failed to publish a message");
+            send_to_sentry(e);
+            RequestError::InternalServerError
+        })
+}
+
+/// Publishes the ephemeral signal of the agent to the joined classroom.
+///
+/// Unlike messages, signals are not authorized one by one since they are too frequent,
+/// the agent is authorized on joining the classroom.
+async fn signal<S: State>(
+    state: S,
+    agent_id: &AgentId,
+    subscriptions: &Subscriptions,
+    request: PublishRequest,
+) -> Result<(), RequestError> {
+    let PublishRequest {
+        classroom_id,
+        receiver_id,
+        data,
+    } = request;
+
+    let session = subscriptions
+        .get(&classroom_id)
+        .ok_or(RequestError::ClassroomNotJoined)?;
+
+    // Agents share the limit across their connections, so they can't bypass it by opening more
+    if !state.signal_limiter().try_acquire(agent_id) {
+        return Err(RequestError::RateLimited);
+    }
+
+    let event = Event::from(SignalEvent::Sent {
+        agent_id: agent_id.clone(),
+        data,
+    });
+
+    state
+        .nats_client()
+        .publish_signal(session, event, receiver_id)
+        .await
+        .map_err(|e| {
+            error!(error = %e, %session, "Failed to publish a signal");
+            send_to_sentry(e);
+            RequestError::InternalServerError
+        })
+}
+
+async fn set_status<S: State>(
+    state: S,
+    subscriptions: &Subscriptions,
+    request: SetStatusRequest,
+) -> Result<(), RequestError> {
+    let SetStatusRequest {
+        classroom_id,
+        status,
+        status_text,
+    } = request;
+
+    // The request is well-formed, only its content is rejected
+    if status_text
+        .as_ref()
+        .is_some_and(|text| text.chars().count() > MAX_STATUS_TEXT_LENGTH)
+    {
+        return Err(RequestError::InvalidPayload);
+    }
+
+    let sessions = match classroom_id {
+        Some(classroom_id) => vec![subscriptions
+            .get(&classroom_id)
+            .ok_or(RequestError::ClassroomNotJoined)?],
+        None => subscriptions.sessions().collect(),
+    };
+
+    let mut conn = state.get_conn().await.map_err(|e| {
+        error!(error = %e, "Failed to get db connection");
+        send_to_sentry(e);
+        RequestError::InternalServerError
+    })?;
+
+    for session in sessions {
+        agent_session::UpdateStatusQuery::new(
+            session.key().classroom_id,
+            &session.key().agent_id,
+            status,
+            status_text.as_deref(),
+        )
+        .execute(&mut conn)
+        .await
+        .map_err(|e| {
+            error!(error = %e, %session, "Failed to update agent status");
+            send_to_sentry(e.into());
+            RequestError::InternalServerError
+        })?;
+
+        let event = Event::from(AgentEvent::StatusChanged {
+            agent_id: session.key().clone().agent_id,
+            status,
+            status_text: status_text.clone(),
+        });
+
+        if let Err(e) = state
+            .nats_client()
+            .publish_event(session, event, STATUS_CHANGED_OPERATION.into())
+            .await
+        {
+            error!(error = %e, %session, "Failed to send agent.status_changed notification");
+            send_to_sentry(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Replaces the token of the connection with a new one of the same agent.
+fn refresh_token(
+    authn: Arc<ConfigMap>,
+    agent_id: &AgentId,
+    subscriptions: &mut Subscriptions,
+    request: RefreshTokenRequest,
+) -> Result<(), RequestError> {
+    let (token_agent_id, token_expires_at) =
+        decode_token(request.token, authn, agent_id.label().to_owned()).map_err(|e| {
+            warn!(error = %e, %agent_id, "Failed to authenticate a refreshed token");
+            RequestError::Unauthenticated
+        })?;
+
+    if token_agent_id != *agent_id {
+        warn!(%agent_id, %token_agent_id, "Refreshed token belongs to another agent");
+        return Err(RequestError::AccessDenied);
+    }
+
+    subscriptions.token = TokenExpiry::new(token_expires_at);
+
+    Ok(())
+}
+
+/// Marks the agent active in all joined classrooms if it was idle.
+async fn report_activity<S: State>(
+    state: S,
+    subscriptions: &mut Subscriptions,
+) -> Result<(), RequestError> {
+    subscriptions.activity.last_reported_at = Instant::now();
+    if !subscriptions.activity.idle {
+        return Ok(());
+    }
+
+    subscriptions.activity.idle = false;
+    set_idle(state, subscriptions, false).await
+}
+
+async fn set_idle<S: State>(
+    state: S,
+    subscriptions: &Subscriptions,
+    idle: bool,
+) -> Result<(), RequestError> {
+    for session in subscriptions.sessions() {
+        update_idle(state.clone(), session, idle).await?;
+    }
+
+    Ok(())
+}
+
+/// Updates the idle flag of the session and sends `agent.idle` or `agent.active`
+/// unless the agent is active on another device, so the agent stays active then.
+async fn update_idle<S: State>(
+    state: S,
+    session: &Session,
+    idle: bool,
+) -> Result<(), RequestError> {
+    let mut conn = state.get_conn().await.map_err(|e| {
+        error!(error = %e, "Failed to get db connection");
+        send_to_sentry(e);
+        RequestError::InternalServerError
+    })?;
+
+    agent_session::UpdateIdleQuery::new(session.id(), idle)
+        .execute(&mut conn)
+        .await
+        .map_err(|e| {
+            error!(error = %e, %session, "Failed to update agent idleness");
+            send_to_sentry(e.into());
+            RequestError::InternalServerError
+        })?;
+
+    let session_key = session.key();
+    let active_devices = agent_session::DeviceCountQuery::new(
+        session_key.classroom_id,
+        &session_key.agent_id,
+        &session_key.device_id,
+    )
+    .active_only()
+    .execute(&mut conn)
+    .await
+    .map_err(|e| {
+        error!(error = %e, %session, "Failed to count active devices of the agent");
+        send_to_sentry(e.into());
+        RequestError::InternalServerError
+    })?;
+
+    if active_devices > 0 {
+        return Ok(());
+    }
+
+    let agent_id = session_key.agent_id.clone();
+    let (event, operation) = if idle {
+        (AgentEvent::Idle { agent_id }, IDLE_OPERATION)
+    } else {
+        (AgentEvent::Active { agent_id }, ACTIVE_OPERATION)
+    };
+
+    if let Err(e) = state
+        .nats_client()
+        .publish_event(session, Event::from(event), operation.into())
+        .await
+    {
+        error!(error = %e, %session, "Failed to send agent.{} notification", operation);
+        send_to_sentry(e);
+    }
+
+    Ok(())
+}
+
+/// Returns all agents in the classroom of the session.
+pub(super) async fn get_presence_snapshot<S: State>(
+    state: S,
+    session: &Session,
+) -> Result<Vec<Agent>, RequestError> {
+    let mut conn = state.get_conn().await.map_err(|e| {
+        error!(error = %e, "Failed to get db connection");
+        send_to_sentry(e);
+        RequestError::InternalServerError
+    })?;
+
+    let mut agents = Vec::new();
+    loop {
+        let sequence_id = agents
+            .last()
+            .map(|agent: &Agent| i64::from(agent.sequence_id) as usize)
+            .unwrap_or_default();
+
+        let page = agent_session::AgentList::new(
+            session.key().classroom_id,
+            sequence_id,
+            PRESENCE_SNAPSHOT_PAGE_SIZE,
+        )
+        .execute(&mut conn)
+        .await
+        .map_err(|e| {
+            error!(error = %e, %session, "Failed to get list of agents");
+            send_to_sentry(e.into());
+            RequestError::InternalServerError
+        })?;
+
+        let is_last_page = page.len() < PRESENCE_SNAPSHOT_PAGE_SIZE;
+        agents.extend(page);
+
+        if is_last_page {
+            return Ok(agents);
+        }
+    }
+}
+
+async fn create_or_replace_agent_session<S: State>(
+    state: S,
+    session_key: &SessionKey,
+    metadata: Option<&serde_json::Value>,
+) -> Result<(SessionId, SessionKind), UnrecoverableSessionError> {
+    let mut conn = state.get_conn().await.map_err(|e| {
+        error!(error = %e, "Failed to get db connection");
+        send_to_sentry(e);
+        UnrecoverableSessionError::InternalServerError
+    })?;
+
+    let classroom_id = session_key.classroom_id;
+    let agent_id = &session_key.agent_id;
+
+    // Attempt to close old session of the same device on the same replica
+    // If the session is found, don't create a new session and return the previous id
+    match state.terminate_session(session_key.clone()).await {
+        Ok(TerminateSession::Found(session_id)) => {
+            // The new connection may come with another metadata
+            agent_session::UpdateQuery::new(session_id, state.replica_id())
+                .metadata(metadata)
+                .execute(&mut conn)
+                .await
+                .map_err(|e| {
+                    error!(error = %e, ?session_id, "Failed to update metadata of agent session");
+                    send_to_sentry(e.into());
+                    UnrecoverableSessionError::InternalServerError
+                })?;
+
+            return Ok((session_id, SessionKind::Replaced));
+        }
+        Err(e) => {
+            error!(error = %e, "Failed to terminate session: {}", session_key);
+            send_to_sentry(e);
+            return Err(UnrecoverableSessionError::InternalServerError);
+        }
+        _ => {}
+    }
+
+    let audience = agent_id.as_account_id().audience();
+
+    // The agent may be in the classroom on other devices, up to the limit
+    let mut session_kind = SessionKind::New;
+    if let Some(limit) = state.config().devices.limit(audience) {
+        let count =
+            agent_session::DeviceCountQuery::new(classroom_id, agent_id, &session_key.device_id)
+                .execute(&mut conn)
+                .await
+                .map_err(|e| {
+                    error!(error = %e, %session_key, "Failed to count devices of the agent");
+                    send_to_sentry(e.into());
+                    UnrecoverableSessionError::InternalServerError
+                })?;
+
+        if count >= limit as i64 {
+            warn!(%session_key, limit, "Too many devices");
+            return Err(UnrecoverableSessionError::TooManyDevices);
+        }
+
+        if count > 0 {
+            session_kind = SessionKind::Concurrent;
+        }
+    }
+
+    let result = insert_agent_session(&state, &mut conn, session_key, metadata).await?;
+
+    match result {
+        InsertResult::Ok(agent_session) => Ok((agent_session.id, session_kind)),
+        InsertResult::Error(e) => {
+            error!(error = %e, "Failed to create an agent session");
             send_to_sentry(e.into());
             Err(UnrecoverableSessionError::InternalServerError)
         }
@@ -467,19 +1491,20 @@ async fn create_or_replace_agent_session<S: State>(
             // Attempt to close old session on another replica
             use app::api::v1::session::Response as DeleteResponse;
 
-            let replica_ip = db::replica::GetIpQuery::new(session_key.clone())
+            let replica = db::replica::GetBySessionQuery::new(session_key.clone())
                 .execute(&mut conn)
                 .await
                 .map_err(|e| {
-                    error!(error = %e, %session_key, "Failed to get replica ip");
+                    error!(error = %e, %session_key, "Failed to get replica of the session");
                     send_to_sentry(e.into());
                     UnrecoverableSessionError::InternalServerError
                 })?;
 
-            match replica::close_connection(state.clone(), replica_ip.ip(), session_key).await {
+            match replica::close_connection(&state, &replica, session_key.clone()).await {
                 Ok(DeleteResponse::DeleteSuccess(session_id)) => {
                     let replica_id = state.replica_id();
                     match agent_session::UpdateQuery::new(session_id, replica_id)
+                        .metadata(metadata)
                         .execute(&mut conn)
                         .await
                     {
@@ -509,47 +1534,155 @@ async fn create_or_replace_agent_session<S: State>(
     }
 }
 
+/// Inserts the session unless the classroom is full.
+///
+/// The check and the insertion are serialized by the lock of the classroom,
+/// so concurrent connects on different replicas can't exceed the limit.
+/// The classroom is occupied in the same transaction, `classroom.occupied` is published after it.
+async fn insert_agent_session<S: State>(
+    state: &S,
+    conn: &mut PgConnection,
+    session_key: &SessionKey,
+    metadata: Option<&serde_json::Value>,
+) -> Result<InsertResult, UnrecoverableSessionError> {
+    let classroom_id = session_key.classroom_id;
+    let agent_id = &session_key.agent_id;
+
+    let mut tx = conn.begin().await.map_err(|e| {
+        error!(error = %e, "Failed to begin transaction");
+        send_to_sentry(e.into());
+        UnrecoverableSessionError::InternalServerError
+    })?;
+
+    let capacity = &state.config().capacity;
+    let audience = agent_id.as_account_id().audience();
+    let limits = [
+        (capacity.limit(&classroom_id), None),
+        (capacity.audience_limit(audience), Some(audience)),
+    ];
+
+    if limits.iter().any(|(limit, _)| limit.is_some()) {
+        classroom_occupancy::LockQuery::new(classroom_id)
+            .execute(&mut tx)
+            .await
+            .map_err(|e| {
+                error!(error = %e, %classroom_id, "Failed to lock the classroom");
+                send_to_sentry(e.into());
+                UnrecoverableSessionError::InternalServerError
+            })?;
+    }
+
+    for (limit, audience) in limits {
+        let limit = match limit {
+            Some(limit) => limit,
+            None => continue,
+        };
+
+        let mut query = agent_session::CountQuery::new(classroom_id, agent_id);
+        if let Some(audience) = audience {
+            query = query.audience(audience);
+        }
+
+        let count = query.execute(&mut tx).await.map_err(|e| {
+            error!(error = %e, %classroom_id, "Failed to count agents in the classroom");
+            send_to_sentry(e.into());
+            UnrecoverableSessionError::InternalServerError
+        })?;
+
+        if count >= limit as i64 {
+            warn!(%classroom_id, %agent_id, limit, ?audience, "Classroom is full");
+            return Err(UnrecoverableSessionError::ClassroomFull);
+        }
+    }
+
+    let result = agent_session::InsertQuery::new(
+        agent_id,
+        classroom_id,
+        state.replica_id(),
+        OffsetDateTime::now_utc(),
+    )
+    .device(&session_key.device_id)
+    .metadata(metadata)
+    .execute(&mut tx)
+    .await;
+
+    // Otherwise the transaction is rolled back, so the connection can be used further
+    if let InsertResult::Ok(_) = result {
+        let change = occupancy::update(&mut tx, classroom_id, agent_id)
+            .await
+            .map_err(|e| {
+                error!(error = %e, %classroom_id, "Failed to update classroom occupancy");
+                send_to_sentry(e);
+                UnrecoverableSessionError::InternalServerError
+            })?;
+
+        tx.commit().await.map_err(|e| {
+            error!(error = %e, "Failed to commit transaction");
+            send_to_sentry(e.into());
+            UnrecoverableSessionError::InternalServerError
+        })?;
+
+        publish_occupancy(state, change).await;
+    }
+
+    Ok(result)
+}
+
 async fn authorize_agent<S: State>(
     state: S,
     agent_id: &AgentId,
     classroom_id: &ClassroomId,
+    action: &str,
 ) -> Result<(), UnrecoverableSessionError> {
     let account_id = agent_id.as_account_id();
-    let object = AuthzObject::new(&["classrooms", &classroom_id.to_string()]).into();
-
-    let audience = state
-        .lookup_known_authz_audience(account_id.audience())
-        .unwrap_or(account_id.audience())
-        .to_owned();
 
-    if let Err(err) = state
-        .authz()
-        .authorize(audience, account_id.clone(), object, "connect".into())
-        .await
-        .measure()
-    {
+    if let Err(err) = access::authorize(&state, *classroom_id, account_id, action).await {
         error!(error = %err, "Failed to authorize action");
         return Err(UnrecoverableSessionError::AccessDenied);
     };
 
+    // Kicked agents may be banned from connecting to the classroom for a while
+    if action == CONNECT_ACTION {
+        let mut conn = state.get_conn().await.map_err(|e| {
+            error!(error = %e, "Failed to get db connection");
+            send_to_sentry(e);
+            UnrecoverableSessionError::InternalServerError
+        })?;
+
+        let ban = db::classroom_ban::FindQuery::new(*classroom_id, account_id)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| {
+                error!(error = %e, %classroom_id, "Failed to find classroom ban");
+                send_to_sentry(e.into());
+                UnrecoverableSessionError::InternalServerError
+            })?;
+
+        if let Some(ban) = ban {
+            warn!(%classroom_id, %agent_id, expires_at = %ban.expires_at, "Agent is banned");
+            return Err(UnrecoverableSessionError::AccessDenied);
+        }
+    }
+
     Ok(())
 }
 
-fn get_agent_id_from_token(
+/// Returns the agent of the token and the expiration time of the token in unix seconds.
+fn decode_token(
     token: String,
     authn: Arc<ConfigMap>,
     agent_label: String,
-) -> Result<AgentId> {
+) -> Result<(AgentId, Option<u64>)> {
     let data = decode_jws_compact_with_config::<String>(&token, authn.as_ref())?;
     let claims = data.claims;
 
     let account = AccountId::new(claims.subject(), claims.audience());
     let agent_id = AgentId::new(agent_label, account);
 
-    Ok(agent_id)
+    Ok((agent_id, claims.expiration_time()))
 }
 
-fn serialize_to_json<T: Serialize>(response: &T) -> String {
+pub(super) fn serialize_to_json<T: Serialize>(response: &T) -> String {
     serde_json::to_string(&response).unwrap_or_default()
 }
 
@@ -558,12 +1691,55 @@ mod tests {
     use super::*;
     use crate::test_helpers::prelude::*;
     use serde_json::json;
+    use std::net::{IpAddr, Ipv4Addr};
     use uuid::Uuid;
 
+    async fn create_session(db_pool: &TestDb, agent: &TestAgent) -> (Session, Uuid) {
+        let mut conn = db_pool.get_conn().await;
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let replica_id = db::replica::InsertQuery::new(
+            "presence-1".into(),
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+        )
+        .expect("Failed to create insert query for replica")
+        .execute(&mut conn)
+        .await
+        .expect("Failed to insert a replica")
+        .id;
+
+        let agent_session = agent_session::InsertQuery::new(
+            agent.agent_id(),
+            classroom_id,
+            replica_id,
+            OffsetDateTime::now_utc(),
+        )
+        .execute(&mut conn)
+        .await
+        .expect("Failed to insert an agent session");
+
+        let session_key = SessionKey::new(agent.agent_id().to_owned(), classroom_id);
+        let session = Session::new(agent_session.id, session_key, SessionKind::New);
+
+        (session, replica_id)
+    }
+
+    fn subscribe(session: Session) -> Subscriptions {
+        let mut subscriptions = Subscriptions::default();
+        let (_, nats_rx) = tokio::sync::mpsc::channel(1);
+        let (_, signal_rx) = tokio::sync::mpsc::channel(1);
+        let (_, close_rx) = tokio::sync::mpsc::channel(1);
+        subscriptions.insert(session, ReceiverStream::new(nats_rx), signal_rx, close_rx);
+
+        subscriptions
+    }
+
+    fn json_request(cmd: &serde_json::Value) -> Result<Request> {
+        decode_request(Message::Text(cmd.to_string()), Encoding::Json)
+    }
+
     mod handle_authn_message {
         use super::*;
-        use crate::db;
-        use std::net::{IpAddr, Ipv4Addr};
 
         #[tokio::test]
         async fn unsupported_request() {
@@ -654,12 +1830,140 @@ mod tests {
         }
 
         #[tokio::test]
-        async fn success() {
+        async fn classroom_full() {
             let test_container = TestContainer::new();
             let postgres = test_container.run_postgres();
             let db_pool = TestDb::new(&postgres.connection_string).await;
-            let classroom_id: ClassroomId = Uuid::new_v4().into();
-            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let agent_1 = TestAgent::new("http", "user1", USR_AUDIENCE);
+            let agent_2 = TestAgent::new("http", "user2", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent_1).await;
+            let classroom_id = session.key().classroom_id;
+
+            let cmd = json!({
+                "type": "connect_request",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "token": agent_2.token(),
+                    "agent_label": "http"
+                }
+            });
+
+            let msg = Message::Text(cmd.to_string());
+            let authn = Arc::new(authn::new());
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent_2.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let mut state = TestState::new(db_pool, authz, replica_id);
+            state
+                .config_mut()
+                .capacity
+                .audiences
+                .insert(USR_AUDIENCE.to_string(), 1);
+
+            let result = handle_authn_message(msg, authn, state)
+                .await
+                .expect_err("Unexpectedly succeeded");
+
+            assert_eq!(result, UnrecoverableSessionError::ClassroomFull);
+        }
+
+        #[tokio::test]
+        async fn audience_capacity() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let other = TestAgent::new("http", "user1", "other.example.org");
+            let agent = TestAgent::new("http", "user2", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &other).await;
+            let classroom_id = session.key().classroom_id;
+
+            let cmd = json!({
+                "type": "connect_request",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "token": agent.token(),
+                    "agent_label": "http"
+                }
+            });
+
+            let msg = Message::Text(cmd.to_string());
+            let authn = Arc::new(authn::new());
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let mut state = TestState::new(db_pool, authz, replica_id);
+            // Agents of other audiences don't count against the limit of the audience
+            state
+                .config_mut()
+                .capacity
+                .audiences
+                .insert(USR_AUDIENCE.to_string(), 1);
+
+            handle_authn_message(msg, authn, state)
+                .await
+                .expect("Failed to handle authentication message");
+        }
+
+        #[tokio::test]
+        async fn banned() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user1", USR_AUDIENCE);
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+            {
+                let mut conn = db_pool.get_conn().await;
+                let expires_at = OffsetDateTime::now_utc() + std::time::Duration::from_secs(600);
+
+                db::classroom_ban::InsertQuery::new(classroom_id, agent.account_id(), expires_at)
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to ban agent");
+            }
+
+            let cmd = json!({
+                "type": "connect_request",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "token": agent.token(),
+                    "agent_label": "http"
+                }
+            });
+
+            let msg = Message::Text(cmd.to_string());
+            let authn = Arc::new(authn::new());
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let state = TestState::new(db_pool, authz, Uuid::new_v4());
+
+            let result = handle_authn_message(msg, authn, state)
+                .await
+                .expect_err("Unexpectedly succeeded");
+
+            assert_eq!(result, UnrecoverableSessionError::AccessDenied);
+        }
+
+        #[tokio::test]
+        async fn success() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
             let token = agent.token();
 
             let replica_id = {
@@ -696,7 +2000,7 @@ mod tests {
             );
             let state = TestState::new(db_pool, authz, replica_id);
 
-            let session = handle_authn_message(msg, authn, state)
+            let (session, options) = handle_authn_message(msg, authn, state)
                 .await
                 .expect("Failed to handle authentication message");
 
@@ -704,6 +2008,884 @@ mod tests {
                 session.key().clone(),
                 SessionKey::new(agent.agent_id().to_owned(), classroom_id)
             );
+            assert_eq!(options.encoding, Encoding::Json);
+        }
+
+        async fn connect_device(
+            db_pool: TestDb,
+            agent: &TestAgent,
+            device_limit: usize,
+        ) -> Result<(Session, ConnectOptions), UnrecoverableSessionError> {
+            let (session, replica_id) = create_session(&db_pool, agent).await;
+            let classroom_id = session.key().classroom_id;
+
+            {
+                let mut conn = db_pool.get_conn().await;
+
+                agent_session::InsertQuery::new(
+                    agent.agent_id(),
+                    classroom_id,
+                    replica_id,
+                    OffsetDateTime::now_utc(),
+                )
+                .device("phone")
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session");
+            }
+
+            let cmd = json!({
+                "type": "connect_request",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "token": agent.token(),
+                    "agent_label": "http",
+                    "device_id": "laptop"
+                }
+            });
+
+            let msg = Message::Text(cmd.to_string());
+            let authn = Arc::new(authn::new());
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let mut state = TestState::new(db_pool, authz, replica_id);
+            state
+                .config_mut()
+                .devices
+                .audiences
+                .insert(USR_AUDIENCE.to_string(), device_limit);
+
+            handle_authn_message(msg, authn, state).await
+        }
+
+        #[tokio::test]
+        async fn too_many_devices() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user1", USR_AUDIENCE);
+
+            let result = connect_device(db_pool, &agent, 2)
+                .await
+                .expect_err("Unexpectedly succeeded");
+
+            assert_eq!(result, UnrecoverableSessionError::TooManyDevices);
+        }
+
+        #[tokio::test]
+        async fn concurrent_device() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user1", USR_AUDIENCE);
+
+            let (session, _) = connect_device(db_pool.clone(), &agent, 3)
+                .await
+                .expect("Failed to handle authentication message");
+
+            assert_eq!(session.kind(), SessionKind::Concurrent);
+            assert_eq!(session.key().device_id, "laptop");
+
+            // The agent is listed once in the classroom
+            let mut conn = db_pool.get_conn().await;
+            let agents = agent_session::AgentList::new(session.key().classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert_eq!(agents.len(), 1);
+        }
+
+        #[tokio::test]
+        async fn replace_idle_session() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user1", USR_AUDIENCE);
+
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+
+            {
+                let mut conn = db_pool.get_conn().await;
+
+                agent_session::UpdateIdleQuery::new(session.id(), true)
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to mark agent idle");
+            }
+
+            let cmd = json!({
+                "type": "connect_request",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "token": agent.token(),
+                    "agent_label": "http"
+                }
+            });
+
+            let msg = Message::Text(cmd.to_string());
+            let authn = Arc::new(authn::new());
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let mut state = TestState::new(db_pool.clone(), authz, replica_id);
+            state.set_local_session(session.id());
+
+            let (new_session, _) = handle_authn_message(msg, authn, state)
+                .await
+                .expect("Failed to handle authentication message");
+
+            assert_eq!(new_session.kind(), SessionKind::Replaced);
+            assert_eq!(new_session.id(), session.id());
+
+            // The new connection is active
+            let mut conn = db_pool.get_conn().await;
+            let agents = agent_session::AgentList::new(classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert_eq!(agents.len(), 1);
+            assert!(!agents[0].idle);
+        }
+
+        async fn connect_with_metadata(
+            db_pool: TestDb,
+            agent: &TestAgent,
+            metadata: serde_json::Value,
+        ) -> Result<(Session, ConnectOptions), UnrecoverableSessionError> {
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+            let replica_id = {
+                let mut conn = db_pool.get_conn().await;
+
+                db::replica::InsertQuery::new(
+                    "presence-1".into(),
+                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                )
+                .expect("Failed to create insert query for replica")
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert a replica")
+                .id
+            };
+
+            let cmd = json!({
+                "type": "connect_request",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "token": agent.token(),
+                    "agent_label": "http",
+                    "metadata": metadata
+                }
+            });
+
+            let msg = Message::Text(cmd.to_string());
+            let authn = Arc::new(authn::new());
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let state = TestState::new(db_pool, authz, replica_id);
+
+            handle_authn_message(msg, authn, state).await
+        }
+
+        #[tokio::test]
+        async fn metadata() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user1", USR_AUDIENCE);
+            let metadata = json!({ "display_name": "John", "role": "student" });
+
+            let (session, options) =
+                connect_with_metadata(db_pool.clone(), &agent, metadata.clone())
+                    .await
+                    .expect("Failed to handle authentication message");
+
+            assert_eq!(options.metadata.as_ref(), Some(&metadata));
+
+            let mut conn = db_pool.get_conn().await;
+            let agents = agent_session::AgentList::new(session.key().classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert_eq!(agents.len(), 1);
+            assert_eq!(agents[0].metadata.as_ref(), Some(&metadata));
+        }
+
+        #[tokio::test]
+        async fn invalid_metadata() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user1", USR_AUDIENCE);
+
+            let result = connect_with_metadata(db_pool.clone(), &agent, json!("John"))
+                .await
+                .expect_err("Unexpectedly succeeded");
+
+            assert_eq!(result, UnrecoverableSessionError::SerializationFailed);
+
+            let metadata = json!({ "display_name": "a".repeat(1024) });
+            let result = connect_with_metadata(db_pool, &agent, metadata)
+                .await
+                .expect_err("Unexpectedly succeeded");
+
+            assert_eq!(result, UnrecoverableSessionError::SerializationFailed);
+        }
+    }
+
+    mod handle_request {
+        use super::*;
+        use crate::db::agent_session::AgentList;
+
+        #[tokio::test]
+        async fn set_status() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool.clone(), TestAuthz::new(), replica_id);
+
+            let cmd = json!({
+                "type": "set_status",
+                "payload": {
+                    "status": "away",
+                    "status_text": "brb"
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp, json!({ "type": "set_status_success" }));
+
+            let mut conn = db_pool.get_conn().await;
+            let agents = AgentList::new(classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert_eq!(agents.len(), 1);
+            assert_eq!(agents[0].status, AgentStatus::Away);
+            assert_eq!(agents[0].status_text.as_deref(), Some("brb"));
+        }
+
+        #[tokio::test]
+        async fn activity_after_idle() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool.clone(), TestAuthz::new(), replica_id);
+
+            subscriptions.activity.idle = true;
+            set_idle(state.clone(), &subscriptions, true)
+                .await
+                .expect("Failed to mark agent idle");
+
+            {
+                let mut conn = db_pool.get_conn().await;
+                let agents = AgentList::new(classroom_id, 0, 10)
+                    .execute(&mut conn)
+                    .await
+                    .expect("Failed to get list of agents");
+
+                assert_eq!(agents.len(), 1);
+                assert!(agents[0].idle);
+            }
+
+            let cmd = json!({ "type": "activity" });
+
+            // Activity isn't confirmed
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            assert!(resp.is_none());
+            assert!(!subscriptions.activity.idle);
+
+            let mut conn = db_pool.get_conn().await;
+            let agents = AgentList::new(classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert_eq!(agents.len(), 1);
+            assert!(!agents[0].idle);
+        }
+
+        async fn refresh(agent: &TestAgent, token: String) -> (serde_json::Value, Subscriptions) {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let (session, replica_id) = create_session(&db_pool, agent).await;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+
+            let cmd = json!({
+                "type": "refresh_token",
+                "payload": { "token": token }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+
+            (resp, subscriptions)
+        }
+
+        #[tokio::test]
+        async fn refresh_token() {
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+
+            let (resp, subscriptions) = refresh(&agent, agent.token()).await;
+            assert_eq!(resp["type"], "refresh_token_success");
+            assert!(subscriptions.token.expires_at.is_some());
+            assert!(!subscriptions.token.notified);
+        }
+
+        #[tokio::test]
+        async fn refresh_token_of_another_agent() {
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let other = TestAgent::new("http", "user456", USR_AUDIENCE);
+
+            let (resp, subscriptions) = refresh(&agent, other.token()).await;
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "access_denied");
+            assert!(subscriptions.token.expires_at.is_none());
+        }
+
+        #[tokio::test]
+        async fn refresh_invalid_token() {
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+
+            let (resp, _) = refresh(&agent, "1234".to_owned()).await;
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "unauthenticated");
+        }
+
+        #[tokio::test]
+        async fn status_text_too_long() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+
+            let cmd = json!({
+                "type": "set_status",
+                "payload": {
+                    "status": "busy",
+                    "status_text": "a".repeat(256)
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "invalid_payload");
+        }
+
+        #[tokio::test]
+        async fn set_status_in_not_joined_classroom() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+            let cmd = json!({
+                "type": "set_status",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "status": "away"
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "classroom_not_joined");
+        }
+
+        #[tokio::test]
+        async fn publish() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "publish",
+            );
+            let state = TestState::new(db_pool, authz, replica_id);
+
+            let cmd = json!({
+                "type": "publish",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "data": { "type": "hand_raised" }
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp, json!({ "type": "publish_success" }));
+        }
+
+        #[tokio::test]
+        async fn publish_unauthorized() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+
+            let cmd = json!({
+                "type": "publish",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "data": { "type": "hand_raised" }
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "access_denied");
+        }
+
+        #[tokio::test]
+        async fn publish_too_large_data() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+
+            let cmd = json!({
+                "type": "publish",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "data": "a".repeat(4096)
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "serialization_failed");
+        }
+
+        #[tokio::test]
+        async fn signal() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            // Two sockets of the same agent
+            let another = Session::new(session.id(), session.key().clone(), SessionKind::New);
+            let mut subscriptions1 = subscribe(session);
+            let mut subscriptions2 = subscribe(another);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+            let rate = state.config().websocket.signal_rate_limit;
+
+            let cmd = json!({
+                "type": "signal",
+                "payload": {
+                    "classroom_id": classroom_id,
+                    "data": { "type": "typing" }
+                }
+            });
+
+            for _ in 0..rate {
+                let resp = handle_request(
+                    state.clone(),
+                    Arc::new(authn::new()),
+                    agent.agent_id(),
+                    &mut subscriptions1,
+                    json_request(&cmd),
+                )
+                .await;
+                assert!(resp.is_none());
+            }
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions2,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "rate_limited");
+        }
+
+        #[tokio::test]
+        async fn join_classroom() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let mut subscriptions = subscribe(session);
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+            let mut authz = TestAuthz::new();
+            authz.allow(
+                agent.account_id(),
+                vec!["classrooms", &classroom_id.to_string()],
+                "connect",
+            );
+            let state = TestState::new(db_pool.clone(), authz, replica_id);
+
+            let cmd = json!({
+                "type": "join_classroom",
+                "payload": {
+                    "classroom_id": classroom_id
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(
+                resp,
+                json!({
+                    "type": "join_classroom_success",
+                    "payload": { "classroom_id": classroom_id }
+                })
+            );
+            assert_eq!(subscriptions.sessions().count(), 2);
+
+            let mut conn = db_pool.get_conn().await;
+            let agents = AgentList::new(classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert_eq!(agents.len(), 1);
+            assert_eq!(agents[0].agent_id, agent.agent_id().to_owned());
+        }
+
+        #[tokio::test]
+        async fn join_classroom_unauthorized() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+            let cmd = json!({
+                "type": "join_classroom",
+                "payload": {
+                    "classroom_id": classroom_id
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "access_denied");
+            assert_eq!(subscriptions.sessions().count(), 1);
+        }
+
+        #[tokio::test]
+        async fn leave_classroom() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool.clone(), TestAuthz::new(), replica_id);
+
+            let cmd = json!({
+                "type": "leave_classroom",
+                "payload": {
+                    "classroom_id": classroom_id
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(
+                resp,
+                json!({
+                    "type": "leave_classroom_success",
+                    "payload": { "classroom_id": classroom_id }
+                })
+            );
+            assert!(subscriptions.is_empty());
+
+            let mut conn = db_pool.get_conn().await;
+            let agents = AgentList::new(classroom_id, 0, 10)
+                .execute(&mut conn)
+                .await
+                .expect("Failed to get list of agents");
+
+            assert!(agents.is_empty());
+        }
+
+        #[tokio::test]
+        async fn leave_not_joined_classroom() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent).await;
+            let mut subscriptions = subscribe(session);
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+            let cmd = json!({
+                "type": "leave_classroom",
+                "payload": {
+                    "classroom_id": classroom_id
+                }
+            });
+
+            let resp = handle_request(
+                state,
+                Arc::new(authn::new()),
+                agent.agent_id(),
+                &mut subscriptions,
+                json_request(&cmd),
+            )
+            .await;
+            let resp = serde_json::to_value(resp).expect("Failed to serialize response");
+            assert_eq!(resp["type"], "request_error");
+            assert_eq!(resp["payload"]["type"], "classroom_not_joined");
+        }
+    }
+
+    mod decode_request {
+        use super::*;
+
+        #[test]
+        fn binary_request() {
+            let classroom_id: ClassroomId = Uuid::new_v4().into();
+            let cmd = json!({
+                "type": "leave_classroom",
+                "payload": { "classroom_id": classroom_id }
+            });
+            let msg = Encoding::Msgpack
+                .encode(&cmd)
+                .expect("Failed to encode request");
+
+            let request = decode_request(msg, Encoding::Msgpack).expect("Failed to decode request");
+            assert!(matches!(
+                request,
+                Request::LeaveClassroom(ClassroomRequest { classroom_id: id }) if id == classroom_id
+            ));
+
+            // JSON is always accepted in text frames
+            let msg = Message::Text(cmd.to_string());
+            decode_request(msg, Encoding::Msgpack).expect("Failed to decode request");
+        }
+
+        #[test]
+        fn binary_request_without_negotiation() {
+            let msg = Message::Binary(b"{}".to_vec());
+
+            assert!(decode_request(msg, Encoding::Json).is_err());
+        }
+    }
+
+    mod subscriptions {
+        use super::*;
+
+        #[test]
+        fn token_deadline() {
+            let window = Duration::from_secs(60);
+            let exp = OffsetDateTime::now_utc().unix_timestamp() as u64 + 600;
+            let mut token = TokenExpiry::new(Some(exp));
+
+            let expires_at = token.expires_at.expect("Token must expire");
+            assert_eq!(token.deadline(window), Some(expires_at - window));
+
+            assert!(!token.notify());
+            assert_eq!(token.deadline(window), Some(expires_at));
+            assert!(token.notify());
+
+            assert_eq!(TokenExpiry::new(None).deadline(window), None);
+        }
+
+        #[tokio::test]
+        async fn skip_replayed_events() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent = TestAgent::new("http", "user123", USR_AUDIENCE);
+            let (session, _) = create_session(&db_pool, &agent).await;
+            let classroom_id = session.key().classroom_id;
+            let mut subscriptions = subscribe(session);
+            subscriptions.replayed.insert(classroom_id, 10);
+
+            assert!(!subscriptions.is_replayed(classroom_id, None));
+            assert!(subscriptions.is_replayed(classroom_id, Some(9)));
+            assert!(subscriptions.is_replayed(classroom_id, Some(10)));
+            assert!(!subscriptions.is_replayed(classroom_id, Some(11)));
+            // Live events have caught up, nothing is skipped anymore
+            assert!(!subscriptions.is_replayed(classroom_id, Some(5)));
+        }
+    }
+
+    mod get_presence_snapshot {
+        use super::*;
+
+        #[tokio::test]
+        async fn all_agents() {
+            let test_container = TestContainer::new();
+            let postgres = test_container.run_postgres();
+            let db_pool = TestDb::new(&postgres.connection_string).await;
+            let agent_1 = TestAgent::new("http", "user1", USR_AUDIENCE);
+            let agent_2 = TestAgent::new("http", "user2", USR_AUDIENCE);
+            let (session, replica_id) = create_session(&db_pool, &agent_1).await;
+
+            {
+                let mut conn = db_pool.get_conn().await;
+
+                agent_session::InsertQuery::new(
+                    agent_2.agent_id(),
+                    session.key().classroom_id,
+                    replica_id,
+                    OffsetDateTime::now_utc(),
+                )
+                .execute(&mut conn)
+                .await
+                .expect("Failed to insert an agent session");
+            }
+
+            let state = TestState::new(db_pool, TestAuthz::new(), replica_id);
+            let agents = get_presence_snapshot(state, &session)
+                .await
+                .expect("Failed to get presence snapshot");
+
+            let agent_ids = agents.into_iter().map(|a| a.agent_id).collect::<Vec<_>>();
+            assert_eq!(
+                agent_ids,
+                vec![agent_1.agent_id().to_owned(), agent_2.agent_id().to_owned()]
+            );
         }
     }
 }
@@ -1,18 +1,40 @@
-use crate::classroom::ClassroomId;
+use crate::{classroom::ClassroomId, db::agent_session::Agent, session::AgentStatus};
 use http::StatusCode;
 use serde::{de::Error, Deserialize, Deserializer};
 use serde_derive::Serialize;
 use std::sync::Arc;
+use svc_agent::AgentId;
 use svc_error::{extension::sentry, Error as SvcError};
 
+pub use encoding::Encoding;
 pub use handler::handler;
+pub use sse::handler as sse_handler;
 
+mod encoding;
 mod handler;
+mod restriction;
+mod sse;
+
+const MAX_STATUS_TEXT_LENGTH: usize = 255;
+const MAX_DEVICE_ID_LENGTH: usize = 64;
+/// Metadata is sent in every `agent.entered` and presence snapshot, so it must stay small.
+const MAX_METADATA_SIZE: usize = 1024;
+/// Messages are meant for lightweight signals, not for data transfer.
+const MAX_MESSAGE_DATA_SIZE: usize = 4096;
 
 #[derive(Deserialize)]
 #[serde(tag = "type", content = "payload", rename_all = "snake_case")]
 pub enum Request {
     ConnectRequest(ConnectRequest),
+    SetStatus(SetStatusRequest),
+    JoinClassroom(ClassroomRequest),
+    LeaveClassroom(ClassroomRequest),
+    Publish(PublishRequest),
+    Signal(PublishRequest),
+    /// Sent by the client periodically while a human is using it.
+    Activity,
+    /// Replaces the token of the connection before it expires.
+    RefreshToken(RefreshTokenRequest),
 }
 
 #[derive(Deserialize)]
@@ -21,6 +43,31 @@ pub struct ConnectRequest {
     token: String,
     #[serde(deserialize_with = "deserialize_agent_label")]
     agent_label: String,
+    #[serde(default)]
+    presence_snapshot: bool,
+    /// The last stream sequence received by the agent before reconnecting.
+    #[serde(default)]
+    resume_from: Option<u64>,
+    /// Distinguishes concurrent sessions of the agent if its audience allows several devices.
+    #[serde(default, deserialize_with = "deserialize_device_id")]
+    device_id: Option<String>,
+    /// Shown to other agents along with the agent (e.g. display name, role, client version).
+    #[serde(default, deserialize_with = "deserialize_metadata")]
+    metadata: Option<serde_json::Value>,
+    /// Encoding of frames sent after `connect_request`.
+    #[serde(default)]
+    encoding: Encoding,
+}
+
+/// Options of the connection requested in `connect_request`.
+#[derive(Debug, Default)]
+struct ConnectOptions {
+    presence_snapshot: bool,
+    resume_from: Option<u64>,
+    metadata: Option<serde_json::Value>,
+    /// Expiration time of the token in unix seconds, if the token has one.
+    token_expires_at: Option<u64>,
+    encoding: Encoding,
 }
 
 fn deserialize_agent_label<'de, D>(de: D) -> Result<String, D::Error>
@@ -35,12 +82,121 @@ where
     Ok(s)
 }
 
+fn deserialize_device_id<'de, D>(de: D) -> Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = Option::<String>::deserialize(de)?;
+    if let Some(s) = &s {
+        if s.is_empty() {
+            return Err(D::Error::custom("device_id is empty"));
+        }
+
+        if s.chars().count() > MAX_DEVICE_ID_LENGTH {
+            return Err(D::Error::custom("device_id is too long"));
+        }
+    }
+
+    Ok(s)
+}
+
+fn deserialize_metadata<'de, D>(de: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let metadata = Option::<serde_json::Value>::deserialize(de)?;
+    if let Some(metadata) = &metadata {
+        if !metadata.is_object() {
+            return Err(D::Error::custom("metadata is not an object"));
+        }
+
+        let size = serde_json::to_vec(metadata)
+            .map_err(D::Error::custom)?
+            .len();
+        if size > MAX_METADATA_SIZE {
+            return Err(D::Error::custom("metadata is too large"));
+        }
+    }
+
+    Ok(metadata)
+}
+
+#[derive(Deserialize)]
+pub struct SetStatusRequest {
+    /// If not set, the status is set in all joined classrooms.
+    #[serde(default)]
+    classroom_id: Option<ClassroomId>,
+    status: AgentStatus,
+    /// Up to `MAX_STATUS_TEXT_LENGTH` characters, it's checked on handling the request.
+    #[serde(default)]
+    status_text: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshTokenRequest {
+    token: String,
+}
+
+#[derive(Deserialize)]
+pub struct ClassroomRequest {
+    classroom_id: ClassroomId,
+}
+
+#[derive(Deserialize)]
+pub struct PublishRequest {
+    classroom_id: ClassroomId,
+    /// If set, the message is sent only to this agent.
+    #[serde(default)]
+    receiver_id: Option<AgentId>,
+    #[serde(deserialize_with = "deserialize_message_data")]
+    data: serde_json::Value,
+}
+
+fn deserialize_message_data<'de, D>(de: D) -> Result<serde_json::Value, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let data = serde_json::Value::deserialize(de)?;
+    let size = serde_json::to_vec(&data).map_err(D::Error::custom)?.len();
+    if size > MAX_MESSAGE_DATA_SIZE {
+        return Err(D::Error::custom("data is too large"));
+    }
+
+    Ok(data)
+}
+
 #[derive(Serialize)]
 #[serde(tag = "type", content = "payload", rename_all = "snake_case")]
 pub enum Response {
     ConnectSuccess,
+    PresenceSnapshot(Vec<Agent>),
+    SetStatusSuccess,
+    JoinClassroomSuccess {
+        classroom_id: ClassroomId,
+    },
+    LeaveClassroomSuccess {
+        classroom_id: ClassroomId,
+    },
+    PublishSuccess,
+    RefreshTokenSuccess,
+    /// The session in the classroom is replaced by another connection,
+    /// but the connection stays open for other joined classrooms.
+    ClassroomReplaced {
+        classroom_id: ClassroomId,
+    },
+    /// The agent is kicked from the classroom by a moderator,
+    /// but the connection stays open for other joined classrooms.
+    ClassroomKicked {
+        classroom_id: ClassroomId,
+    },
+    /// Access of the agent to the classroom is revoked,
+    /// but the connection stays open for other joined classrooms.
+    ClassroomAccessDenied {
+        classroom_id: ClassroomId,
+    },
     UnrecoverableSessionError(SvcError),
     RecoverableSessionError(SvcError),
+    RequestError(SvcError),
 }
 
 #[derive(Debug, PartialEq)]
@@ -53,10 +209,31 @@ enum UnrecoverableSessionError {
     AuthTimedOut,
     PongTimedOut,
     Replaced,
+    ClassroomFull,
+    TooManyDevices,
+    Kicked,
 }
 
 enum RecoverableSessionError {
     Terminated,
+    /// The token expires soon, the connection is closed if it isn't refreshed.
+    TokenExpiring,
+}
+
+/// Errors of requests sent after the session is established.
+/// They don't close the connection.
+#[derive(Debug, PartialEq)]
+enum RequestError {
+    AccessDenied,
+    Unauthenticated,
+    ClassroomNotJoined,
+    ClassroomFull,
+    TooManyDevices,
+    RateLimited,
+    InvalidPayload,
+    UnsupportedRequest,
+    SerializationFailed,
+    InternalServerError,
 }
 
 impl From<UnrecoverableSessionError> for Response {
@@ -88,6 +265,15 @@ impl From<UnrecoverableSessionError> for Response {
             UnrecoverableSessionError::Replaced => builder
                 .status(StatusCode::UNPROCESSABLE_ENTITY)
                 .kind("replaced", "replaced"),
+            UnrecoverableSessionError::ClassroomFull => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("classroom_full", "Classroom full"),
+            UnrecoverableSessionError::TooManyDevices => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("too_many_devices", "Too many devices"),
+            UnrecoverableSessionError::Kicked => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("kicked", "Kicked"),
         };
 
         Response::UnrecoverableSessionError(builder.build())
@@ -102,12 +288,67 @@ impl From<RecoverableSessionError> for Response {
             RecoverableSessionError::Terminated => builder
                 .status(StatusCode::UNPROCESSABLE_ENTITY)
                 .kind("terminated", "terminated"),
+            RecoverableSessionError::TokenExpiring => builder
+                .status(StatusCode::UNAUTHORIZED)
+                .kind("token_expiring", "Token expiring"),
         };
 
         Response::RecoverableSessionError(builder.build())
     }
 }
 
+impl From<RequestError> for Response {
+    fn from(e: RequestError) -> Self {
+        let mut builder = SvcError::builder();
+
+        builder = match e {
+            RequestError::AccessDenied => builder
+                .status(StatusCode::FORBIDDEN)
+                .kind("access_denied", "Access denied"),
+            RequestError::Unauthenticated => builder
+                .status(StatusCode::UNAUTHORIZED)
+                .kind("unauthenticated", "Unauthenticated"),
+            RequestError::ClassroomNotJoined => builder
+                .status(StatusCode::NOT_FOUND)
+                .kind("classroom_not_joined", "Classroom not joined"),
+            RequestError::ClassroomFull => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("classroom_full", "Classroom full"),
+            RequestError::TooManyDevices => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("too_many_devices", "Too many devices"),
+            RequestError::RateLimited => builder
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .kind("rate_limited", "Rate limited"),
+            RequestError::InvalidPayload => builder
+                .status(StatusCode::BAD_REQUEST)
+                .kind("invalid_payload", "Invalid payload"),
+            RequestError::UnsupportedRequest => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("unsupported_request", "Unsupported request"),
+            RequestError::SerializationFailed => builder
+                .status(StatusCode::UNPROCESSABLE_ENTITY)
+                .kind("serialization_failed", "Serialization failed"),
+            RequestError::InternalServerError => builder
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .kind("internal_server_error", "Internal server error"),
+        };
+
+        Response::RequestError(builder.build())
+    }
+}
+
+impl From<UnrecoverableSessionError> for RequestError {
+    fn from(e: UnrecoverableSessionError) -> Self {
+        match e {
+            UnrecoverableSessionError::AccessDenied => RequestError::AccessDenied,
+            UnrecoverableSessionError::ClassroomFull => RequestError::ClassroomFull,
+            UnrecoverableSessionError::TooManyDevices => RequestError::TooManyDevices,
+            _ => RequestError::InternalServerError,
+        }
+    }
+}
+
 impl From<anyhow::Error> for Response {
     fn from(e: anyhow::Error) -> Self {
         let err = SvcError::builder()
@@ -0,0 +1,382 @@
+use crate::{
+    app::{access, state::State},
+    classroom::ClassroomId,
+};
+use async_nats::HeaderMap;
+use std::{collections::HashMap, time::Duration};
+use svc_agent::AgentId;
+use svc_authn::{AccountId, Authenticable};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::{error, warn};
+
+/// Only agents of the audience receive the event.
+const RECEIVER_AUDIENCE: &str = "Receiver-Audience";
+/// Only agents allowed to perform the action on the classroom receive the event.
+const RECEIVER_ACTION: &str = "Receiver-Action";
+/// Events are much more frequent than changes of access, so decisions are reused for a while.
+const DECISION_TTL: Duration = Duration::from_secs(60);
+/// Max number of events waiting for a decision, e.g. if authz is slow.
+const MAX_DEFERRED_EVENTS: usize = 100;
+
+type DecisionKey = (ClassroomId, String);
+
+/// An envelope of the event with its stream sequence, if any.
+pub type Envelope = (serde_json::Value, Option<u64>);
+
+/// Too many events wait for authz. Dropping them would leave a gap in the stream,
+/// so the connection is closed and the agent resumes it from the last received sequence.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Overflow;
+
+/// Restriction of receivers of the event set by its publisher in NATS headers,
+/// e.g. for events which only teachers may see.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Restriction {
+    audience: Option<String>,
+    action: Option<String>,
+}
+
+impl Restriction {
+    pub fn from_headers(headers: Option<&HeaderMap>) -> Self {
+        let get = |name: &str| {
+            headers
+                .and_then(|headers| headers.get(name))
+                .map(|value| value.as_str().to_owned())
+                .filter(|value| !value.is_empty())
+        };
+
+        Self {
+            audience: get(RECEIVER_AUDIENCE),
+            action: get(RECEIVER_ACTION),
+        }
+    }
+}
+
+enum Check {
+    Decided(bool),
+    /// The cached decision is used while a new one is being made.
+    Stale(bool, DecisionKey),
+    Unknown(DecisionKey),
+}
+
+/// Authz decisions of the connection, its agent is the same in all joined classrooms.
+///
+/// The connection loop doesn't wait for authz: unknown decisions are made in background
+/// and events are deferred until then, see [`AuthzDecisions::resolved`].
+pub struct AuthzDecisions {
+    decisions: HashMap<DecisionKey, (bool, Instant)>,
+    /// Events by decisions being made in background.
+    deferred: HashMap<DecisionKey, Vec<Envelope>>,
+    tx: mpsc::UnboundedSender<(DecisionKey, Option<bool>)>,
+    rx: mpsc::UnboundedReceiver<(DecisionKey, Option<bool>)>,
+}
+
+impl Default for AuthzDecisions {
+    fn default() -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        Self {
+            decisions: HashMap::new(),
+            deferred: HashMap::new(),
+            tx,
+            rx,
+        }
+    }
+}
+
+impl AuthzDecisions {
+    /// Checks whether the agent may receive the event with the restriction.
+    /// It waits for authz, so it's only used before the connection loop, e.g. for replayed events.
+    /// Events are never sent if the decision can't be made.
+    pub async fn allows<S: State>(
+        &mut self,
+        state: &S,
+        agent_id: &AgentId,
+        classroom_id: ClassroomId,
+        restriction: &Restriction,
+    ) -> bool {
+        let key = match self.check(agent_id, classroom_id, restriction) {
+            Check::Decided(allowed) => return allowed,
+            Check::Stale(_, key) | Check::Unknown(key) => key,
+        };
+
+        let allowed = decide(state, agent_id.as_account_id(), &key).await;
+        self.record(key, allowed);
+        allowed.unwrap_or(false)
+    }
+
+    /// Returns the envelope if the agent may receive the event with the restriction.
+    /// If the decision is unknown, the envelope is deferred until it's made.
+    pub fn admit<S: State>(
+        &mut self,
+        state: &S,
+        agent_id: &AgentId,
+        classroom_id: ClassroomId,
+        restriction: &Restriction,
+        envelope: Envelope,
+    ) -> Result<Option<Envelope>, Overflow> {
+        match self.check(agent_id, classroom_id, restriction) {
+            Check::Decided(allowed) => Ok(allowed.then_some(envelope)),
+            Check::Stale(allowed, key) => {
+                if !self.deferred.contains_key(&key) {
+                    self.decide_in_background(state, agent_id, key.clone());
+                    self.deferred.insert(key, Vec::new());
+                }
+
+                Ok(allowed.then_some(envelope))
+            }
+            Check::Unknown(key) => {
+                if !self.deferred.contains_key(&key) {
+                    self.decide_in_background(state, agent_id, key.clone());
+                }
+
+                let deferred = self.deferred.entry(key).or_default();
+                if deferred.len() >= MAX_DEFERRED_EVENTS {
+                    warn!(%classroom_id, %agent_id, "Too many events wait for authz");
+                    return Err(Overflow);
+                }
+
+                deferred.push(envelope);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Waits for the next decision made in background
+    /// and returns deferred envelopes which the agent may receive.
+    pub async fn resolved(&mut self) -> Vec<Envelope> {
+        // The sender is kept in `self`, so the channel is never closed
+        let (key, allowed) = match self.rx.recv().await {
+            Some(decision) => decision,
+            None => return std::future::pending().await,
+        };
+
+        let deferred = self.deferred.remove(&key).unwrap_or_default();
+        self.record(key, allowed);
+
+        match allowed {
+            Some(true) => deferred,
+            _ => Vec::new(),
+        }
+    }
+
+    fn check(
+        &self,
+        agent_id: &AgentId,
+        classroom_id: ClassroomId,
+        restriction: &Restriction,
+    ) -> Check {
+        if let Some(audience) = &restriction.audience {
+            if agent_id.as_account_id().audience() != audience {
+                return Check::Decided(false);
+            }
+        }
+
+        let action = match &restriction.action {
+            Some(action) => action,
+            None => return Check::Decided(true),
+        };
+
+        let key = (classroom_id, action.to_owned());
+        match self.decisions.get(&key) {
+            Some((allowed, decided_at)) if decided_at.elapsed() < DECISION_TTL => {
+                Check::Decided(*allowed)
+            }
+            Some((allowed, _)) => Check::Stale(*allowed, key),
+            None => Check::Unknown(key),
+        }
+    }
+
+    fn record(&mut self, key: DecisionKey, allowed: Option<bool>) {
+        match allowed {
+            Some(allowed) => {
+                self.decisions.insert(key, (allowed, Instant::now()));
+            }
+            // Not cached, so it's authorized again with the next event
+            None => {
+                self.decisions.remove(&key);
+            }
+        }
+    }
+
+    fn decide_in_background<S: State>(&self, state: &S, agent_id: &AgentId, key: DecisionKey) {
+        let state = state.clone();
+        let account_id = agent_id.as_account_id().to_owned();
+        let tx = self.tx.clone();
+
+        tokio::spawn(async move {
+            let allowed = decide(&state, &account_id, &key).await;
+            // The connection may be closed already
+            let _ = tx.send((key, allowed));
+        });
+    }
+}
+
+/// Returns `None` if the decision can't be made.
+async fn decide<S: State>(state: &S, account_id: &AccountId, key: &DecisionKey) -> Option<bool> {
+    let (classroom_id, action) = key;
+
+    match access::authorize(state, *classroom_id, account_id, action).await {
+        Ok(_) => Some(true),
+        Err(err) if matches!(err.kind(), svc_authz::ErrorKind::Forbidden(_)) => Some(false),
+        Err(err) => {
+            error!(error = %err, %classroom_id, %account_id, "Failed to authorize receiving an event");
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::prelude::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn restriction_from_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RECEIVER_AUDIENCE, USR_AUDIENCE);
+        headers.insert(RECEIVER_ACTION, "");
+
+        let restriction = Restriction::from_headers(Some(&headers));
+        assert_eq!(
+            restriction,
+            Restriction {
+                audience: Some(USR_AUDIENCE.to_owned()),
+                action: None,
+            }
+        );
+
+        assert_eq!(Restriction::from_headers(None), Restriction::default());
+    }
+
+    #[tokio::test]
+    async fn allows_by_audience_and_action() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let teacher = TestAgent::new("web", "teacher", USR_AUDIENCE);
+        let student = TestAgent::new("web", "student", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            teacher.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "moderate",
+        );
+        let state = TestState::new(db_pool, authz, Uuid::new_v4());
+
+        let restriction = Restriction {
+            audience: Some(USR_AUDIENCE.to_owned()),
+            action: Some("moderate".to_owned()),
+        };
+
+        let mut decisions = AuthzDecisions::default();
+        assert!(
+            decisions
+                .allows(&state, teacher.agent_id(), classroom_id, &restriction)
+                .await
+        );
+        assert_eq!(decisions.decisions.len(), 1);
+
+        let mut decisions = AuthzDecisions::default();
+        assert!(
+            !decisions
+                .allows(&state, student.agent_id(), classroom_id, &restriction)
+                .await
+        );
+
+        let restriction = Restriction {
+            audience: Some("other.example.org".to_owned()),
+            action: None,
+        };
+        assert!(
+            !decisions
+                .allows(&state, teacher.agent_id(), classroom_id, &restriction)
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn admit_defers_unknown_decisions() {
+        let test_container = TestContainer::new();
+        let postgres = test_container.run_postgres();
+        let db_pool = TestDb::new(&postgres.connection_string).await;
+        let teacher = TestAgent::new("web", "teacher", USR_AUDIENCE);
+        let student = TestAgent::new("web", "student", USR_AUDIENCE);
+        let classroom_id: ClassroomId = Uuid::new_v4().into();
+
+        let mut authz = TestAuthz::new();
+        authz.allow(
+            teacher.account_id(),
+            vec!["classrooms", &classroom_id.to_string()],
+            "moderate",
+        );
+        let state = TestState::new(db_pool, authz, Uuid::new_v4());
+
+        let restriction = Restriction {
+            audience: None,
+            action: Some("moderate".to_owned()),
+        };
+        let envelope = |n: u64| (serde_json::json!({ "n": n }), Some(n));
+
+        let mut decisions = AuthzDecisions::default();
+        for n in 1..=2 {
+            let admitted = decisions.admit(
+                &state,
+                teacher.agent_id(),
+                classroom_id,
+                &restriction,
+                envelope(n),
+            );
+            assert_eq!(admitted, Ok(None));
+        }
+
+        // Events are sent in order once the decision is made
+        assert_eq!(decisions.resolved().await, vec![envelope(1), envelope(2)]);
+        assert_eq!(
+            decisions.admit(
+                &state,
+                teacher.agent_id(),
+                classroom_id,
+                &restriction,
+                envelope(3)
+            ),
+            Ok(Some(envelope(3)))
+        );
+
+        let mut decisions = AuthzDecisions::default();
+        let admitted = decisions.admit(
+            &state,
+            student.agent_id(),
+            classroom_id,
+            &restriction,
+            envelope(1),
+        );
+        assert_eq!(admitted, Ok(None));
+        assert!(decisions.resolved().await.is_empty());
+        assert_eq!(decisions.decisions.len(), 1);
+
+        // The connection must resync instead of losing events
+        let mut decisions = AuthzDecisions::default();
+        for n in 0..MAX_DEFERRED_EVENTS as u64 {
+            let admitted = decisions.admit(
+                &state,
+                teacher.agent_id(),
+                classroom_id,
+                &restriction,
+                envelope(n),
+            );
+            assert_eq!(admitted, Ok(None));
+        }
+        let admitted = decisions.admit(
+            &state,
+            teacher.agent_id(),
+            classroom_id,
+            &restriction,
+            envelope(MAX_DEFERRED_EVENTS as u64),
+        );
+        assert_eq!(admitted, Err(Overflow));
+    }
+}
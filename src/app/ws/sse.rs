@@ -0,0 +1,485 @@
+use crate::{
+    app::{
+        session_manager::ConnectionCommand,
+        state::State,
+        ws::{
+            deserialize_agent_label, deserialize_device_id, deserialize_metadata,
+            handler::{
+                build_envelope, connect, discard_session, get_presence_snapshot, leave_session,
+                move_to_history, publish_left, publish_occupancy, register_and_subscribe_session,
+                replay_events, send_to_sentry, serialize_to_json, TokenExpiry,
+            },
+            restriction::{AuthzDecisions, Overflow, Restriction},
+            ConnectOptions, ConnectRequest, Encoding, RecoverableSessionError, Response,
+            UnrecoverableSessionError,
+        },
+    },
+    classroom::ClassroomId,
+    session::Session,
+};
+use anyhow::anyhow;
+use async_nats::Message as SignalMessage;
+use axum::{
+    extract::{Extension, Query},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Response as HttpResponse,
+    },
+    Json,
+};
+use futures_util::StreamExt;
+use http::{header, HeaderMap};
+use serde::Deserialize;
+use std::{convert::Infallible, sync::Arc};
+use svc_authn::jose::ConfigMap;
+use svc_nats_client::Message as NatsMessage;
+use tokio::{
+    sync::mpsc::{self, Receiver, Sender},
+    time::{sleep_until, Instant},
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, info, warn};
+
+/// Events are buffered while the agent is slowly reading the stream.
+const EVENT_BUFFER_SIZE: usize = 100;
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+
+/// Parameters of `connect_request` passed in the query string.
+#[derive(Deserialize)]
+pub struct ConnectQuery {
+    classroom_id: ClassroomId,
+    /// Used if the token isn't passed in the `Authorization` header.
+    #[serde(default)]
+    token: Option<String>,
+    #[serde(deserialize_with = "deserialize_agent_label")]
+    agent_label: String,
+    #[serde(default)]
+    presence_snapshot: bool,
+    /// Used if the browser doesn't send `Last-Event-ID` on reconnecting.
+    #[serde(default)]
+    resume_from: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_device_id")]
+    device_id: Option<String>,
+    /// JSON object, the same as in `connect_request`.
+    #[serde(default)]
+    metadata: Option<String>,
+}
+
+impl ConnectQuery {
+    fn into_connect_request(
+        self,
+        headers: &HeaderMap,
+    ) -> Result<ConnectRequest, UnrecoverableSessionError> {
+        let token = bearer_token(headers)
+            .or(self.token)
+            .ok_or(UnrecoverableSessionError::Unauthenticated)?;
+
+        let resume_from = match headers.get(LAST_EVENT_ID_HEADER) {
+            Some(value) => {
+                let sequence = value
+                    .to_str()
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .ok_or(UnrecoverableSessionError::SerializationFailed)?;
+
+                Some(sequence)
+            }
+            None => self.resume_from,
+        };
+
+        let metadata = match self.metadata {
+            Some(metadata) => serde_json::from_str::<serde_json::Value>(&metadata)
+                .and_then(deserialize_metadata)
+                .map_err(|e| {
+                    warn!(error = %e, "Failed to deserialize metadata");
+                    UnrecoverableSessionError::SerializationFailed
+                })?,
+            None => None,
+        };
+
+        Ok(ConnectRequest {
+            classroom_id: self.classroom_id,
+            token,
+            agent_label: self.agent_label,
+            presence_snapshot: self.presence_snapshot,
+            resume_from,
+            device_id: self.device_id,
+            metadata,
+            // SSE events are text only
+            encoding: Encoding::Json,
+        })
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_owned())
+}
+
+/// A fallback for networks where WebSocket upgrades are blocked.
+///
+/// The agent connects to a single classroom and only receives events,
+/// they are sent as `data` of SSE events in the same envelopes as over WebSocket.
+pub async fn handler<S: State>(
+    Extension(state): Extension<S>,
+    Extension(authn): Extension<Arc<ConfigMap>>,
+    query: Result<Query<ConnectQuery>, axum::extract::rejection::QueryRejection>,
+    headers: HeaderMap,
+) -> HttpResponse {
+    let result = match query {
+        Ok(Query(query)) => query.into_connect_request(&headers),
+        Err(e) => {
+            warn!(error = %e, "Failed to deserialize connect query");
+            Err(UnrecoverableSessionError::SerializationFailed)
+        }
+    };
+
+    let result = match result {
+        Ok(request) => connect(request, authn, state.clone()).await,
+        Err(e) => Err(e),
+    };
+
+    let (session, options) = match result {
+        Ok(result) => result,
+        Err(error) => {
+            error!(?error, "sse connection is rejected");
+            state.metrics().sse_connection_error().inc();
+
+            return error_response(Response::from(error));
+        }
+    };
+
+    let result =
+        register_and_subscribe_session(state.clone(), &session, options.metadata.as_ref()).await;
+    let (nats_rx, signal_rx, close_rx) = match result {
+        Ok(result) => result,
+        Err(err) => {
+            let error = anyhow!(
+                "an error occurred during the registration of the session, {}",
+                &err
+            );
+            error!(%error, %session);
+            send_to_sentry(error);
+
+            let change = discard_session(state.clone(), &session).await;
+            publish_occupancy(&state, change).await;
+
+            return error_response(Response::from(err));
+        }
+    };
+
+    info!(%session, "successful sse registration");
+    state.metrics().sse_connection_success().inc();
+    state.metrics().sse_connection_total().inc();
+
+    let (tx, rx) = mpsc::channel(EVENT_BUFFER_SIZE);
+    let keep_alive = KeepAlive::new().interval(state.config().websocket.ping_interval);
+
+    tokio::task::spawn(stream_session(
+        state, session, options, nats_rx, signal_rx, close_rx, tx,
+    ));
+
+    Sse::new(ReceiverStream::new(rx).map(Ok::<_, Infallible>))
+        .keep_alive(keep_alive)
+        .into_response()
+}
+
+fn error_response(resp: Response) -> HttpResponse {
+    let status = match &resp {
+        Response::UnrecoverableSessionError(e) => e.status_code(),
+        _ => http::StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (status, Json(resp)).into_response()
+}
+
+/// Sends events of the session to the agent until it disconnects,
+/// then the session is closed the same way as a WebSocket one.
+async fn stream_session<S: State>(
+    state: S,
+    session: Session,
+    options: ConnectOptions,
+    mut nats_rx: ReceiverStream<Arc<NatsMessage>>,
+    mut signal_rx: Receiver<Arc<SignalMessage>>,
+    mut close_rx: Receiver<ConnectionCommand>,
+    tx: Sender<SseEvent>,
+) {
+    let agent_id = session.key().agent_id.clone();
+    let classroom_id = session.key().classroom_id;
+
+    send(&tx, serialize_to_json(&Response::ConnectSuccess), None).await;
+
+    // Missed events are replayed after subscribing to NATS,
+    // live events which are replayed are skipped then
+    let mut authz_decisions = AuthzDecisions::default();
+    let mut replayed_sequence = None;
+    if let Some(resume_from) = options.resume_from {
+        match replay_events(state.clone(), &session, resume_from, &mut authz_decisions).await {
+            Ok((envelopes, sequence)) => {
+                // Envelopes don't keep sequences, so only the last one is sent as the event id
+                let count = envelopes.len();
+                for (i, envelope) in envelopes.into_iter().enumerate() {
+                    let id = if i + 1 == count { sequence } else { None };
+                    send(&tx, serialize_to_json(&envelope), id).await;
+                }
+
+                replayed_sequence = sequence;
+            }
+            Err(err) => {
+                send(&tx, serialize_to_json(&Response::from(err)), None).await;
+            }
+        }
+    }
+
+    if options.presence_snapshot {
+        let resp = get_presence_snapshot(state.clone(), &session)
+            .await
+            .map(Response::PresenceSnapshot)
+            .unwrap_or_else(Response::from);
+        send(&tx, serialize_to_json(&resp), None).await;
+    }
+
+    // Mark a connection as terminating on graceful shutdown
+    let mut connect_terminating = false;
+
+    // The token can't be refreshed over SSE, so the agent reconnects with a new one
+    let mut token = TokenExpiry::new(options.token_expires_at);
+    let token_refresh_window = state.config().websocket.token_refresh_window;
+
+    loop {
+        let token_deadline = token.deadline(token_refresh_window);
+
+        tokio::select! {
+            msg = nats_rx.next() => {
+                let msg = match msg {
+                    Some(msg) => msg,
+                    None => {
+                        warn!(%classroom_id, "nats stream is over");
+                        break;
+                    }
+                };
+
+                let sequence = msg.info().ok().map(|info| info.stream_sequence);
+                match (replayed_sequence, sequence) {
+                    (Some(replayed), Some(sequence)) if sequence <= replayed => continue,
+                    _ => replayed_sequence = None,
+                }
+
+                let restriction = Restriction::from_headers(msg.message.headers.as_ref());
+                let envelope = match build_envelope(&agent_id, classroom_id, &msg.message, sequence) {
+                    Some(envelope) => authz_decisions.admit(&state, &agent_id, classroom_id, &restriction, (envelope, sequence)),
+                    None => Ok(None),
+                };
+                let envelope = match envelope {
+                    Ok(envelope) => envelope,
+                    Err(Overflow) => {
+                        warn!(%classroom_id, "closing connection to resume it after authz catches up");
+                        break;
+                    }
+                };
+
+                if let Some((envelope, sequence)) = envelope {
+                    if !send(&tx, serialize_to_json(&envelope), sequence).await {
+                        break;
+                    }
+                }
+            }
+            Some(msg) = signal_rx.recv() => {
+                // Signals are not persisted, so they have no stream sequence
+                let restriction = Restriction::from_headers(msg.headers.as_ref());
+                let envelope = match build_envelope(&agent_id, classroom_id, &msg, None) {
+                    Some(envelope) => authz_decisions.admit(&state, &agent_id, classroom_id, &restriction, (envelope, None)),
+                    None => Ok(None),
+                };
+                let envelope = match envelope {
+                    Ok(envelope) => envelope,
+                    Err(Overflow) => {
+                        warn!(%classroom_id, "closing connection to resume it after authz catches up");
+                        break;
+                    }
+                };
+
+                if let Some((envelope, _)) = envelope {
+                    if !send(&tx, serialize_to_json(&envelope), None).await {
+                        break;
+                    }
+                }
+            }
+            // Events deferred until authz decides whether the agent may receive them
+            envelopes = authz_decisions.resolved() => {
+                let mut closed = false;
+                for (envelope, sequence) in envelopes {
+                    if !send(&tx, serialize_to_json(&envelope), sequence).await {
+                        closed = true;
+                        break;
+                    }
+                }
+
+                if closed {
+                    break;
+                }
+            }
+            cmd = close_rx.recv() => {
+                match cmd {
+                    Some(ConnectionCommand::Close) => {
+                        // The session is taken over by another connection,
+                        // so it must not be moved to history here
+                        let msg = serialize_to_json(&Response::from(UnrecoverableSessionError::Replaced));
+                        send(&tx, msg, None).await;
+                    }
+                    Some(cmd @ (ConnectionCommand::Kick | ConnectionCommand::Deny)) => {
+                        // The session manager has already forgotten the session
+                        let change = move_to_history(state.clone(), &session).await;
+                        publish_left(state.clone(), &session).await;
+                        publish_occupancy(&state, change).await;
+
+                        let error = match cmd {
+                            ConnectionCommand::Kick => UnrecoverableSessionError::Kicked,
+                            _ => UnrecoverableSessionError::AccessDenied,
+                        };
+                        send(&tx, serialize_to_json(&Response::from(error)), None).await;
+                    }
+                    Some(ConnectionCommand::Terminate) => {
+                        if !connect_terminating {
+                            connect_terminating = true;
+                            let msg = serialize_to_json(&Response::from(RecoverableSessionError::Terminated));
+                            send(&tx, msg, None).await;
+                        }
+
+                        continue;
+                    }
+                    None => {
+                        warn!(%classroom_id, "cmd channel is closed, leaving...");
+                        break;
+                    }
+                }
+
+                info!("SSE connection is closed");
+                state.metrics().sse_connection_total().dec();
+
+                return;
+            }
+            _ = sleep_until(token_deadline.unwrap_or_else(Instant::now)), if token_deadline.is_some() => {
+                if !token.notify() {
+                    let msg = serialize_to_json(&Response::from(RecoverableSessionError::TokenExpiring));
+                    send(&tx, msg, None).await;
+                    continue;
+                }
+
+                warn!(%agent_id, "SSE connection is closed (token expired)");
+                let msg = serialize_to_json(&Response::from(UnrecoverableSessionError::Unauthenticated));
+                send(&tx, msg, None).await;
+                break;
+            }
+            _ = tx.closed() => {
+                info!("An agent closed SSE connection");
+                break;
+            }
+        }
+    }
+
+    state.metrics().sse_connection_total().dec();
+
+    // Skip next steps if the connection is terminating
+    if connect_terminating {
+        return;
+    }
+
+    leave_session(state, &session).await;
+}
+
+/// Returns `false` if the agent has disconnected.
+async fn send(tx: &Sender<SseEvent>, data: String, id: Option<u64>) -> bool {
+    let mut event = SseEvent::default().data(data);
+    if let Some(id) = id {
+        event = event.id(id.to_string());
+    }
+
+    if tx.send(event).await.is_err() {
+        info!("An agent disconnected (event not sent)");
+        return false;
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+    use uuid::Uuid;
+
+    fn query(token: Option<&str>) -> ConnectQuery {
+        ConnectQuery {
+            classroom_id: Uuid::new_v4().into(),
+            token: token.map(ToOwned::to_owned),
+            agent_label: "web".to_owned(),
+            presence_snapshot: false,
+            resume_from: Some(5),
+            device_id: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn token_from_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer header-token"),
+        );
+
+        let request = query(Some("query-token"))
+            .into_connect_request(&headers)
+            .expect("Failed to build connect request");
+
+        assert_eq!(request.token, "header-token");
+        assert_eq!(request.resume_from, Some(5));
+    }
+
+    #[test]
+    fn token_from_query() {
+        let request = query(Some("query-token"))
+            .into_connect_request(&HeaderMap::new())
+            .expect("Failed to build connect request");
+
+        assert_eq!(request.token, "query-token");
+    }
+
+    #[test]
+    fn missing_token() {
+        let error = query(None)
+            .into_connect_request(&HeaderMap::new())
+            .map(|_| ())
+            .expect_err("Unexpectedly succeeded");
+
+        assert_eq!(error, UnrecoverableSessionError::Unauthenticated);
+    }
+
+    #[test]
+    fn resume_from_last_event_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert(LAST_EVENT_ID_HEADER, HeaderValue::from_static("42"));
+
+        let request = query(Some("token"))
+            .into_connect_request(&headers)
+            .expect("Failed to build connect request");
+
+        assert_eq!(request.resume_from, Some(42));
+    }
+
+    #[test]
+    fn invalid_metadata() {
+        let mut query = query(Some("token"));
+        query.metadata = Some("[1, 2]".to_owned());
+
+        let error = query
+            .into_connect_request(&HeaderMap::new())
+            .map(|_| ())
+            .expect_err("Unexpectedly succeeded");
+
+        assert_eq!(error, UnrecoverableSessionError::SerializationFailed);
+    }
+}
@@ -1,6 +1,15 @@
+use crate::classroom::ClassroomId;
 use serde_derive::Deserialize;
-use std::{net::SocketAddr, time::Duration};
-use svc_authn::{jose::ConfigMap as AuthnConfig, AccountId};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use svc_authn::{
+    jose::{Algorithm, ConfigMap as AuthnConfig},
+    AccountId,
+};
 use svc_authz::ConfigMap as Authz;
 use svc_error::extension::sentry::Config as SentryConfig;
 
@@ -10,12 +19,23 @@ pub struct Config {
     pub listener_address: SocketAddr,
     pub metrics_listener_address: SocketAddr,
     pub internal_listener_address: SocketAddr,
+    pub internal_api: InternalApiConfig,
     pub sentry: Option<SentryConfig>,
     pub authn: AuthnConfig,
     pub websocket: WebSocketConfig,
     pub authz: Authz,
     pub svc_audience: String,
     pub nats: svc_nats_client::Config,
+    #[serde(default)]
+    pub capacity: CapacityConfig,
+    #[serde(default)]
+    pub replica: ReplicaConfig,
+    #[serde(default)]
+    pub devices: DevicesConfig,
+    #[serde(default)]
+    pub webhooks: WebhooksConfig,
+    #[serde(default)]
+    pub agent_list: AgentListConfig,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -28,6 +48,201 @@ pub struct WebSocketConfig {
     pub authentication_timeout: Duration,
     #[serde(with = "humantime_serde")]
     pub wait_before_close_connection: Duration,
+    /// How far back missed events are replayed to a reconnecting agent.
+    #[serde(with = "humantime_serde")]
+    pub resume_window: Duration,
+    /// Max number of signals per second sent by an agent over all of its connections to a replica.
+    pub signal_rate_limit: u32,
+    /// The agent is considered idle if the client hasn't sent `activity` for this time.
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+    /// `token_expiring` is sent this time before the token of the connection expires.
+    #[serde(with = "humantime_serde")]
+    pub token_refresh_window: Duration,
+    /// Access of connected agents to classrooms is re-checked with this interval, never if not set.
+    #[serde(default, with = "humantime_serde")]
+    pub reauthorization_interval: Option<Duration>,
+}
+
+/// Replicas call the internal API of each other with tokens signed by the service's own key,
+/// the tokens are verified with `authn` config.
+#[derive(Clone, Deserialize)]
+pub struct InternalApiConfig {
+    #[serde(deserialize_with = "svc_authn::serde::file")]
+    pub key: Vec<u8>,
+    #[serde(deserialize_with = "svc_authn::serde::algorithm")]
+    pub algorithm: Algorithm,
+    /// Timeout of requests to other replicas.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    #[serde(default)]
+    pub transport: ReplicaTransport,
+}
+
+/// How replicas send commands to each other.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicaTransport {
+    /// HTTP requests to the internal API by pod IPs obtained from Kubernetes.
+    #[default]
+    Http,
+    /// NATS requests to replica-specific subjects, pod IPs aren't needed.
+    Nats,
+}
+
+impl fmt::Debug for InternalApiConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The private key must not get into logs
+        f.debug_struct("InternalApiConfig")
+            .field("algorithm", &self.algorithm)
+            .field("timeout", &self.timeout)
+            .field("transport", &self.transport)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Liveness of replicas.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReplicaConfig {
+    /// How often the replica sends a heartbeat and cleans up dead replicas.
+    #[serde(with = "humantime_serde")]
+    pub heartbeat_interval: Duration,
+    /// A replica is considered dead if it hasn't sent a heartbeat for this time.
+    #[serde(with = "humantime_serde")]
+    pub stale_threshold: Duration,
+    /// How the replica finds out its IP for the internal API.
+    #[serde(default)]
+    pub address: AddressDiscovery,
+}
+
+impl Default for ReplicaConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(10),
+            stale_threshold: Duration::from_secs(60),
+            address: AddressDiscovery::default(),
+        }
+    }
+}
+
+/// How a replica finds out its IP which other replicas use to reach it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AddressDiscovery {
+    /// Pod IP obtained from the Kubernetes API by the replica label.
+    #[default]
+    Kubernetes,
+    /// An explicitly configured IP.
+    Address { address: IpAddr },
+    /// The first IP the hostname resolves to.
+    Hostname { hostname: String },
+    /// IP of the network interface, the first non-loopback one if the name is omitted.
+    Interface { name: Option<String> },
+}
+
+/// Max number of concurrent agents in classrooms, unlimited by default.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CapacityConfig {
+    /// Applies to classrooms without a specific limit.
+    pub default: Option<usize>,
+    /// Max number of agents of the audience in a classroom, in addition to the limit of the classroom.
+    #[serde(default)]
+    pub audiences: HashMap<String, usize>,
+    /// Limits of specific classrooms.
+    #[serde(default)]
+    pub classrooms: HashMap<ClassroomId, usize>,
+}
+
+impl CapacityConfig {
+    /// Max number of agents of all audiences in the classroom.
+    pub fn limit(&self, classroom_id: &ClassroomId) -> Option<usize> {
+        self.classrooms.get(classroom_id).copied().or(self.default)
+    }
+
+    /// Max number of agents of the audience in the classroom.
+    pub fn audience_limit(&self, audience: &str) -> Option<usize> {
+        self.audiences.get(audience).copied()
+    }
+}
+
+/// Max number of concurrent sessions of an agent in a classroom distinguished by device ids.
+/// Agents of other audiences have a single session which is replaced by a new connection.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct DevicesConfig {
+    #[serde(default)]
+    pub audiences: HashMap<String, usize>,
+}
+
+impl DevicesConfig {
+    pub fn limit(&self, audience: &str) -> Option<usize> {
+        self.audiences.get(audience).copied()
+    }
+}
+
+/// The list of agents for callers which can `read` the classroom but not `list_agents` in it.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AgentListConfig {
+    /// If set, such callers only get the number of agents and agents with these metadata roles
+    /// (e.g. teachers), otherwise they get the full list.
+    #[serde(default)]
+    pub redacted_view_roles: Option<Vec<String>>,
+}
+
+/// Webhooks notify external systems which can't read NATS
+/// about agents entering and leaving classrooms.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    /// Webhooks by audiences of agents.
+    pub audiences: HashMap<String, WebhookConfig>,
+    /// Timeout of a webhook request.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// How often the queue of notifications is checked.
+    #[serde(with = "humantime_serde")]
+    pub poll_interval: Duration,
+    /// Max number of notifications claimed at once.
+    pub batch_size: i64,
+    /// Max number of notifications sent in parallel.
+    pub concurrency: usize,
+    /// A notification is dropped after this number of failed attempts.
+    pub max_attempts: i32,
+    /// The delay before the first retry, doubled on each next one up to `max_backoff`.
+    #[serde(with = "humantime_serde")]
+    pub min_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            audiences: HashMap::new(),
+            timeout: Duration::from_secs(5),
+            poll_interval: Duration::from_secs(1),
+            batch_size: 100,
+            concurrency: 10,
+            max_attempts: 10,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Notifications are signed with HMAC-SHA256 by the secret.
+#[derive(Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: String,
+}
+
+impl fmt::Debug for WebhookConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The secret must not get into logs
+        f.debug_struct("WebhookConfig")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
 }
 
 pub fn load() -> Result<Config, config::ConfigError> {
@@ -1,5 +1,9 @@
-use crate::{classroom::ClassroomId, session::SessionId};
+use crate::{
+    classroom::ClassroomId,
+    session::{AgentStatus, SessionId},
+};
 use serde_derive::Serialize;
+use serde_json::Value as JsonValue;
 use sqlx::{postgres::PgQueryResult, types::time::OffsetDateTime, Error, PgConnection};
 use std::collections::HashMap;
 use svc_agent::AgentId;
@@ -12,6 +16,7 @@ pub struct AgentSession {
     pub classroom_id: ClassroomId,
     pub replica_id: Uuid,
     pub started_at: OffsetDateTime,
+    pub device_id: String,
 }
 
 pub struct InsertQuery<'a> {
@@ -19,6 +24,8 @@ pub struct InsertQuery<'a> {
     classroom_id: ClassroomId,
     replica_id: Uuid,
     started_at: OffsetDateTime,
+    device_id: &'a str,
+    metadata: Option<&'a JsonValue>,
 }
 
 pub enum InsertResult {
@@ -54,34 +61,47 @@ impl<'a> InsertQuery<'a> {
             classroom_id,
             replica_id,
             started_at,
+            device_id: "",
+            metadata: None,
         }
     }
 
+    pub fn device(self, device_id: &'a str) -> Self {
+        Self { device_id, ..self }
+    }
+
+    pub fn metadata(self, metadata: Option<&'a JsonValue>) -> Self {
+        Self { metadata, ..self }
+    }
+
     pub async fn execute(&self, conn: &mut PgConnection) -> InsertResult {
         let query = sqlx::query_as!(
             AgentSession,
             r#"
             INSERT INTO agent_session
-                (agent_id, classroom_id, replica_id, started_at)
-            VALUES ($1, $2, $3, $4)
+                (agent_id, classroom_id, replica_id, started_at, device_id, metadata)
+            VALUES ($1, $2, $3, $4, $5, $6)
             RETURNING
                 id AS "id: SessionId",
                 agent_id AS "agent_id: AgentId",
                 classroom_id AS "classroom_id: ClassroomId",
                 replica_id,
-                started_at
+                started_at,
+                device_id
             "#,
             self.agent_id as &AgentId,
             self.classroom_id as ClassroomId,
             self.replica_id,
             self.started_at,
+            self.device_id,
+            self.metadata,
         );
 
         match query.fetch_one(conn).await {
             Ok(agent_session) => InsertResult::Ok(agent_session),
             Err(Error::Database(err)) => {
                 if let Some(constraint) = err.constraint() {
-                    if constraint == "uniq_classroom_id_agent_id" {
+                    if constraint == "uniq_classroom_id_agent_id_device_id" {
                         return InsertResult::UniqIdsConstraintError;
                     }
                 }
@@ -122,46 +142,132 @@ impl<'a> DeleteQuery<'a> {
 pub struct Agent {
     pub sequence_id: SessionId,
     pub agent_id: AgentId,
+    pub status: AgentStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_text: Option<String>,
+    /// The agent hasn't reported activity on any device for `websocket.idle_timeout`.
+    pub idle: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<JsonValue>,
+}
+
+/// Narrows down the list of agents, unset filters match all agents.
+#[derive(Clone, Copy, Default)]
+pub struct AgentFilter<'a> {
+    pub audience: Option<&'a str>,
+    pub label: Option<&'a str>,
+    /// Roles from the metadata of agents.
+    pub roles: Option<&'a [String]>,
+    pub connected_since: Option<OffsetDateTime>,
 }
 
-pub struct AgentList {
+pub struct AgentList<'a> {
     classroom_id: ClassroomId,
     sequence_id: usize,
     limit: usize,
+    filter: AgentFilter<'a>,
 }
 
-impl AgentList {
+impl<'a> AgentList<'a> {
     pub fn new(classroom_id: ClassroomId, sequence_id: usize, limit: usize) -> Self {
         Self {
             classroom_id,
             sequence_id,
             limit,
+            filter: AgentFilter::default(),
         }
     }
 
+    pub fn filter(self, filter: AgentFilter<'a>) -> Self {
+        Self { filter, ..self }
+    }
+
     pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Vec<Agent>> {
         sqlx::query_as!(
             Agent,
             r#"
             SELECT
                 id AS "sequence_id: SessionId",
-                agent_id AS "agent_id: AgentId"
-            FROM agent_session
-            WHERE
-                classroom_id = $1::uuid
-                AND id > $3
+                agent_id AS "agent_id: AgentId",
+                status AS "status: AgentStatus",
+                status_text,
+                NOT EXISTS (
+                    SELECT 1
+                    FROM agent_session active
+                    WHERE active.classroom_id = s.classroom_id
+                        AND active.agent_id = s.agent_id
+                        AND NOT active.idle
+                ) AS "idle!",
+                metadata
+            FROM (
+                -- Agents with several devices are listed once
+                -- by the oldest of their devices matching the filter
+                SELECT DISTINCT ON (agent_id)
+                    id, agent_id, classroom_id, status, status_text, metadata
+                FROM agent_session
+                WHERE
+                    classroom_id = $1::uuid
+                    AND ($4::text IS NULL OR ((agent_id).account_id).audience = $4)
+                    AND ($5::text IS NULL OR (agent_id).label = $5)
+                    AND ($6::text[] IS NULL OR metadata->>'role' = ANY($6))
+                    AND ($7::timestamptz IS NULL OR started_at >= $7)
+                ORDER BY agent_id, id
+            ) s
+            WHERE id > $3
             ORDER BY id
             LIMIT $2
             "#,
             self.classroom_id as ClassroomId,
             self.limit as i32,
-            self.sequence_id as i32
+            self.sequence_id as i32,
+            self.filter.audience,
+            self.filter.label,
+            self.filter.roles,
+            self.filter.connected_since
         )
         .fetch_all(conn)
         .await
     }
 }
 
+/// Counts agents in the classroom which match the filter.
+pub struct FilteredCountQuery<'a> {
+    classroom_id: ClassroomId,
+    filter: AgentFilter<'a>,
+}
+
+impl<'a> FilteredCountQuery<'a> {
+    pub fn new(classroom_id: ClassroomId, filter: AgentFilter<'a>) -> Self {
+        Self {
+            classroom_id,
+            filter,
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+            -- Agents with several devices are counted once, the same as in the list of agents
+            SELECT COUNT(DISTINCT agent_id) AS "count!"
+            FROM agent_session
+            WHERE
+                classroom_id = $1::uuid
+                AND ($2::text IS NULL OR ((agent_id).account_id).audience = $2)
+                AND ($3::text IS NULL OR (agent_id).label = $3)
+                AND ($4::text[] IS NULL OR metadata->>'role' = ANY($4))
+                AND ($5::timestamptz IS NULL OR started_at >= $5)
+            "#,
+            self.classroom_id as ClassroomId,
+            self.filter.audience,
+            self.filter.label,
+            self.filter.roles,
+            self.filter.connected_since
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
 #[derive(Serialize)]
 pub struct AgentCount {
     pub classroom_id: ClassroomId,
@@ -186,7 +292,7 @@ impl<'a> AgentCounter<'a> {
             r#"
             SELECT
                 classroom_id AS "classroom_id: ClassroomId",
-                COUNT(agent_id) AS "count!"
+                COUNT(DISTINCT agent_id) AS "count!"
             FROM agent_session
             WHERE
                 classroom_id = ANY ($1)
@@ -206,6 +312,127 @@ impl<'a> AgentCounter<'a> {
     }
 }
 
+/// Counts agents in the classroom except the given one,
+/// so that the agent can replace its own session in a full classroom.
+pub struct CountQuery<'a> {
+    classroom_id: ClassroomId,
+    agent_id: &'a AgentId,
+    audience: Option<&'a str>,
+}
+
+impl<'a> CountQuery<'a> {
+    pub fn new(classroom_id: ClassroomId, agent_id: &'a AgentId) -> Self {
+        Self {
+            classroom_id,
+            agent_id,
+            audience: None,
+        }
+    }
+
+    /// Counts only agents of the audience.
+    pub fn audience(self, audience: &'a str) -> Self {
+        Self {
+            audience: Some(audience),
+            ..self
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(DISTINCT agent_id) AS "count!"
+            FROM agent_session
+            WHERE
+                classroom_id = $1
+                AND agent_id <> $2
+                AND ($3::text IS NULL OR ((agent_id).account_id).audience = $3)
+            "#,
+            self.classroom_id as ClassroomId,
+            self.agent_id as &AgentId,
+            self.audience
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
+/// Counts sessions of the agent in the classroom on devices other than the given one.
+pub struct DeviceCountQuery<'a> {
+    classroom_id: ClassroomId,
+    agent_id: &'a AgentId,
+    device_id: &'a str,
+    active_only: bool,
+}
+
+impl<'a> DeviceCountQuery<'a> {
+    pub fn new(classroom_id: ClassroomId, agent_id: &'a AgentId, device_id: &'a str) -> Self {
+        Self {
+            classroom_id,
+            agent_id,
+            device_id,
+            active_only: false,
+        }
+    }
+
+    /// Counts only devices which aren't idle.
+    pub fn active_only(self) -> Self {
+        Self {
+            active_only: true,
+            ..self
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!"
+            FROM agent_session
+            WHERE
+                classroom_id = $1
+                AND agent_id = $2
+                AND device_id <> $3
+                AND (NOT $4 OR NOT idle)
+            "#,
+            self.classroom_id as ClassroomId,
+            self.agent_id as &AgentId,
+            self.device_id,
+            self.active_only
+        )
+        .fetch_one(conn)
+        .await
+    }
+}
+
+pub struct ListQuery {
+    replica_id: Uuid,
+}
+
+impl ListQuery {
+    pub fn by_replica(replica_id: Uuid) -> Self {
+        Self { replica_id }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Vec<AgentSession>> {
+        sqlx::query_as!(
+            AgentSession,
+            r#"
+            SELECT
+                id AS "id: SessionId",
+                agent_id AS "agent_id: AgentId",
+                classroom_id AS "classroom_id: ClassroomId",
+                replica_id,
+                started_at,
+                device_id
+            FROM agent_session
+            WHERE replica_id = $1
+            "#,
+            self.replica_id
+        )
+        .fetch_all(conn)
+        .await
+    }
+}
+
 pub struct GetQuery {
     id: SessionId,
 }
@@ -224,7 +451,8 @@ impl GetQuery {
                 agent_id AS "agent_id: AgentId",
                 classroom_id AS "classroom_id: ClassroomId",
                 replica_id,
-                started_at
+                started_at,
+                device_id
             FROM agent_session
             WHERE
                 id = $1
@@ -237,25 +465,107 @@ impl GetQuery {
     }
 }
 
-pub struct UpdateQuery {
+/// Moves the session to the new connection which replaces it,
+/// the agent is active on the new connection.
+pub struct UpdateQuery<'a> {
     id: SessionId,
     replica_id: Uuid,
+    metadata: Option<&'a JsonValue>,
 }
 
-impl UpdateQuery {
+impl<'a> UpdateQuery<'a> {
     pub fn new(id: SessionId, replica_id: Uuid) -> Self {
-        Self { id, replica_id }
+        Self {
+            id,
+            replica_id,
+            metadata: None,
+        }
+    }
+
+    /// The metadata of the new connection which replaces the session.
+    pub fn metadata(self, metadata: Option<&'a JsonValue>) -> Self {
+        Self { metadata, ..self }
     }
 
     pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
         sqlx::query!(
             r#"
             UPDATE agent_session
-            SET replica_id = $2
+            SET replica_id = $2,
+                metadata = $3,
+                idle = false
             WHERE id = $1
             "#,
             &self.id as &SessionId,
-            self.replica_id
+            self.replica_id,
+            self.metadata
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+/// Updates the status of the agent in the classroom on all devices.
+pub struct UpdateStatusQuery<'a> {
+    classroom_id: ClassroomId,
+    agent_id: &'a AgentId,
+    status: AgentStatus,
+    status_text: Option<&'a str>,
+}
+
+impl<'a> UpdateStatusQuery<'a> {
+    pub fn new(
+        classroom_id: ClassroomId,
+        agent_id: &'a AgentId,
+        status: AgentStatus,
+        status_text: Option<&'a str>,
+    ) -> Self {
+        Self {
+            classroom_id,
+            agent_id,
+            status,
+            status_text,
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            UPDATE agent_session
+            SET status = $3,
+                status_text = $4
+            WHERE classroom_id = $1
+                AND agent_id = $2
+            "#,
+            self.classroom_id as ClassroomId,
+            self.agent_id as &AgentId,
+            self.status as AgentStatus,
+            self.status_text
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+pub struct UpdateIdleQuery {
+    id: SessionId,
+    idle: bool,
+}
+
+impl UpdateIdleQuery {
+    pub fn new(id: SessionId, idle: bool) -> Self {
+        Self { id, idle }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            UPDATE agent_session
+            SET idle = $2
+            WHERE id = $1
+            "#,
+            self.id as SessionId,
+            self.idle
         )
         .execute(conn)
         .await
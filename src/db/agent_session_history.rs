@@ -4,7 +4,6 @@ use sqlx::{
     types::time::OffsetDateTime,
     PgConnection,
 };
-use std::collections::Bound;
 use svc_agent::AgentId;
 use uuid::Uuid;
 
@@ -74,13 +73,15 @@ impl<'a> InsertQuery<'a> {
     }
 }
 
+/// Extends the history up to now by the overlapping session,
+/// which may have started earlier than the history.
 pub struct UpdateLifetimeQuery {
     id: SessionId,
-    started_at: Bound<OffsetDateTime>,
+    started_at: OffsetDateTime,
 }
 
 impl UpdateLifetimeQuery {
-    pub fn new(id: SessionId, started_at: Bound<OffsetDateTime>) -> Self {
+    pub fn new(id: SessionId, started_at: OffsetDateTime) -> Self {
         Self { id, started_at }
     }
 
@@ -88,11 +89,11 @@ impl UpdateLifetimeQuery {
         sqlx::query!(
             r#"
             UPDATE agent_session_history
-            SET lifetime = $2
+            SET lifetime = tstzrange(LEAST(lower(lifetime), $2), now())
             WHERE id = $1
             "#,
             self.id as SessionId,
-            PgRange::from((self.started_at, Bound::Excluded(OffsetDateTime::now_utc())))
+            self.started_at
         )
         .execute(conn)
         .await
@@ -115,7 +116,7 @@ impl UpdateLifetimesQuery {
                 SELECT
                     s.id,
                     ash.id AS history_id,
-                    tstzrange(lower(ash.lifetime), now()) AS new_lifetime
+                    tstzrange(LEAST(lower(ash.lifetime), s.started_at), now()) AS new_lifetime
                 FROM agent_session s
                     LEFT OUTER JOIN agent_session_history ash
                         ON ash.agent_id = s.agent_id
@@ -193,3 +194,100 @@ impl<'a> InsertFromAgentSessionQuery<'a> {
         q.fetch_all(conn).await
     }
 }
+
+pub struct AgentLifetime {
+    pub agent_id: AgentId,
+    pub lifetime: PgRange<OffsetDateTime>,
+}
+
+/// Returns lifetimes of finished and current sessions in the classroom
+/// clipped by the given time window.
+pub struct AttendanceQuery {
+    classroom_id: ClassroomId,
+    from: Option<OffsetDateTime>,
+    to: Option<OffsetDateTime>,
+}
+
+impl AttendanceQuery {
+    pub fn new(
+        classroom_id: ClassroomId,
+        from: Option<OffsetDateTime>,
+        to: Option<OffsetDateTime>,
+    ) -> Self {
+        Self {
+            classroom_id,
+            from,
+            to,
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Vec<AgentLifetime>> {
+        sqlx::query_as!(
+            AgentLifetime,
+            r#"
+            WITH lifetimes AS (
+                SELECT agent_id, lifetime
+                FROM agent_session_history
+                WHERE classroom_id = $1
+                UNION ALL
+                SELECT agent_id, tstzrange(started_at, now()) AS lifetime
+                FROM agent_session
+                WHERE classroom_id = $1
+            )
+            SELECT
+                agent_id AS "agent_id!: AgentId",
+                lifetime * tstzrange($2, $3) AS "lifetime!"
+            FROM lifetimes
+            WHERE lifetime && tstzrange($2, $3)
+            ORDER BY lower(lifetime)
+            "#,
+            self.classroom_id as ClassroomId,
+            self.from,
+            self.to,
+        )
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// Returns the latest history of the agent in the classroom,
+/// which is the one just updated when the agent leaves.
+/// Histories of overlapping sessions are merged, so it spans
+/// from the first connect to the last disconnect of the agent's devices.
+pub struct LastLifetimeQuery<'a> {
+    classroom_id: ClassroomId,
+    agent_id: &'a AgentId,
+}
+
+impl<'a> LastLifetimeQuery<'a> {
+    pub fn new(classroom_id: ClassroomId, agent_id: &'a AgentId) -> Self {
+        Self {
+            classroom_id,
+            agent_id,
+        }
+    }
+
+    pub async fn execute(
+        &self,
+        conn: &mut PgConnection,
+    ) -> sqlx::Result<Option<AgentSessionHistory>> {
+        sqlx::query_as!(
+            AgentSessionHistory,
+            r#"
+            SELECT
+                id AS "id!: SessionId",
+                lifetime AS "lifetime!"
+            FROM agent_session_history
+            WHERE
+                agent_id = $1
+                AND classroom_id = $2
+            ORDER BY upper(lifetime) DESC
+            LIMIT 1
+            "#,
+            self.agent_id as &AgentId,
+            self.classroom_id as ClassroomId
+        )
+        .fetch_optional(conn)
+        .await
+    }
+}
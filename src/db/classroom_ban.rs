@@ -0,0 +1,77 @@
+use crate::classroom::ClassroomId;
+use sqlx::{postgres::PgQueryResult, types::time::OffsetDateTime, PgConnection};
+use svc_authn::AccountId;
+
+pub struct ClassroomBan {
+    pub expires_at: OffsetDateTime,
+}
+
+pub struct InsertQuery<'a> {
+    classroom_id: ClassroomId,
+    account_id: &'a AccountId,
+    expires_at: OffsetDateTime,
+}
+
+impl<'a> InsertQuery<'a> {
+    pub fn new(
+        classroom_id: ClassroomId,
+        account_id: &'a AccountId,
+        expires_at: OffsetDateTime,
+    ) -> Self {
+        Self {
+            classroom_id,
+            account_id,
+            expires_at,
+        }
+    }
+
+    /// Bans the account in the classroom or extends the existing ban.
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            INSERT INTO classroom_ban (classroom_id, account_id, expires_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (classroom_id, account_id)
+            DO UPDATE SET expires_at = EXCLUDED.expires_at, created_at = now()
+            "#,
+            self.classroom_id as ClassroomId,
+            self.account_id as &AccountId,
+            self.expires_at
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+pub struct FindQuery<'a> {
+    classroom_id: ClassroomId,
+    account_id: &'a AccountId,
+}
+
+impl<'a> FindQuery<'a> {
+    pub fn new(classroom_id: ClassroomId, account_id: &'a AccountId) -> Self {
+        Self {
+            classroom_id,
+            account_id,
+        }
+    }
+
+    /// Returns the ban of the account in the classroom if it hasn't expired yet.
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Option<ClassroomBan>> {
+        sqlx::query_as!(
+            ClassroomBan,
+            r#"
+            SELECT expires_at
+            FROM classroom_ban
+            WHERE
+                classroom_id = $1
+                AND account_id = $2
+                AND expires_at > now()
+            "#,
+            self.classroom_id as ClassroomId,
+            self.account_id as &AccountId
+        )
+        .fetch_optional(conn)
+        .await
+    }
+}
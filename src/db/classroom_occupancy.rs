@@ -0,0 +1,75 @@
+use crate::classroom::ClassroomId;
+use sqlx::{postgres::PgQueryResult, PgConnection};
+
+pub struct LockQuery {
+    classroom_id: ClassroomId,
+}
+
+impl LockQuery {
+    pub fn new(classroom_id: ClassroomId) -> Self {
+        Self { classroom_id }
+    }
+
+    /// Serializes occupancy changes and capacity checks of the classroom
+    /// until the end of the transaction.
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtextextended($1::uuid::text, 0))")
+            .bind(self.classroom_id)
+            .execute(conn)
+            .await
+    }
+}
+
+pub struct OccupyQuery {
+    classroom_id: ClassroomId,
+}
+
+impl OccupyQuery {
+    pub fn new(classroom_id: ClassroomId) -> Self {
+        Self { classroom_id }
+    }
+
+    /// Marks the classroom as occupied if there are agent sessions in it.
+    /// Returns the id of the occupancy only if the classroom wasn't occupied before.
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO classroom_occupancy (classroom_id)
+            SELECT $1
+            WHERE EXISTS (SELECT 1 FROM agent_session WHERE classroom_id = $1)
+            ON CONFLICT (classroom_id) DO NOTHING
+            RETURNING id
+            "#,
+            self.classroom_id as ClassroomId
+        )
+        .fetch_optional(conn)
+        .await
+    }
+}
+
+pub struct VacateQuery {
+    classroom_id: ClassroomId,
+}
+
+impl VacateQuery {
+    pub fn new(classroom_id: ClassroomId) -> Self {
+        Self { classroom_id }
+    }
+
+    /// Marks the classroom as vacated if there are no agent sessions in it.
+    /// Returns the id of the finished occupancy only if the classroom was occupied before.
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            r#"
+            DELETE FROM classroom_occupancy
+            WHERE
+                classroom_id = $1
+                AND NOT EXISTS (SELECT 1 FROM agent_session WHERE classroom_id = $1)
+            RETURNING id
+            "#,
+            self.classroom_id as ClassroomId
+        )
+        .fetch_optional(conn)
+        .await
+    }
+}
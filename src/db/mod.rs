@@ -3,7 +3,10 @@ use std::{env::var, time::Duration};
 
 pub mod agent_session;
 pub mod agent_session_history;
+pub mod classroom_ban;
+pub mod classroom_occupancy;
 pub mod replica;
+pub mod webhook_delivery;
 
 const DEFAULT_POOL_SIZE: u32 = 5;
 const DEFAULT_POOL_IDLE_SIZE: u32 = 1;
@@ -1,6 +1,6 @@
 use crate::{classroom::ClassroomId, session::SessionKey};
 use sqlx::postgres::PgQueryResult;
-use sqlx::types::ipnetwork::IpNetwork;
+use sqlx::types::{ipnetwork::IpNetwork, time::OffsetDateTime};
 use sqlx::PgConnection;
 use std::net::IpAddr;
 use svc_agent::AgentId;
@@ -30,7 +30,7 @@ impl InsertQuery {
             INSERT INTO replica (label, ip)
             VALUES ($1, $2)
             ON CONFLICT (label)
-            DO UPDATE SET ip = EXCLUDED.ip
+            DO UPDATE SET ip = EXCLUDED.ip, heartbeat_at = NOW()
             RETURNING id
             "#,
             &self.label,
@@ -63,41 +63,138 @@ impl DeleteQuery {
     }
 }
 
-pub struct ReplicaIp {
+pub struct HeartbeatQuery {
+    id: Uuid,
+}
+
+impl HeartbeatQuery {
+    pub fn new(id: Uuid) -> Self {
+        Self { id }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            UPDATE replica
+            SET heartbeat_at = NOW()
+            WHERE id = $1
+            "#,
+            self.id
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+pub struct StaleListQuery {
+    heartbeat_before: OffsetDateTime,
+    except_id: Uuid,
+}
+
+impl StaleListQuery {
+    pub fn new(heartbeat_before: OffsetDateTime, except_id: Uuid) -> Self {
+        Self {
+            heartbeat_before,
+            except_id,
+        }
+    }
+
+    /// Locks replicas which haven't sent a heartbeat in time until the end of the transaction.
+    /// Replicas locked by another transaction are skipped, so they are cleaned up only once.
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Vec<Uuid>> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT id
+            FROM replica
+            WHERE
+                heartbeat_at < $1
+                AND id <> $2
+            FOR UPDATE SKIP LOCKED
+            "#,
+            self.heartbeat_before,
+            self.except_id
+        )
+        .fetch_all(conn)
+        .await
+    }
+}
+
+/// The replica which holds the session.
+pub struct SessionReplica {
+    pub id: Uuid,
     ip: IpNetwork,
 }
 
-impl ReplicaIp {
+impl SessionReplica {
     pub fn ip(&self) -> IpAddr {
         self.ip.ip()
     }
 }
 
-pub struct GetIpQuery {
+pub struct GetBySessionQuery {
     session_key: SessionKey,
 }
 
-impl GetIpQuery {
+impl GetBySessionQuery {
     pub fn new(session_key: SessionKey) -> Self {
         Self { session_key }
     }
 
-    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<ReplicaIp> {
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<SessionReplica> {
         sqlx::query_as!(
-            ReplicaIp,
+            SessionReplica,
             r#"
-            SELECT replica.ip
+            SELECT replica.id, replica.ip
             FROM replica
             JOIN agent_session
                 ON replica.id = agent_session.replica_id
             WHERE agent_session.agent_id = $1
                 AND agent_session.classroom_id = $2
+                AND agent_session.device_id = $3
             LIMIT 1
             "#,
             &self.session_key.agent_id as &AgentId,
             &self.session_key.classroom_id as &ClassroomId,
+            &self.session_key.device_id,
         )
         .fetch_one(conn)
         .await
     }
 }
+
+/// Lists other replicas with sessions of the agent in the classroom on any device.
+pub struct ListByAgentQuery<'a> {
+    classroom_id: ClassroomId,
+    agent_id: &'a AgentId,
+    except_id: Uuid,
+}
+
+impl<'a> ListByAgentQuery<'a> {
+    pub fn new(classroom_id: ClassroomId, agent_id: &'a AgentId, except_id: Uuid) -> Self {
+        Self {
+            classroom_id,
+            agent_id,
+            except_id,
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Vec<SessionReplica>> {
+        sqlx::query_as!(
+            SessionReplica,
+            r#"
+            SELECT DISTINCT replica.id, replica.ip
+            FROM replica
+            JOIN agent_session
+                ON replica.id = agent_session.replica_id
+            WHERE agent_session.agent_id = $1
+                AND agent_session.classroom_id = $2
+                AND replica.id <> $3
+            "#,
+            self.agent_id as &AgentId,
+            self.classroom_id as ClassroomId,
+            self.except_id,
+        )
+        .fetch_all(conn)
+        .await
+    }
+}
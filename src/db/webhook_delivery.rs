@@ -0,0 +1,119 @@
+use sqlx::{postgres::PgQueryResult, types::time::OffsetDateTime, PgConnection};
+
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub audience: String,
+    pub payload: String,
+    pub attempts: i32,
+}
+
+pub struct InsertQuery<'a> {
+    audience: &'a str,
+    payload: &'a str,
+}
+
+impl<'a> InsertQuery<'a> {
+    pub fn new(audience: &'a str, payload: &'a str) -> Self {
+        Self { audience, payload }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            INSERT INTO webhook_delivery (audience, payload)
+            VALUES ($1, $2)
+            "#,
+            self.audience,
+            self.payload
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+/// Takes due deliveries and postpones them until `lease_until`,
+/// so they are retried by any replica if this one fails to finish them.
+pub struct ClaimQuery {
+    limit: i64,
+    lease_until: OffsetDateTime,
+}
+
+impl ClaimQuery {
+    pub fn new(limit: i64, lease_until: OffsetDateTime) -> Self {
+        Self { limit, lease_until }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<Vec<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"
+            UPDATE webhook_delivery
+            SET attempts = attempts + 1,
+                next_attempt_at = $2
+            WHERE id IN (
+                SELECT id
+                FROM webhook_delivery
+                WHERE next_attempt_at <= now()
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, audience, payload, attempts
+            "#,
+            self.limit,
+            self.lease_until
+        )
+        .fetch_all(conn)
+        .await
+    }
+}
+
+pub struct RescheduleQuery {
+    id: i64,
+    next_attempt_at: OffsetDateTime,
+}
+
+impl RescheduleQuery {
+    pub fn new(id: i64, next_attempt_at: OffsetDateTime) -> Self {
+        Self {
+            id,
+            next_attempt_at,
+        }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            UPDATE webhook_delivery
+            SET next_attempt_at = $2
+            WHERE id = $1
+            "#,
+            self.id,
+            self.next_attempt_at
+        )
+        .execute(conn)
+        .await
+    }
+}
+
+pub struct DeleteQuery {
+    id: i64,
+}
+
+impl DeleteQuery {
+    pub fn new(id: i64) -> Self {
+        Self { id }
+    }
+
+    pub async fn execute(&self, conn: &mut PgConnection) -> sqlx::Result<PgQueryResult> {
+        sqlx::query!(
+            r#"
+            DELETE FROM webhook_delivery
+            WHERE id = $1
+            "#,
+            self.id
+        )
+        .execute(conn)
+        .await
+    }
+}
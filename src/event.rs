@@ -0,0 +1,107 @@
+use crate::{classroom::ClassroomId, session::AgentStatus};
+use serde_derive::Serialize;
+use svc_agent::AgentId;
+
+/// Events published by presence.
+///
+/// The wire format is the same as in `svc_events::Event`, but agent events
+/// are extended with labels that are specific to presence.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "version", rename_all = "snake_case")]
+pub enum Event {
+    V1(EventV1),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "entity_type", rename_all = "snake_case")]
+pub enum EventV1 {
+    Agent(AgentEventV1),
+    Message(MessageEventV1),
+    Signal(SignalEventV1),
+    Classroom(ClassroomEventV1),
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "label", rename_all = "snake_case")]
+pub enum AgentEventV1 {
+    Entered {
+        agent_id: AgentId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        metadata: Option<serde_json::Value>,
+    },
+    Left {
+        agent_id: AgentId,
+    },
+    StatusChanged {
+        agent_id: AgentId,
+        status: AgentStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_text: Option<String>,
+    },
+    /// The agent stopped reporting activity on all devices.
+    Idle {
+        agent_id: AgentId,
+    },
+    /// The idle agent reported activity again.
+    Active {
+        agent_id: AgentId,
+    },
+}
+
+/// Application-defined messages published by agents through the socket.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "label", rename_all = "snake_case")]
+pub enum MessageEventV1 {
+    Published {
+        agent_id: AgentId,
+        data: serde_json::Value,
+    },
+}
+
+/// Ephemeral signals (typing indicators, cursor positions), they aren't persisted.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "label", rename_all = "snake_case")]
+pub enum SignalEventV1 {
+    Sent {
+        agent_id: AgentId,
+        data: serde_json::Value,
+    },
+}
+
+/// Lifecycle of the classroom: the first agent entered it or the last one left it.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "label", rename_all = "snake_case")]
+pub enum ClassroomEventV1 {
+    Occupied { classroom_id: ClassroomId },
+    Vacated { classroom_id: ClassroomId },
+}
+
+impl From<EventV1> for Event {
+    fn from(event: EventV1) -> Self {
+        Event::V1(event)
+    }
+}
+
+impl From<AgentEventV1> for EventV1 {
+    fn from(event: AgentEventV1) -> Self {
+        EventV1::Agent(event)
+    }
+}
+
+impl From<MessageEventV1> for EventV1 {
+    fn from(event: MessageEventV1) -> Self {
+        EventV1::Message(event)
+    }
+}
+
+impl From<SignalEventV1> for EventV1 {
+    fn from(event: SignalEventV1) -> Self {
+        EventV1::Signal(event)
+    }
+}
+
+impl From<ClassroomEventV1> for EventV1 {
+    fn from(event: ClassroomEventV1) -> Self {
+        EventV1::Classroom(event)
+    }
+}
@@ -7,6 +7,7 @@ mod authz;
 mod classroom;
 mod config;
 mod db;
+mod event;
 mod session;
 #[cfg(test)]
 mod test_helpers;
@@ -3,10 +3,13 @@ use serde_derive::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 use svc_agent::AgentId;
 
-#[derive(Hash, Eq, PartialEq, Clone, sqlx::Type, Debug, Deserialize, Serialize)]
+#[derive(Hash, Eq, PartialEq, Clone, Debug, Deserialize, Serialize)]
 pub struct SessionKey {
     pub agent_id: AgentId,
     pub classroom_id: ClassroomId,
+    /// Empty unless the audience of the agent allows several devices.
+    #[serde(default)]
+    pub device_id: String,
 }
 
 impl SessionKey {
@@ -14,16 +17,26 @@ impl SessionKey {
         Self {
             agent_id,
             classroom_id,
+            device_id: String::new(),
         }
     }
+
+    pub fn with_device(self, device_id: String) -> Self {
+        Self { device_id, ..self }
+    }
+
+    /// Checks whether both keys are of the same agent in the same classroom, regardless of devices.
+    pub fn is_same_agent(&self, other: &SessionKey) -> bool {
+        self.agent_id == other.agent_id && self.classroom_id == other.classroom_id
+    }
 }
 
 impl Display for SessionKey {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "(agent_id: {}, classroom_id: {})",
-            self.agent_id, self.classroom_id
+            "(agent_id: {}, classroom_id: {}, device_id: {})",
+            self.agent_id, self.classroom_id, self.device_id
         )
     }
 }
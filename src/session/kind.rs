@@ -4,6 +4,8 @@ use std::fmt::{Display, Formatter};
 pub enum SessionKind {
     New,
     Replaced,
+    /// The agent is already in the classroom on another device.
+    Concurrent,
 }
 
 impl Display for SessionKind {
@@ -11,6 +13,7 @@ impl Display for SessionKind {
         let kind = match self {
             SessionKind::New => "new",
             SessionKind::Replaced => "replaced",
+            SessionKind::Concurrent => "concurrent",
         };
 
         write!(f, "{}", kind)
@@ -3,10 +3,12 @@ use std::fmt::{Display, Formatter};
 mod id;
 mod key;
 mod kind;
+mod status;
 
 pub use id::SessionId;
 pub use key::SessionKey;
 pub use kind::SessionKind;
+pub use status::AgentStatus;
 
 #[derive(Debug)]
 pub struct Session {
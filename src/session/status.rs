@@ -0,0 +1,10 @@
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, sqlx::Type, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[sqlx(type_name = "agent_status", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum AgentStatus {
+    Online,
+    Away,
+    Busy,
+}
@@ -1,14 +1,13 @@
-use crate::test_helpers::TOKEN_ISSUER;
+use crate::test_helpers::{PRIVATE_KEY_PATH, TOKEN_ISSUER};
 use once_cell::sync::Lazy;
 use svc_agent::{mqtt::Address, AgentId};
 use svc_authn::{jose::Algorithm, token::jws_compact::TokenBuilder, AccountId, Authenticable};
 
 pub const API_VERSION: &str = "v1";
 const TOKEN_EXPIRATION: i64 = 600;
-const KEY_PATH: &str = "data/keys/svc.private_key.p8.der.sample";
 
 static PRIVATE_KEY: Lazy<Vec<u8>> =
-    Lazy::new(|| std::fs::read(KEY_PATH).expect("Failed to read private key file"));
+    Lazy::new(|| std::fs::read(PRIVATE_KEY_PATH).expect("Failed to read private key file"));
 
 pub struct TestAgent {
     address: Address,
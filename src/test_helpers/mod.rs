@@ -9,7 +9,8 @@ pub mod test_container;
 pub mod prelude {
     pub use super::{
         agent::TestAgent, authn, authz::TestAuthz, db::TestDb, factory, state::TestState,
-        test_container::TestContainer, PUBKEY_PATH, SVC_AUDIENCE, TOKEN_ISSUER, USR_AUDIENCE,
+        test_container::TestContainer, PRIVATE_KEY_PATH, PUBKEY_PATH, SVC_AUDIENCE, TOKEN_ISSUER,
+        USR_AUDIENCE,
     };
 }
 
@@ -17,3 +18,4 @@ pub const SVC_AUDIENCE: &str = "dev.example.org";
 pub const USR_AUDIENCE: &str = "dev.example.com";
 pub const TOKEN_ISSUER: &str = "iam.example.com";
 pub const PUBKEY_PATH: &str = "data/keys/svc.public_key.p8.der.sample";
+pub const PRIVATE_KEY_PATH: &str = "data/keys/svc.private_key.p8.der.sample";
@@ -2,12 +2,15 @@ use crate::{
     app::{
         metrics::Metrics,
         nats::NatsClient,
+        rate_limiter::AgentRateLimiter,
+        replica::InternalApiClient,
         session_manager::{ConnectionCommand, DeleteSession, TerminateSession},
         state::State,
         util::AudienceEstimator,
     },
     classroom::ClassroomId,
-    config::{Config, WebSocketConfig},
+    config::{Config, InternalApiConfig, WebSocketConfig},
+    event::EventV1 as Event,
     session::*,
     test_helpers::prelude::*,
 };
@@ -17,10 +20,11 @@ use sqlx::{pool::PoolConnection, Postgres};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
-use svc_authn::AccountId;
+use svc_agent::AgentId;
+use svc_authn::{jose::Algorithm, AccountId};
 use svc_authz::ClientMap as Authz;
-use svc_events::EventV1 as Event;
 use svc_nats_client::Message;
 use tokio::sync::mpsc;
 use uuid::Uuid;
@@ -33,6 +37,9 @@ pub struct TestState {
     replica_id: Uuid,
     nats_client: Arc<dyn NatsClient>,
     audience_estimator: AudienceEstimator,
+    internal_api: InternalApiClient,
+    local_session: Option<SessionId>,
+    signal_limiter: Arc<AgentRateLimiter>,
 }
 
 impl TestState {
@@ -48,6 +55,12 @@ impl TestState {
                 IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
                 3002,
             ),
+            internal_api: InternalApiConfig {
+                key: std::fs::read(PRIVATE_KEY_PATH).expect("Failed to read private key file"),
+                algorithm: Algorithm::ES256,
+                timeout: Duration::from_secs(5),
+                transport: Default::default(),
+            },
             sentry: None,
             authn: Default::default(),
             websocket: WebSocketConfig {
@@ -55,6 +68,11 @@ impl TestState {
                 pong_expiration_interval: Default::default(),
                 authentication_timeout: Default::default(),
                 wait_before_close_connection: Default::default(),
+                resume_window: Default::default(),
+                signal_rate_limit: 10,
+                idle_timeout: Duration::from_secs(300),
+                token_refresh_window: Duration::from_secs(60),
+                reauthorization_interval: None,
             },
             authz: Default::default(),
             svc_audience: SVC_AUDIENCE.to_string(),
@@ -64,8 +82,16 @@ impl TestState {
                 subscribe_durable: None,
                 subscribe_ephemeral: None,
             },
+            capacity: Default::default(),
+            replica: Default::default(),
+            devices: Default::default(),
+            webhooks: Default::default(),
+            agent_list: Default::default(),
         };
         let audience_estimator = AudienceEstimator::new(&config.authz);
+        let signal_limiter = AgentRateLimiter::new(config.websocket.signal_rate_limit);
+        let internal_api =
+            InternalApiClient::new(&config).expect("Failed to create internal api client");
         Self {
             config,
             db_pool,
@@ -73,16 +99,37 @@ impl TestState {
             replica_id,
             nats_client: Arc::new(TestNatsClient {}) as Arc<dyn NatsClient>,
             audience_estimator,
+            internal_api,
+            local_session: None,
+            signal_limiter: Arc::new(signal_limiter),
         }
     }
+
+    pub fn config_mut(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    /// The session which `terminate_session` finds on this replica.
+    pub fn set_local_session(&mut self, session_id: SessionId) {
+        self.local_session = Some(session_id);
+    }
 }
 
 struct TestNatsClient;
 
 #[async_trait]
 impl NatsClient for TestNatsClient {
-    async fn subscribe(&self, _classroom_id: ClassroomId) -> Result<mpsc::Receiver<Message>> {
-        unimplemented!()
+    async fn subscribe(&self, _classroom_id: ClassroomId) -> Result<mpsc::Receiver<Arc<Message>>> {
+        let (_, rx) = mpsc::channel::<Arc<Message>>(1);
+        Ok(rx)
+    }
+    async fn replay(
+        &self,
+        _classroom_id: ClassroomId,
+        _from_sequence: u64,
+        _window: Duration,
+    ) -> Result<Vec<Message>> {
+        Ok(vec![])
     }
     async fn publish_event(
         &self,
@@ -90,7 +137,59 @@ impl NatsClient for TestNatsClient {
         _event: Event,
         _operation: String,
     ) -> Result<()> {
-        unimplemented!()
+        Ok(())
+    }
+    async fn publish_classroom_event(
+        &self,
+        _classroom_id: ClassroomId,
+        _event: Event,
+        _operation: &str,
+        _occupancy_id: i64,
+        _sender_id: AgentId,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn publish_message(
+        &self,
+        _session: &Session,
+        _event: Event,
+        _receiver_id: Option<AgentId>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn subscribe_signals(
+        &self,
+        _classroom_id: ClassroomId,
+    ) -> Result<mpsc::Receiver<Arc<async_nats::Message>>> {
+        let (_, rx) = mpsc::channel(1);
+        Ok(rx)
+    }
+    async fn publish_signal(
+        &self,
+        _session: &Session,
+        _event: Event,
+        _receiver_id: Option<AgentId>,
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn subscribe_replica_commands(
+        &self,
+        _replica_id: Uuid,
+    ) -> Result<mpsc::Receiver<async_nats::Message>> {
+        let (_, rx) = mpsc::channel::<async_nats::Message>(1);
+        Ok(rx)
+    }
+    async fn request_replica(
+        &self,
+        _replica_id: Uuid,
+        _command: &str,
+        _payload: Vec<u8>,
+        _timeout: Duration,
+    ) -> Result<Vec<u8>> {
+        Err(anyhow::anyhow!("Replicas are unreachable in tests"))
+    }
+    async fn reply(&self, _subject: String, _payload: Vec<u8>) -> Result<()> {
+        Ok(())
     }
 }
 
@@ -122,13 +221,24 @@ impl State for TestState {
     }
 
     async fn terminate_session(&self, _: SessionKey) -> Result<TerminateSession> {
-        Ok(TerminateSession::NotFound)
+        match self.local_session {
+            Some(session_id) => Ok(TerminateSession::Found(session_id)),
+            None => Ok(TerminateSession::NotFound),
+        }
     }
 
     async fn delete_session(&self, _: SessionKey) -> Result<DeleteSession> {
         Ok(DeleteSession::NotFound)
     }
 
+    async fn kick_session(&self, _: SessionKey) -> Result<DeleteSession> {
+        Ok(DeleteSession::NotFound)
+    }
+
+    fn deny_sessions(&self, _: ClassroomId, _: AccountId) -> Result<()> {
+        Ok(())
+    }
+
     async fn get_conn(&self) -> Result<PoolConnection<Postgres>> {
         let conn = self.db_pool.get_conn().await;
         Ok(conn)
@@ -141,4 +251,12 @@ impl State for TestState {
     fn lookup_known_authz_audience(&self, aud: &str) -> Option<&str> {
         self.audience_estimator.estimate(aud)
     }
+
+    fn internal_api(&self) -> &InternalApiClient {
+        &self.internal_api
+    }
+
+    fn signal_limiter(&self) -> &AgentRateLimiter {
+        &self.signal_limiter
+    }
 }